@@ -1,9 +1,9 @@
-use core::str;
 use std::{
+    cell::RefCell,
     fs::{self, File},
     io::{self, Write},
     path::Path,
-    process::Command,
+    rc::Rc,
     sync::{
         mpsc::{self, Receiver},
         {Arc, RwLock},
@@ -12,7 +12,7 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use once_cell::sync::Lazy;
+use bite::VM;
 
 use crate::printer::{center_text, print_hint, print_msg};
 use crate::stage_problems::StageInfo;
@@ -23,13 +23,6 @@ pub enum UserInput {
 
 static FILE_DIR: &str = "exercises";
 
-static PATH: Lazy<String> = Lazy::new(|| {
-    format!(
-        "{}/git/HonorThesis/target/release/Thesis",
-        std::env::var("HOME").expect("HOME environment variable not set")
-    )
-});
-
 pub fn start_user_input(stages: Arc<RwLock<StageInfo>>) -> mpsc::Receiver<UserInput> {
     let (tx, rx) = mpsc::channel::<UserInput>();
 
@@ -37,10 +30,10 @@ pub fn start_user_input(stages: Arc<RwLock<StageInfo>>) -> mpsc::Receiver<UserIn
         let mut input = String::new();
         io::stdin().read_line(&mut input).unwrap();
 
-        if &input.cmp(&String::from("quit\n")) == &std::cmp::Ordering::Equal {
+        if input == "quit\n" {
             tx.send(UserInput::Quit).expect("Unable to send message");
             break;
-        } else if &input.cmp(&String::from("hint\n")) == &std::cmp::Ordering::Equal {
+        } else if input == "hint\n" {
             print_hint(stages.read().unwrap().get_stage_hint());
             println!("\n\n");
             print!("\x1B7\x1B[H");
@@ -118,12 +111,9 @@ pub fn start_file_listener(
                 Ok(file_data) => {
                     let modified_time =
                         file_data.modified().expect("Unable to check modified time");
-                    match last_modified.cmp(&modified_time) {
-                        std::cmp::Ordering::Less => {
-                            is_modified = true;
-                            last_modified = modified_time;
-                        }
-                        _ => {}
+                    if last_modified < modified_time {
+                        is_modified = true;
+                        last_modified = modified_time;
                     }
                 }
                 Err(_err) => {
@@ -137,33 +127,14 @@ pub fn start_file_listener(
             drop(read_lock);
 
             if is_modified {
-                // Replace with built version in bin path on user device
-                let program_result = Command::new(PATH.as_str())
-                    .arg("run")
-                    .arg(file_path_str)
-                    .output()
-                    .expect("Failed to run command");
-
-                let success = program_result.status.success();
+                let (success, output) = run_exercise(&file_path);
                 if !contains_str(file_path_str).unwrap() && success {
                     // Marks it complete so hint gives correct hint
                     stages.write().unwrap().set_stage_completed(curr_stage);
                     curr_stage += 1;
                 }
 
-                if success {
-                    print_msg(
-                        success,
-                        str::from_utf8(&program_result.stdout).unwrap(),
-                        stages.clone(),
-                    );
-                } else {
-                    print_msg(
-                        success,
-                        str::from_utf8(&program_result.stderr).unwrap(),
-                        stages.clone(),
-                    );
-                }
+                print_msg(success, &output, stages.clone());
             }
         }
     })
@@ -188,7 +159,7 @@ pub fn current_stage(stages: Arc<RwLock<StageInfo>>) -> usize {
 
         let file_path = Path::new(FILE_DIR).join(file_name);
         let file_path_str = file_path.to_str().unwrap();
-        if !fs::metadata(file_path_str).is_ok() {
+        if fs::metadata(file_path_str).is_err() {
             match create_file(stages.clone(), i) {
                 Ok(_) => {
                     return i;
@@ -200,31 +171,17 @@ pub fn current_stage(stages: Arc<RwLock<StageInfo>>) -> usize {
             }
         }
 
-        let program_result = Command::new(PATH.as_str())
-            .arg("run")
-            .arg(file_path_str)
-            .output()
-            .expect("Failed to run command.");
+        let (success, output) = run_exercise(&file_path);
 
         drop(read_only);
 
-        let success = program_result.status.success();
+        print_msg(success, &output, stages.clone());
         if success {
-            print_msg(
-                success,
-                str::from_utf8(&program_result.stdout).unwrap(),
-                stages.clone(),
-            );
             if contains_str(file_path_str).unwrap() {
                 return i;
             }
             stages.write().unwrap().set_stage_completed(i);
         } else {
-            print_msg(
-                success,
-                str::from_utf8(&program_result.stderr).unwrap(),
-                stages.clone(),
-            );
             return i;
         }
     }
@@ -232,6 +189,51 @@ pub fn current_stage(stages: Arc<RwLock<StageInfo>>) -> usize {
     stages.read().unwrap().total_stages()
 }
 
+// What a program printed, kept so it can be shown once the program is done
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+// Runs the exercise in a VM of its own. Gives back whether it worked, with what it printed when
+// it did and what went wrong when it didn't.
+fn run_exercise(path: &Path) -> (bool, String) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(err) => return (false, format!("Can't read {}: {}", path.display(), err)),
+    };
+
+    if let Err(diagnostics) = bite::compile(&source) {
+        let rendered: Vec<String> = diagnostics.iter().map(|x| x.render(&source)).collect();
+        return (false, rendered.join("\n\n"));
+    }
+
+    let output = Output::default();
+    let errors = Output::default();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    vm.set_error_output(errors.clone());
+
+    match vm.interpret_file(source, path) {
+        Ok(_) => (true, output.text()),
+        Err(_) => (false, errors.text()),
+    }
+}
+
 fn create_file(stages: Arc<RwLock<StageInfo>>, problem_num: usize) -> Result<(), io::Error> {
     let file_path =
         Path::new(FILE_DIR).join(&stages.read().unwrap().get_stage_complete_at(problem_num).0);
@@ -245,7 +247,7 @@ fn create_file(stages: Arc<RwLock<StageInfo>>, problem_num: usize) -> Result<(),
     file_contents.push_str("\n\n");
     file_contents.push_str(read_only.get_problem(problem_num));
 
-    file.write(file_contents.as_bytes())?;
+    file.write_all(file_contents.as_bytes())?;
 
     Ok(())
 }
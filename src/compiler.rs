@@ -1,17 +1,26 @@
-use std::{cell::RefCell, mem, rc::Rc};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    mem,
+    path::Path,
+    rc::Rc,
+};
 
-use chunk::{Chunk, OpCode};
-use values::{FunctionObj, Obj, StrObj, Value};
+use chunk::{Chunk, OpCode, JUMP_SIZE};
+use diagnostic::Diagnostic;
+use values::{FunctionObj, GlobalSlots, Obj, ObjData, UpvalueIdx, Value};
 
 use crate::{
-    debug::disassemble_chunk,
     expr_prec::{get_parse_rule, ParseFn, PRECEDENCE},
-    scanner::{Scanner, Token, TokenType},
-    vm::DEBUG,
+    scanner::{unescape, Scanner, Token, TokenType},
+    vm::heap::Heap,
 };
 
+pub mod bytecode;
 pub mod chunk;
 pub mod common;
+pub mod diagnostic;
+pub mod optimizer;
 pub mod values;
 
 //enum CompileError {
@@ -21,6 +30,8 @@ pub mod values;
 struct Local {
     name: Token,
     depth: LocalState,
+    // Set when a nested function captures it, so the scope closes it instead of popping it.
+    is_captured: bool,
 }
 
 #[derive(PartialEq)]
@@ -30,47 +41,126 @@ enum LocalState {
     Init(usize),
 }
 
+// The loop being compiled, so break and continue know where to go.
+struct Loop {
+    // Where continue jumps back to
+    start: usize,
+    // Locals deeper than this were declared inside the loop and have to be popped before leaving
+    scope_depth: usize,
+    // OpJumps from break, patched once the loop ends
+    breaks: Vec<usize>,
+}
+
+#[allow(clippy::enum_variant_names)]
 enum FunctionType {
     FunctionType,
+    // Declared inside a struct, gets the instance it was called on as self
+    MethodType,
     ScriptType,
 }
 
 struct Compiler {
+    // The compiler of the function we are nested in. None for the top level script.
+    enclosing: Option<Box<Compiler>>,
+
     function: FunctionObj,
     function_type: FunctionType,
 
     locals: Vec<Local>,
     local_count: usize,
     scope_depth: usize,
+
+    // Innermost last. A function declared inside a loop starts with none.
+    loops: Vec<Loop>,
 }
 
 impl Compiler {
     fn new(function_type: FunctionType) -> Self {
-        // Holds the function
+        // Holds the function, or the receiver in methods
+        let first_name = match function_type {
+            FunctionType::MethodType => "self",
+            _ => "",
+        };
         let first_idx_holder = Local {
             name: Token {
                 line: 0,
-                lexeme: "".to_string(),
+                column: 0,
+                span: 0..0,
+                lexeme: first_name.to_string(),
                 token_type: TokenType::None,
             },
             depth: LocalState::Init(0),
+            is_captured: false,
         };
 
-        let mut locals: Vec<Local> = Vec::new();
-        locals.push(first_idx_holder);
+        let locals: Vec<Local> = vec![first_idx_holder];
 
         Compiler {
+            enclosing: None,
+
             function: FunctionObj::new(),
             function_type,
 
             locals,
             local_count: 1,
             scope_depth: 0,
+
+            loops: Vec::new(),
+        }
+    }
+
+    // Returns the slot of the local and whether it is still uninitialized.
+    fn resolve_local(&self, name: &Token) -> Option<(usize, bool)> {
+        for i in (0..self.locals.len()).rev() {
+            let local = &self.locals[i];
+            if local.name.lexeme == name.lexeme {
+                return Some((i, local.depth == LocalState::Uninit));
+            }
+        }
+
+        None
+    }
+
+    // Walks outwards through the enclosing compilers. Each function in between gets its own
+    // upvalue pointing at the one above it, so the value can be passed down at runtime.
+    fn resolve_upvalue(&mut self, name: &Token) -> Option<usize> {
+        let enclosing = self.enclosing.as_mut()?;
+
+        if let Some((idx, _)) = enclosing.resolve_local(name) {
+            enclosing.locals[idx].is_captured = true;
+            return Some(self.add_upvalue(idx, true));
+        }
+
+        if let Some(idx) = enclosing.resolve_upvalue(name) {
+            return Some(self.add_upvalue(idx, false));
+        }
+
+        None
+    }
+
+    // Whether the name is a local here or in any function we are nested in, without capturing it.
+    fn is_local_anywhere(&self, name: &Token) -> bool {
+        self.resolve_local(name).is_some()
+            || self
+                .enclosing
+                .as_ref()
+                .is_some_and(|x| x.is_local_anywhere(name))
+    }
+
+    fn add_upvalue(&mut self, index: usize, is_local: bool) -> usize {
+        let upvalue = UpvalueIdx { index, is_local };
+
+        // The same variable can be referenced many times, only capture it once.
+        if let Some(idx) = self.function.upvalues.iter().position(|x| *x == upvalue) {
+            return idx;
         }
+
+        self.function.upvalues.push(upvalue);
+        self.function.upvalues.len() - 1
     }
 }
 
-pub struct Parser {
+pub struct Parser<'a> {
     previous: Option<Token>,
     current: Option<Token>,
 
@@ -78,14 +168,38 @@ pub struct Parser {
     compiler: Compiler,
 
     scanner: Option<Scanner>,
+    // Shared with every chunk compiled from it
+    source: Rc<str>,
+
+    // Strings and functions made while compiling are allocated straight into the VM's heap
+    heap: &'a mut Heap,
 
     pub has_error: bool,
     // Can possibly replace with Result/Option type
     panic_error: bool,
+    // Everything reported while compiling, handed back by compile()
+    diagnostics: RefCell<Vec<Diagnostic>>,
+
+    // In the REPL, bare expressions print their value and running out of input isn't reported
+    // since the user might still be typing.
+    repl: bool,
+    incomplete: Cell<bool>,
+
+    // Fields of the Structs declared globally in this source, so initializers can be checked
+    // before running. Anything else is checked by the VM.
+    structs: HashMap<String, Vec<String>>,
+    // The variable an infix rule is applied to, when it's a bare name. Lets `Point { }` know
+    // which Struct it builds.
+    infix_target: Option<Token>,
+
+    // Runs the optimizer over every chunk once it's done
+    optimize: bool,
+    // Slots of the module's globals, the ones from earlier code in the same module come first
+    global_slots: GlobalSlots,
 }
 
-impl Parser {
-    pub fn new() -> Self {
+impl<'a> Parser<'a> {
+    pub fn new(heap: &'a mut Heap) -> Self {
         Parser {
             // Inital state is None, All subsequent values are not null.
             // Current and Peek/Next, makes more sense so far
@@ -95,51 +209,85 @@ impl Parser {
             compiler: Compiler::new(FunctionType::ScriptType),
 
             scanner: None,
+            source: Rc::from(""),
+
+            heap,
 
             has_error: false,
             panic_error: false,
+            diagnostics: RefCell::new(Vec::new()),
+
+            repl: false,
+            incomplete: Cell::new(false),
+
+            structs: HashMap::new(),
+            infix_target: None,
+
+            optimize: true,
+            global_slots: GlobalSlots::default(),
         }
     }
 
-    pub fn compile(&mut self, source: String) -> Option<FunctionObj> {
+    pub fn new_repl(heap: &'a mut Heap) -> Self {
+        let mut parser = Parser::new(heap);
+        parser.repl = true;
+        parser
+    }
+
+    // On by default, turning it off keeps the bytecode exactly as the parser emitted it.
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    // Globals get the slots they already have in here, and new ones are added after them.
+    pub fn set_global_slots(&mut self, global_slots: GlobalSlots) {
+        self.global_slots = global_slots;
+    }
+
+    // Hands the slots back once compiling is done, so the module can keep them for later code.
+    pub fn take_global_slots(&mut self) -> GlobalSlots {
+        mem::take(&mut self.global_slots)
+    }
+
+    // True when compiling failed only because the source ended too early, like a missing 'end'.
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete.get()
+    }
+
+    pub fn compile(&mut self, source: String) -> Result<FunctionObj, Vec<Diagnostic>> {
+        self.source = Rc::from(source.as_str());
+        self.compiler.function.chunk.source = self.source.clone();
         self.scanner = Some(Scanner::new(source));
 
         self.advance();
 
         self.skip_empty_line();
-        while !self.match_token_type(TokenType::EOF) {
+        while !self.match_token_type(TokenType::Eof) {
             // Because we use '\n' as the terminator, we need to care extra about empty random new
             // lines.
             self.declaration();
             self.skip_empty_line();
         }
 
-        if DEBUG && self.has_error {
-            // From here on out, we will treat the global scope as "main()"
-            if let Some(function_name) = self.compiler.function.name.clone() {
-                disassemble_chunk(self.current_chunk(), function_name);
-            } else {
-                disassemble_chunk(self.current_chunk(), "<script>".to_string());
-            }
-        }
-
         self.emit_opcode(OpCode::OpNone);
         self.emit_opcode(OpCode::OpReturn);
 
         // Now inside vm.interpret, we no longer need to .clone(). Saves computation and memory
-        let function_obj = mem::replace(
+        let mut function_obj = mem::replace(
             &mut self.compiler.function,
             FunctionObj {
                 arity: 0,
                 chunk: Chunk::new(),
                 name: Some(String::new()),
-                is_marked: false,
+                upvalues: Vec::new(),
             },
         );
 
+        self.optimize_chunk(&mut function_obj.chunk);
+
         match self.has_error {
-            true => None,
-            false => Some(function_obj),
+            true => Err(self.diagnostics.take()),
+            false => Ok(function_obj),
         }
     }
 
@@ -150,6 +298,8 @@ impl Parser {
             self.fn_decl();
         } else if self.match_token_type(TokenType::Let) {
             self.var_decl();
+        } else if self.match_token_type(TokenType::Import) {
+            self.import_decl();
         } else {
             self.statement();
         }
@@ -164,19 +314,24 @@ impl Parser {
     fn class_decl(&mut self) {
         self.consume(TokenType::Identifier, "Expected Struct name here.");
         if let Some(ref token) = self.previous {
+            let name = token.clone();
             let struct_idx = self.make_identifier_constant(token.clone());
-            self.declare_var();
+            let slot = self.declare_previous_var();
 
             self.emit_opcode(OpCode::OpClass(struct_idx));
-            self.define_var(struct_idx);
+            self.define_var(slot);
 
             self.name_variable(false);
             self.consume(TokenType::LeftBrace, "Expected opening brace '{' here");
 
-            self.parse_field_decl();
+            let fields = self.parse_field_decl();
 
             self.consume(TokenType::RightBrace, "Expected closing brace '}' here");
 
+            if self.compiler.scope_depth == 0 && self.compiler.enclosing.is_none() {
+                self.structs.insert(name.lexeme, fields);
+            }
+
             self.emit_opcode(OpCode::OpPop);
         }
     }
@@ -199,7 +354,7 @@ impl Parser {
         self.skip_empty_line();
         let mut curr_token_type = self.grab_curr_token_type().unwrap();
 
-        while curr_token_type != TokenType::End && curr_token_type != TokenType::EOF {
+        while curr_token_type != TokenType::End && curr_token_type != TokenType::Eof {
             self.declaration();
             self.skip_empty_line();
             curr_token_type = self.grab_curr_token_type().unwrap();
@@ -209,31 +364,24 @@ impl Parser {
     }
 
     fn function(&mut self, function_type: FunctionType, fn_name: String) {
-        let user_fn_obj = FunctionObj {
+        let mut user_fn_obj = FunctionObj {
             arity: 0,
             chunk: Chunk::new(),
             name: Some(fn_name),
-            is_marked: false,
+            upvalues: Vec::new(),
         };
-
-        if self.compiler.scope_depth >= 1 && DEBUG {
-            eprintln!("There is a function declared in a local scope");
-        }
+        user_fn_obj.chunk.source = self.source.clone();
 
         let mut func_compiler = Compiler::new(function_type);
         func_compiler.function = user_fn_obj;
 
-        // Stores the original Compiler, and sets a new compiler to fill.
+        // Stores the original Compiler, and sets a new compiler to fill. The original is kept as
+        // the enclosing compiler so variables from outer functions can be captured.
         let main_fn_compiler = mem::replace(&mut self.compiler, func_compiler);
+        self.compiler.enclosing = Some(Box::new(main_fn_compiler));
 
         self.begin_scope();
 
-        if let Some(ref fn_name) = main_fn_compiler.function.name {
-            if fn_name.len() != 0 {
-                eprintln!("There is a function declared in a local scope");
-            }
-        }
-
         self.consume(TokenType::LeftParen, "Expected '(' after function name");
 
         let curr_token_type = self.grab_curr_token_type().unwrap();
@@ -262,11 +410,14 @@ impl Parser {
         self.emit_opcode(OpCode::OpReturn);
 
         // Stores the Function Compiler, and sets originl back in place.
-        let user_fn_obj = mem::replace(&mut self.compiler, main_fn_compiler);
-        let user_fn = Value::Obj(Obj::Function(Rc::new(RefCell::new(user_fn_obj.function))));
+        let main_fn_compiler = self.compiler.enclosing.take().unwrap();
+        let mut user_fn_obj = mem::replace(&mut self.compiler, *main_fn_compiler);
+        self.optimize_chunk(&mut user_fn_obj.function.chunk);
+        let user_fn = self.heap.alloc(ObjData::Function(user_fn_obj.function));
+        let user_fn = Value::Obj(Obj::Function(user_fn));
 
         let idx = self.add_value(user_fn);
-        self.emit_opcode(OpCode::OpConstant(idx));
+        self.emit_opcode(OpCode::OpClosure(idx));
     }
 
     fn if_block(&mut self) {
@@ -276,7 +427,7 @@ impl Parser {
         self.skip_empty_line();
         while curr_token_type != TokenType::End
             && curr_token_type != TokenType::Else
-            && curr_token_type != TokenType::EOF
+            && curr_token_type != TokenType::Eof
         {
             self.declaration();
             self.skip_empty_line();
@@ -308,6 +459,63 @@ impl Parser {
         self.define_var(idx);
     }
 
+    // `import "utils"` binds the module to utils and `import "lib/utils" as u` binds it to u. The
+    // file is only looked for once the import runs.
+    fn import_decl(&mut self) {
+        self.consume(
+            TokenType::String,
+            "Expected the path of the module as a string here",
+        );
+        let path_token = match self.previous.clone() {
+            Some(token) if token.token_type == TokenType::String => token,
+            _ => return,
+        };
+        let path = unescape(&path_token.lexeme[1..path_token.lexeme.len() - 1]);
+
+        let is_as = self
+            .current
+            .as_ref()
+            .is_some_and(|x| x.token_type == TokenType::Identifier && x.lexeme == "as");
+        let name_idx = if is_as {
+            self.advance();
+            self.parse_variable()
+        } else {
+            let name = Path::new(&path)
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .unwrap_or_default()
+                .to_string();
+
+            let name_token = Scanner::new(name.clone()).scan_token();
+            if name_token.token_type != TokenType::Identifier || name_token.lexeme != name {
+                let msg = format!(
+                    "Can't name the module after \"{}\", give it a name with 'as'",
+                    path
+                );
+                self.error(&path_token, &msg);
+                self.panic_error = true;
+                self.has_error = true;
+                return;
+            }
+
+            // The name comes from the path, so the path is what gets declared
+            self.previous = Some(Token {
+                token_type: TokenType::Identifier,
+                lexeme: name,
+                ..path_token
+            });
+            self.declare_previous_var()
+        };
+
+        let path = self.heap.intern(&path);
+        let path_idx = self.add_value(Value::Obj(Obj::String(path)));
+        self.emit_opcode(OpCode::OpImport(path_idx));
+
+        self.consume(TokenType::NewLine, "Expected New Line after Expression");
+
+        self.define_var(name_idx);
+    }
+
     fn statement(&mut self) {
         if self.match_token_type(TokenType::If) {
             self.if_stmt();
@@ -319,6 +527,10 @@ impl Parser {
             self.return_stmt();
         } else if self.match_token_type(TokenType::While) {
             self.while_stmt();
+        } else if self.match_token_type(TokenType::Break) {
+            self.break_stmt();
+        } else if self.match_token_type(TokenType::Continue) {
+            self.continue_stmt();
         } else if self.match_token_type(TokenType::For) {
             self.for_stmt();
         } else {
@@ -329,16 +541,12 @@ impl Parser {
     // ****************************     Statements     ***************************
 
     fn return_stmt(&mut self) {
-        match self.compiler.function_type {
-            FunctionType::ScriptType => match self.previous {
-                Some(ref token) => {
-                    self.error(token, "Can't have return values at top level");
-                    self.panic_error = true;
-                    self.has_error = true;
-                }
-                _ => {}
-            },
-            _ => {}
+        if let (FunctionType::ScriptType, Some(token)) =
+            (&self.compiler.function_type, &self.previous)
+        {
+            self.error(token, "Can't have return values at top level");
+            self.panic_error = true;
+            self.has_error = true;
         }
 
         if self.match_token_type(TokenType::NewLine) {
@@ -380,34 +588,35 @@ impl Parser {
             self.expression();
             self.consume(TokenType::Comma, "Expected Comma serperator here");
 
-            jumps = self.emit_jump_code(OpCode::OpJumpIfFalse(255));
+            jumps = self.emit_jump_code(OpCode::OpJumpIfFalse(u16::MAX));
             self.emit_opcode(OpCode::OpPop);
         }
 
         // The Increment
         let curr_token_type = self.grab_curr_token_type().unwrap();
         if curr_token_type != TokenType::Do {
-            let body_jump = self.emit_jump_code(OpCode::OpJump(255));
+            let body_jump = self.emit_jump_code(OpCode::OpJump(u16::MAX));
             let increment_start = self.current_chunk().code.len();
             self.expression();
             self.emit_opcode(OpCode::OpPop);
 
-            let loop_offset = self.current_chunk().code.len() - loop_start + 1;
-            self.emit_opcode(OpCode::OpLoop(loop_offset as u8));
+            self.emit_loop(loop_start);
 
             loop_start = increment_start;
             self.patch_jump_code(body_jump);
         }
 
+        // continue runs the increment before checking the condition again
+        self.begin_loop(loop_start);
         self.statement();
 
-        let loop_offset = self.current_chunk().code.len() - loop_start + 1;
-        self.emit_opcode(OpCode::OpLoop(loop_offset as u8));
+        self.emit_loop(loop_start);
 
         if jumps != 0 {
             self.patch_jump_code(jumps);
             self.emit_opcode(OpCode::OpPop);
         }
+        self.end_loop();
 
         self.end_scope();
     }
@@ -416,27 +625,64 @@ impl Parser {
         let loop_start = self.current_chunk().code.len();
         self.expression();
 
-        let offset = self.emit_jump_code(OpCode::OpJumpIfFalse(255));
+        let offset = self.emit_jump_code(OpCode::OpJumpIfFalse(u16::MAX));
         self.emit_opcode(OpCode::OpPop);
 
+        self.begin_loop(loop_start);
         self.statement();
-        let loop_offset = self.current_chunk().code.len() - loop_start + 1;
-        self.emit_opcode(OpCode::OpLoop(loop_offset as u8));
+        self.emit_loop(loop_start);
 
         self.patch_jump_code(offset);
         self.emit_opcode(OpCode::OpPop);
+        self.end_loop();
+    }
+
+    fn break_stmt(&mut self) {
+        let token = self.previous.clone().unwrap();
+        if self.compiler.loops.is_empty() {
+            self.error(&token, "Can't use 'break' outside of a loop");
+            self.panic_error = true;
+            self.has_error = true;
+            return;
+        }
+
+        self.pop_loop_locals();
+        let jump = self.emit_jump_code(OpCode::OpJump(u16::MAX));
+        if let Some(current_loop) = self.compiler.loops.last_mut() {
+            current_loop.breaks.push(jump);
+        }
+
+        self.consume(TokenType::NewLine, "Expected New Line after break");
+    }
+
+    fn continue_stmt(&mut self) {
+        let token = self.previous.clone().unwrap();
+        let loop_start = match self.compiler.loops.last() {
+            Some(current_loop) => current_loop.start,
+            None => {
+                self.error(&token, "Can't use 'continue' outside of a loop");
+                self.panic_error = true;
+                self.has_error = true;
+                return;
+            }
+        };
+
+        self.pop_loop_locals();
+        self.emit_loop(loop_start);
+
+        self.consume(TokenType::NewLine, "Expected New Line after continue");
     }
 
     fn if_stmt(&mut self) {
         self.expression();
         self.consume(TokenType::Then, "Expected then after the condition");
 
-        let if_jump = self.emit_jump_code(OpCode::OpJumpIfFalse(255));
+        let if_jump = self.emit_jump_code(OpCode::OpJumpIfFalse(u16::MAX));
         self.emit_opcode(OpCode::OpPop);
 
         self.parse_if_blocks();
 
-        let else_jump = self.emit_jump_code(OpCode::OpJump(255));
+        let else_jump = self.emit_jump_code(OpCode::OpJump(u16::MAX));
 
         self.patch_jump_code(if_jump);
         self.emit_opcode(OpCode::OpPop);
@@ -450,6 +696,15 @@ impl Parser {
     fn expression_stmt(&mut self) {
         self.expression();
         self.consume(TokenType::NewLine, "Expected New Line after Expression");
+
+        if self.repl
+            && self.compiler.scope_depth == 0
+            && matches!(self.compiler.function_type, FunctionType::ScriptType)
+        {
+            self.emit_opcode(OpCode::OpPrint);
+            return;
+        }
+
         // Pop afterwards cause no one is able to use when it finishes computing. No one SHOULD be
         // ablt to use it either.
         self.emit_opcode(OpCode::OpPop);
@@ -462,7 +717,7 @@ impl Parser {
 
         if let Some(ref token) = self.previous {
             let prefix = get_parse_rule(token.token_type);
-            if let None = prefix.prefix_rule {
+            if prefix.prefix_rule.is_none() {
                 self.error(token, "Expected Expression");
                 self.panic_error = true;
                 self.has_error = true;
@@ -474,6 +729,10 @@ impl Parser {
             self.call_rule(prefix.prefix_rule.unwrap(), can_assign);
         }
 
+        let mut infix_target = temp_token
+            .clone()
+            .filter(|x| x.token_type == TokenType::Identifier);
+
         if let Some(token) = temp_token {
             if can_assign && self.match_token_type(TokenType::Equal) {
                 {
@@ -489,6 +748,8 @@ impl Parser {
 
         while prec <= get_parse_rule(self.grab_curr_token_type().unwrap()).precedence {
             self.advance();
+            // Only the first infix rule is applied to the bare name
+            self.infix_target = infix_target.take();
             let infix = get_parse_rule(self.grab_prev_token_type().unwrap());
             if let Some(infix_rule) = infix.infix_rule {
                 self.call_rule(infix_rule, can_assign);
@@ -502,35 +763,39 @@ impl Parser {
     }
 
     fn binary(&mut self) {
-        if let Some(ref token) = self.previous {
+        if let Some(token) = self.previous.clone() {
             let operator = token.token_type;
 
             let rule = get_parse_rule(operator);
-            // The numbers would be in the values table already after this.
-            self.parse_precedence(rule.precedence);
-
+            // The numbers would be in the values table already after this. Only the right side
+            // of an exponent can hold another operator of the same precedence, so 2 ^ 3 ^ 2 is
+            // 2 ^ (3 ^ 2) while everything else groups from the left.
             match operator {
-                TokenType::Plus => self.emit_opcode(OpCode::OpAdd),
-                TokenType::Minus => self.emit_opcode(OpCode::OpSubtract),
-                TokenType::Star => self.emit_opcode(OpCode::OpMultiply),
-                TokenType::Slash => self.emit_opcode(OpCode::OpDivide),
-
-                TokenType::EqualEqual => self.emit_opcode(OpCode::OpEqual),
-                TokenType::BangEqual => {
-                    self.emit_opcode(OpCode::OpEqual);
-                    self.emit_opcode(OpCode::OpNot);
-                }
-                TokenType::Greater => self.emit_opcode(OpCode::OpGreater),
-                TokenType::GreaterEqual => {
-                    self.emit_opcode(OpCode::OpLess);
-                    self.emit_opcode(OpCode::OpNot);
-                }
-                TokenType::Less => self.emit_opcode(OpCode::OpLess),
-                TokenType::LessEqual => {
-                    self.emit_opcode(OpCode::OpGreater);
-                    self.emit_opcode(OpCode::OpNot);
-                }
+                TokenType::Caret | TokenType::StarStar => self.parse_precedence(rule.precedence),
+                _ => self.parse_precedence(rule.precedence + 1),
+            }
+
+            let codes: &[OpCode] = match operator {
+                TokenType::Plus => &[OpCode::OpAdd],
+                TokenType::Minus => &[OpCode::OpSubtract],
+                TokenType::Star => &[OpCode::OpMultiply],
+                TokenType::Slash => &[OpCode::OpDivide],
+                TokenType::Percent => &[OpCode::OpModulo],
+                TokenType::Div => &[OpCode::OpFloorDivide],
+                TokenType::Caret | TokenType::StarStar => &[OpCode::OpPower],
+
+                TokenType::EqualEqual => &[OpCode::OpEqual],
+                TokenType::BangEqual => &[OpCode::OpEqual, OpCode::OpNot],
+                TokenType::Greater => &[OpCode::OpGreater],
+                TokenType::GreaterEqual => &[OpCode::OpLess, OpCode::OpNot],
+                TokenType::Less => &[OpCode::OpLess],
+                TokenType::LessEqual => &[OpCode::OpGreater, OpCode::OpNot],
                 _ => unreachable!(),
+            };
+
+            // Errors from these point at the operator rather than the right operand.
+            for code in codes {
+                self.emit_opcode_at(*code, &token);
             }
         }
     }
@@ -543,6 +808,9 @@ impl Parser {
             if can_assign && self.match_token_type(TokenType::Equal) {
                 self.expression();
                 self.emit_opcode(OpCode::OpSetProperty(idx));
+            } else if self.match_token_type(TokenType::LeftParen) {
+                let arg_count = self.argument_list();
+                self.emit_opcode(OpCode::OpInvoke(idx, arg_count));
             } else {
                 self.emit_opcode(OpCode::OpGetProperty(idx));
             }
@@ -555,13 +823,63 @@ impl Parser {
     }
 
     fn instance(&mut self) {
-        // How we parse the struct args is in here.
-        let arg_count = self.instance_args();
-        self.emit_opcode(OpCode::OpCall(arg_count.into()));
+        // Only Structs declared globally in this source can be checked here, and only while
+        // nothing local hides their name.
+        let fields = self
+            .infix_target
+            .take()
+            .filter(|x| !self.compiler.is_local_anywhere(x))
+            .and_then(|x| self.structs.get(&x.lexeme).cloned());
+
+        self.skip_empty_line();
+        let is_named = self.grab_curr_token_type() == Some(TokenType::Identifier)
+            && self
+                .scanner
+                .as_mut()
+                .is_some_and(|x| x.peek_token().token_type == TokenType::Equal);
+
+        if is_named {
+            let field_count = self.named_instance_args(fields.as_deref());
+            self.emit_opcode(OpCode::OpNamedInstance(field_count));
+        } else {
+            let arg_count = self.instance_args();
+            if let Some(fields) = fields {
+                if arg_count != 0 && arg_count as usize != fields.len() {
+                    let token = self.previous.clone().unwrap();
+                    let msg = format!("Expected {} fields but got {}", fields.len(), arg_count);
+                    self.error(&token, &msg);
+                    self.panic_error = true;
+                    self.has_error = true;
+                }
+            }
+            self.emit_opcode(OpCode::OpCall(arg_count));
+        }
+    }
+
+    fn list(&mut self) {
+        let item_count = self.list_items();
+        self.emit_opcode(OpCode::OpBuildList(item_count));
+    }
+
+    fn map(&mut self) {
+        let entry_count = self.map_entries();
+        self.emit_opcode(OpCode::OpBuildMap(entry_count));
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expected ']' after index");
+
+        if can_assign && self.match_token_type(TokenType::Equal) {
+            self.expression();
+            self.emit_opcode(OpCode::OpSetIndex);
+        } else {
+            self.emit_opcode(OpCode::OpGetIndex);
+        }
     }
 
     fn parse_and(&mut self) {
-        let and_jump = self.emit_jump_code(OpCode::OpJumpIfFalse(255));
+        let and_jump = self.emit_jump_code(OpCode::OpJumpIfFalse(u16::MAX));
         self.emit_opcode(OpCode::OpPop);
         self.parse_precedence(PRECEDENCE.and);
 
@@ -569,8 +887,8 @@ impl Parser {
     }
 
     fn parse_or(&mut self) {
-        let if_jump = self.emit_jump_code(OpCode::OpJumpIfFalse(255));
-        let else_jump = self.emit_jump_code(OpCode::OpJump(255));
+        let if_jump = self.emit_jump_code(OpCode::OpJumpIfFalse(u16::MAX));
+        let else_jump = self.emit_jump_code(OpCode::OpJump(u16::MAX));
 
         self.patch_jump_code(if_jump);
         self.emit_opcode(OpCode::OpPop);
@@ -596,7 +914,7 @@ impl Parser {
     }
 
     fn unary(&mut self) {
-        if let Some(ref token) = self.previous {
+        if let Some(token) = self.previous.clone() {
             let token_type = token.token_type;
 
             // Will emit the OpCode inside.
@@ -604,8 +922,8 @@ impl Parser {
             self.parse_precedence(PRECEDENCE.unary + 1);
 
             match token_type {
-                TokenType::Minus => self.emit_opcode(OpCode::OpNegate),
-                TokenType::Bang => self.emit_opcode(OpCode::OpNot),
+                TokenType::Minus => self.emit_opcode_at(OpCode::OpNegate, &token),
+                TokenType::Bang => self.emit_opcode_at(OpCode::OpNot, &token),
                 _ => {}
             }
         }
@@ -620,16 +938,54 @@ impl Parser {
     }
 
     fn string(&mut self) {
-        if let Some(ref token) = self.previous {
-            // TODO consider using str if it doens't need to be mutated
-            let clean_str = &token.lexeme[1..token.lexeme.len() - 1];
-            let idx = self.add_value(Value::Obj(Obj::String(
-                // This clones the string when converting &str to String
-                Rc::new(RefCell::new(StrObj::new(clean_str.to_string()))),
-            )));
+        if let Some(token) = self.previous.clone() {
+            self.string_part(&token);
+        }
+    }
 
-            self.emit_opcode(OpCode::OpConstant(idx));
+    // "Hello {name}!" comes in as the Interpolation "Hello {, the tokens of name, and the String
+    // }!". Every part ends up on the stack and OpBuildString joins them.
+    fn interpolation(&mut self) {
+        let mut part_count: usize = 0;
+
+        loop {
+            let token = self.previous.clone().unwrap();
+            self.string_part(&token);
+            self.expression();
+            part_count += 2;
+
+            if !self.match_token_type(TokenType::Interpolation) {
+                break;
+            }
+        }
+
+        self.consume(
+            TokenType::String,
+            "Expected '}' after the expression in the string",
+        );
+        if self.grab_prev_token_type() != Some(TokenType::String) {
+            return;
+        }
+        let token = self.previous.clone().unwrap();
+        self.string_part(&token);
+        part_count += 1;
+
+        if part_count > u8::MAX as usize {
+            self.error(&token, "Can't have more than 127 expressions in a string");
+            self.panic_error = true;
+            self.has_error = true;
         }
+
+        self.emit_opcode(OpCode::OpBuildString(part_count as u8));
+    }
+
+    // The token's text without the quotes or braces around it
+    fn string_part(&mut self, token: &Token) {
+        let clean_str = unescape(&token.lexeme[1..token.lexeme.len() - 1]);
+        let str_obj = self.heap.intern(&clean_str);
+        let idx = self.add_value(Value::Obj(Obj::String(str_obj)));
+
+        self.emit_opcode(OpCode::OpConstant(idx));
     }
 
     fn variable(&mut self, can_assign: bool) {
@@ -638,17 +994,49 @@ impl Parser {
 
     // ****************************     Helpers     ***************************
 
-    fn parse_field_decl(&mut self) {
+    // Fields and methods can come in any order. Returns the names of the fields, in the order
+    // they were declared
+    fn parse_field_decl(&mut self) -> Vec<String> {
+        let mut fields: Vec<String> = Vec::new();
+        let mut methods: Vec<String> = Vec::new();
+
         self.skip_empty_line();
-        while self.match_token_type(TokenType::Identifier) {
-            if let Some(ref token) = self.previous {
-                let idx = self.make_identifier_constant(token.clone());
+        loop {
+            if self.match_token_type(TokenType::Identifier) {
+                let token = self.previous.clone().unwrap();
+                if fields.contains(&token.lexeme) || methods.contains(&token.lexeme) {
+                    let msg = format!("Field {} is already declared", token.lexeme);
+                    self.error(&token, &msg);
+                    self.panic_error = true;
+                    self.has_error = true;
+                }
 
+                let idx = self.make_identifier_constant(token.clone());
                 self.emit_opcode(OpCode::OpField(idx));
+                fields.push(token.lexeme);
+
+                self.match_token_type(TokenType::Comma);
+            } else if self.match_token_type(TokenType::Function) {
+                self.consume(TokenType::Identifier, "Expected a method name here");
+                let token = self.previous.clone().unwrap();
+                if fields.contains(&token.lexeme) || methods.contains(&token.lexeme) {
+                    let msg = format!("Method {} is already declared", token.lexeme);
+                    self.error(&token, &msg);
+                    self.panic_error = true;
+                    self.has_error = true;
+                }
+
+                let idx = self.make_identifier_constant(token.clone());
+                self.function(FunctionType::MethodType, token.lexeme.clone());
+                self.emit_opcode(OpCode::OpMethod(idx));
+                methods.push(token.lexeme);
+            } else {
+                break;
             }
-            self.match_token_type(TokenType::Comma);
             self.skip_empty_line();
         }
+
+        fields
     }
 
     fn argument_list(&mut self) -> u8 {
@@ -679,6 +1067,141 @@ impl Parser {
         arg_count
     }
 
+    // Lists can be written across multiple lines, so new lines between items are skipped.
+    fn list_items(&mut self) -> u8 {
+        let mut item_count: u8 = 0;
+
+        self.skip_empty_line();
+        let curr_token_type = self.grab_curr_token_type().unwrap();
+        if curr_token_type != TokenType::RightBracket {
+            loop {
+                self.expression();
+
+                if item_count == 255 {
+                    if let Some(ref token) = self.previous {
+                        self.error(token, "Can't have more than 255 items in a list literal");
+                        self.panic_error = true;
+                        self.has_error = true;
+                    }
+                }
+
+                item_count = item_count.wrapping_add(1);
+
+                self.skip_empty_line();
+                if !self.match_token_type(TokenType::Comma) {
+                    break;
+                }
+                self.skip_empty_line();
+
+                // Allows a trailing comma
+                if self.grab_curr_token_type().unwrap() == TokenType::RightBracket {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, "Expected ']' after list items");
+
+        item_count
+    }
+
+    // Same as list_items, but every entry is a key and value separated by ':'.
+    fn map_entries(&mut self) -> u8 {
+        let mut entry_count: u8 = 0;
+
+        self.skip_empty_line();
+        let curr_token_type = self.grab_curr_token_type().unwrap();
+        if curr_token_type != TokenType::RightBrace {
+            loop {
+                self.expression();
+                self.consume(TokenType::Colon, "Expected ':' after map key");
+                self.expression();
+
+                if entry_count == 255 {
+                    if let Some(ref token) = self.previous {
+                        self.error(token, "Can't have more than 255 entries in a map literal");
+                        self.panic_error = true;
+                        self.has_error = true;
+                    }
+                }
+
+                entry_count = entry_count.wrapping_add(1);
+
+                self.skip_empty_line();
+                if !self.match_token_type(TokenType::Comma) {
+                    break;
+                }
+                self.skip_empty_line();
+
+                // Allows a trailing comma
+                if self.grab_curr_token_type().unwrap() == TokenType::RightBrace {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after map entries");
+
+        entry_count
+    }
+
+    // `name = value` pairs. Both go on the stack, the name as a string constant.
+    fn named_instance_args(&mut self, fields: Option<&[String]>) -> u8 {
+        let mut field_count: u8 = 0;
+        let mut names: Vec<String> = Vec::new();
+
+        loop {
+            self.skip_empty_line();
+            if self.grab_curr_token_type().unwrap() == TokenType::RightBrace {
+                break;
+            }
+
+            self.consume(TokenType::Identifier, "Expected a field name here");
+            let token = self.previous.clone().unwrap();
+
+            let msg = if names.contains(&token.lexeme) {
+                Some(format!("Field {} is already given", token.lexeme))
+            } else if fields.is_some_and(|x| !x.contains(&token.lexeme)) {
+                Some(format!("Unknown field {}", token.lexeme))
+            } else {
+                None
+            };
+            if let Some(msg) = msg {
+                self.error(&token, &msg);
+                self.panic_error = true;
+                self.has_error = true;
+            }
+
+            let idx = self.make_identifier_constant(token.clone());
+            self.emit_opcode(OpCode::OpConstant(idx));
+            names.push(token.lexeme.clone());
+
+            self.consume(TokenType::Equal, "Expected '=' after the field name");
+            self.expression();
+
+            if field_count == 255 {
+                self.error(&token, "Can't have more than 255 fields");
+                self.panic_error = true;
+                self.has_error = true;
+            }
+            field_count = field_count.wrapping_add(1);
+
+            self.skip_empty_line();
+            if !self.match_token_type(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after fields");
+
+        if let Some(missing) = fields.and_then(|x| x.iter().find(|x| !names.contains(x))) {
+            let token = self.previous.clone().unwrap();
+            let msg = format!("Missing field {}", missing);
+            self.error(&token, &msg);
+            self.panic_error = true;
+            self.has_error = true;
+        }
+
+        field_count
+    }
+
     fn instance_args(&mut self) -> u8 {
         let mut arg_count: u8 = 0;
 
@@ -696,11 +1219,18 @@ impl Parser {
                     }
                 }
 
-                arg_count += 1;
+                arg_count = arg_count.wrapping_add(1);
 
+                self.skip_empty_line();
                 if !self.match_token_type(TokenType::Comma) {
                     break;
                 }
+                self.skip_empty_line();
+
+                // Allows a trailing comma
+                if self.grab_curr_token_type().unwrap() == TokenType::RightBrace {
+                    break;
+                }
             }
         }
         self.consume(TokenType::RightBrace, "Expected '}' after arguments");
@@ -722,14 +1252,26 @@ impl Parser {
         let op_set_code: OpCode;
 
         let idx = self.resolve_local(&token);
-        if idx == -1 {
-            // Global
-            let idx = self.make_identifier_constant(token.clone());
-            op_get_code = OpCode::OpGetGlobal(idx as usize);
-            op_set_code = OpCode::OpSetGlobal(idx as usize);
-        } else {
+        if idx != -1 {
             op_get_code = OpCode::OpGetLocal(idx as usize);
             op_set_code = OpCode::OpSetLocal(idx as usize);
+        } else if let Some(idx) = self.compiler.resolve_upvalue(&token) {
+            op_get_code = OpCode::OpGetUpvalue(idx);
+            op_set_code = OpCode::OpSetUpvalue(idx);
+        } else if token.lexeme == "self" {
+            self.error(&token, "Can't use self outside of a method");
+            self.panic_error = true;
+            self.has_error = true;
+            return;
+        } else {
+            // Global
+            if can_assign && self.grab_curr_token_type() == Some(TokenType::Equal) {
+                // Might not be a Struct anymore
+                self.structs.remove(&token.lexeme);
+            }
+            let slot = self.global_slot(&token);
+            op_get_code = OpCode::OpGetGlobal(slot);
+            op_set_code = OpCode::OpSetGlobal(slot);
         }
 
         if can_assign && self.match_token_type(TokenType::Equal) {
@@ -752,7 +1294,11 @@ impl Parser {
 
     fn parse_variable(&mut self) -> usize {
         self.consume(TokenType::Identifier, "Expected an Identifier name here");
+        self.declare_previous_var()
+    }
 
+    // Declares the name in the previous token, returning its slot when it's a global
+    fn declare_previous_var(&mut self) -> usize {
         // Defines the Local Variable here
         self.declare_var();
 
@@ -761,13 +1307,18 @@ impl Parser {
         }
 
         let token = self.previous.clone().unwrap();
-        self.make_identifier_constant(token)
+        self.structs.remove(&token.lexeme);
+        self.global_slot(&token)
+    }
+
+    fn global_slot(&mut self, token: &Token) -> usize {
+        let name = self.heap.intern(&token.lexeme);
+        self.global_slots.slot(name)
     }
 
     fn make_identifier_constant(&mut self, token: Token) -> usize {
-        self.add_value(Value::Obj(Obj::String(Rc::new(RefCell::new(StrObj::new(
-            token.lexeme,
-        ))))))
+        let str_obj = self.heap.intern(&token.lexeme);
+        self.add_value(Value::Obj(Obj::String(str_obj)))
     }
 
     // Only for local varables
@@ -804,31 +1355,37 @@ impl Parser {
     }
 
     fn resolve_local(&mut self, name: &Token) -> i32 {
-        for i in (0..self.compiler.locals.len()).rev() {
-            let local = &self.compiler.locals[i];
-            if self.is_eq_token_name(name, &local.name) {
-                if local.depth == LocalState::Uninit {
+        match self.compiler.resolve_local(name) {
+            Some((idx, is_uninit)) => {
+                if is_uninit {
                     self.error(name, "Can't read local variable in it's own init field.");
                     self.has_error = true;
                     self.panic_error = true;
                 }
-                return i as i32;
+                idx as i32
             }
+            None => -1,
         }
-
-        -1
     }
 
     fn add_local(&mut self, token: Token) {
         let local = Local {
             name: token,
             depth: LocalState::Uninit,
+            is_captured: false,
         };
 
         self.compiler.local_count += 1;
         self.compiler.locals.push(local);
     }
 
+    // Code with errors never runs, and its jumps might not be patched
+    fn optimize_chunk(&self, chunk: &mut Chunk) {
+        if self.optimize && !self.has_error {
+            optimizer::optimize(chunk);
+        }
+    }
+
     fn add_value(&mut self, value: Value) -> usize {
         self.current_chunk().add_value(value)
     }
@@ -837,29 +1394,92 @@ impl Parser {
         if let Some(ref token) = self.previous.clone() {
             // Potential Error in the future here, I'm referencing self.chunk rather than getting
             // chunk, is there a potential error? Self.chunk is current chunk...
-            self.current_chunk().write_code(code, token.line);
+            self.emit_opcode_at(code, token);
         }
     }
 
+    // Records the token's position with the opcode, so runtime errors can point at it.
+    fn emit_opcode_at(&mut self, code: OpCode, token: &Token) {
+        let length = token.span.len().max(1);
+        let columns = token.column..token.column + length;
+        self.current_chunk().write_code(code, token.line, columns);
+    }
+
+    // Gives back where the jump starts, so it can be patched later.
     fn emit_jump_code(&mut self, code: OpCode) -> usize {
         self.emit_opcode(code);
-        self.current_chunk().code.len() - 1
+        self.current_chunk().code.len() - JUMP_SIZE
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        let loop_offset = self.current_chunk().code.len() + JUMP_SIZE - loop_start;
+
+        if loop_offset > u16::MAX as usize {
+            if let Some(ref token) = self.previous {
+                self.error(token, "Loop body too large");
+                self.panic_error = true;
+                self.has_error = true;
+            }
+        }
+
+        self.emit_opcode(OpCode::OpLoop(loop_offset as u16));
     }
 
     fn patch_jump_code(&mut self, offset: usize) {
-        let jumps = self.current_chunk().code.len() - offset - 1;
+        let jumps = self.current_chunk().code.len() - offset - JUMP_SIZE;
 
-        match self.current_chunk().code.get_mut(offset) {
-            Some(code) => match code {
-                OpCode::OpJump(jump) => {
-                    *jump = jumps as u8;
-                }
-                OpCode::OpJumpIfFalse(jump) => {
-                    *jump = jumps as u8;
-                }
+        if jumps > u16::MAX as usize {
+            if let Some(ref token) = self.previous {
+                self.error(token, "Too much code to jump over");
+                self.panic_error = true;
+                self.has_error = true;
+            }
+        }
+
+        self.current_chunk().patch_jump(offset, jumps as u16);
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        self.compiler.loops.push(Loop {
+            start,
+            scope_depth: self.compiler.scope_depth,
+            breaks: Vec::new(),
+        });
+    }
+
+    // Every break lands here, after the loop is done.
+    fn end_loop(&mut self) {
+        if let Some(current_loop) = self.compiler.loops.pop() {
+            for jump in current_loop.breaks {
+                self.patch_jump_code(jump);
+            }
+        }
+    }
+
+    // Like end_scope for everything declared inside the loop, but the locals are kept since the
+    // code after break or continue still belongs to their scope.
+    fn pop_loop_locals(&mut self) {
+        let loop_depth = match self.compiler.loops.last() {
+            Some(current_loop) => current_loop.scope_depth,
+            None => return,
+        };
+
+        let mut codes: Vec<OpCode> = Vec::new();
+        for local in self.compiler.locals.iter().rev() {
+            match local.depth {
+                LocalState::Init(depth) if depth <= loop_depth => break,
                 _ => {}
-            },
-            None => {}
+            }
+
+            if local.is_captured {
+                codes.push(OpCode::OpCloseUpvalue);
+            } else {
+                codes.push(OpCode::OpPop);
+            }
+        }
+
+        for code in codes {
+            self.emit_opcode(code);
         }
     }
 
@@ -876,8 +1496,14 @@ impl Parser {
                 _ => {}
             }
 
+            // Captured locals get moved off the stack into their upvalue instead.
+            let is_captured = local.is_captured;
+            if is_captured {
+                self.emit_opcode(OpCode::OpCloseUpvalue);
+            } else {
+                self.emit_opcode(OpCode::OpPop);
+            }
             self.compiler.locals.pop();
-            self.emit_opcode(OpCode::OpPop);
             self.compiler.local_count -= 1;
         }
     }
@@ -897,7 +1523,8 @@ impl Parser {
                         break;
                     }
 
-                    self.error(token, "Unreadable Symbols");
+                    // The Scanner puts what went wrong in the lexeme
+                    self.error(token, &token.lexeme);
                     self.panic_error = true;
                     self.has_error = true;
                 }
@@ -924,24 +1551,23 @@ impl Parser {
         if self.panic_error {
             return;
         }
-        eprint!("[line {}] Error", token.line);
-
-        if token.token_type == TokenType::EOF {
-            eprint!(" at end of file");
-        } else if token.token_type == TokenType::Error {
-            // The message would be passed?
-            // But don't we still want to display the Token??
-        } else {
-            eprint!(" at {}", token);
+        if self.repl && token.token_type == TokenType::Eof {
+            // Anything reported before this was a real error, so more input won't help.
+            if !self.has_error {
+                self.incomplete.set(true);
+            }
+            return;
         }
 
-        eprintln!(": {message}");
+        self.diagnostics
+            .borrow_mut()
+            .push(Diagnostic::error(token, message));
     }
 
     fn synchronize(&mut self) {
         self.panic_error = false;
 
-        while self.grab_curr_token_type().unwrap() != TokenType::EOF {
+        while self.grab_curr_token_type().unwrap() != TokenType::Eof {
             // This might not be wanted since Newline is considered a terminator. But it is also a
             // token that we can randomly have. This makes it difficult when it comes to
             // syncrhonization blocks of code, like Structs
@@ -953,6 +1579,7 @@ impl Parser {
             match self.grab_curr_token_type().unwrap() {
                 TokenType::If
                 | TokenType::Let
+                | TokenType::Import
                 | TokenType::Function
                 | TokenType::Struct
                 | TokenType::Return
@@ -973,12 +1600,16 @@ impl Parser {
             ParseFn::Binary => self.binary(),
             ParseFn::Literal => self.literal(),
             ParseFn::String => self.string(),
+            ParseFn::Interpolation => self.interpolation(),
             ParseFn::Variable => self.variable(can_assign),
             ParseFn::And => self.parse_and(),
             ParseFn::Or => self.parse_or(),
             ParseFn::Call => self.call(),
             ParseFn::Dot => self.dot(can_assign),
             ParseFn::Instance => self.instance(),
+            ParseFn::List => self.list(),
+            ParseFn::Index => self.index(can_assign),
+            ParseFn::Map => self.map(),
         };
     }
 
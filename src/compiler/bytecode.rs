@@ -0,0 +1,395 @@
+use std::{fmt::Display, ops::Range};
+
+use super::{
+    chunk::{read_varint, write_varint, OpCode, JUMP_SIZE},
+    values::{FunctionObj, GlobalSlots, Obj, ObjData, UpvalueIdx, Value},
+};
+use crate::vm::heap::Heap;
+
+// Compiled files start with this, so `run` can tell them apart from source
+pub const MAGIC: &[u8; 5] = b"\x7fBITE";
+// Goes up whenever the format or the opcodes change, older files then have to be compiled again
+pub const VERSION: u16 = 1;
+
+// Why a compiled file couldn't be saved or loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BytecodeError {
+    // Doesn't start with MAGIC
+    NotBytecode,
+    // Made by a different version of Bite
+    WrongVersion(u16),
+    // Ends early or holds something a compiler never writes
+    Corrupt(String),
+    // Only numbers, strings and functions can be saved as constants
+    Unsupported(String),
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::NotBytecode => write!(f, "Not a compiled Bite file"),
+            BytecodeError::WrongVersion(version) => write!(
+                f,
+                "Compiled for bytecode version {}, this Bite reads version {}",
+                version, VERSION
+            ),
+            BytecodeError::Corrupt(message) => write!(f, "Corrupt compiled file: {}", message),
+            BytecodeError::Unsupported(message) => write!(f, "Can't save {}", message),
+        }
+    }
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Turns a compiled script into bytes that `load` reads back. `slots` are the globals the script
+// was compiled with. The source isn't saved, only the lines and columns of each opcode.
+//
+// The layout, with every count and index written like chunk operands:
+//   MAGIC, VERSION as two big endian bytes
+//   global names
+//   the script's function: name, arity, upvalues, constants, code, line runs, columns
+// Functions declared inside are saved in place of their constant.
+pub fn save(
+    heap: &Heap,
+    function: &FunctionObj,
+    slots: &GlobalSlots,
+) -> Result<Vec<u8>, BytecodeError> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&VERSION.to_be_bytes());
+
+    write_varint(&mut out, slots.names.len());
+    for name in &slots.names {
+        write_str(&mut out, &heap.string(*name).name);
+    }
+
+    write_function(heap, function, &mut out)?;
+    Ok(out)
+}
+
+fn write_function(
+    heap: &Heap,
+    function: &FunctionObj,
+    out: &mut Vec<u8>,
+) -> Result<(), BytecodeError> {
+    match function.name {
+        Some(ref name) => {
+            out.push(1);
+            write_str(out, name);
+        }
+        None => out.push(0),
+    }
+    out.push(function.arity);
+
+    write_varint(out, function.upvalues.len());
+    for upvalue in &function.upvalues {
+        out.push(upvalue.is_local as u8);
+        write_varint(out, upvalue.index);
+    }
+
+    let chunk = &function.chunk;
+    write_varint(out, chunk.values.len());
+    for value in &chunk.values {
+        match *value {
+            Value::Number(num) => {
+                out.push(0);
+                out.extend_from_slice(&num.to_le_bytes());
+            }
+            Value::Obj(Obj::String(idx)) => {
+                out.push(1);
+                write_str(out, &heap.string(idx).name);
+            }
+            Value::Obj(Obj::Function(idx)) => {
+                out.push(2);
+                write_function(heap, heap.function(idx), out)?;
+            }
+            value => {
+                let message = format!("the constant {}", heap.display(&value));
+                return Err(BytecodeError::Unsupported(message));
+            }
+        }
+    }
+
+    write_varint(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+
+    // Lines are saved as runs of opcodes on the same line, most lines have several
+    let positions: Vec<(usize, Range<usize>)> = chunk.positions().collect();
+
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (line, _) in &positions {
+        match runs.last_mut() {
+            Some(run) if run.0 == *line => run.1 += 1,
+            _ => runs.push((*line, 1)),
+        }
+    }
+    write_varint(out, runs.len());
+    for (line, count) in runs {
+        write_varint(out, line);
+        write_varint(out, count);
+    }
+
+    for (_, columns) in positions {
+        write_varint(out, columns.start);
+        write_varint(out, columns.len());
+    }
+
+    Ok(())
+}
+
+fn write_str(out: &mut Vec<u8>, string: &str) {
+    write_varint(out, string.len());
+    out.extend_from_slice(string.as_bytes());
+}
+
+// Reads back a script saved with `save`. Its globals get slots in `slots`, which can already
+// have some, and the code is changed to use those. Strings and functions go into `heap` without
+// collecting, like they do while compiling.
+//
+// The checks are there to catch damaged files, not to make any bytes safe to run.
+pub fn load(
+    heap: &mut Heap,
+    slots: &mut GlobalSlots,
+    bytes: &[u8],
+) -> Result<FunctionObj, BytecodeError> {
+    if !is_bytecode(bytes) {
+        return Err(BytecodeError::NotBytecode);
+    }
+    let mut reader = Reader {
+        bytes,
+        offset: MAGIC.len(),
+    };
+
+    let version = u16::from_be_bytes([reader.byte()?, reader.byte()?]);
+    if version != VERSION {
+        return Err(BytecodeError::WrongVersion(version));
+    }
+
+    // The file's globals go into a copy, so a damaged file doesn't leave slots behind in `slots`
+    let mut loaded = slots.clone();
+    // The slot each of the file's globals has in `loaded`
+    let mut globals = Vec::new();
+    for _ in 0..reader.varint()? {
+        let name = heap.intern(&reader.string()?);
+        globals.push(loaded.slot(name));
+    }
+
+    let function = read_function(heap, &globals, &mut reader)?;
+    if reader.offset != bytes.len() {
+        return Err(corrupt("there is more after the script"));
+    }
+
+    *slots = loaded;
+    Ok(function)
+}
+
+fn read_function(
+    heap: &mut Heap,
+    globals: &[usize],
+    reader: &mut Reader,
+) -> Result<FunctionObj, BytecodeError> {
+    let mut function = FunctionObj::new();
+
+    function.name = match reader.byte()? {
+        0 => None,
+        1 => Some(reader.string()?),
+        _ => return Err(corrupt("a function name is malformed")),
+    };
+    function.arity = reader.byte()?;
+
+    for _ in 0..reader.varint()? {
+        let is_local = match reader.byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(corrupt("an upvalue is malformed")),
+        };
+        let index = reader.varint()?;
+        function.upvalues.push(UpvalueIdx { index, is_local });
+    }
+
+    for _ in 0..reader.varint()? {
+        let value = match reader.byte()? {
+            0 => {
+                let mut num = [0; 8];
+                num.copy_from_slice(reader.take(8)?);
+                Value::Number(f64::from_le_bytes(num))
+            }
+            1 => Value::Obj(Obj::String(heap.intern(&reader.string()?))),
+            2 => {
+                let inner = read_function(heap, globals, reader)?;
+                Value::Obj(Obj::Function(heap.alloc(ObjData::Function(inner))))
+            }
+            tag => return Err(corrupt(&format!("unknown constant type {}", tag))),
+        };
+        function.chunk.values.push(value);
+    }
+
+    let len = reader.varint()?;
+    let code = reader.take(len)?;
+
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let (op, next) =
+            OpCode::decode(code, offset).ok_or_else(|| corrupt("unknown or cut off opcode"))?;
+        instructions.push((offset, op));
+        offset = next;
+    }
+
+    let mut lines = Vec::new();
+    for _ in 0..reader.varint()? {
+        let line = reader.varint()?;
+        let count = reader.varint()?;
+        if count > instructions.len() - lines.len() {
+            return Err(corrupt("there are more lines than opcodes"));
+        }
+        lines.extend(std::iter::repeat_n(line, count));
+    }
+    if lines.len() != instructions.len() {
+        return Err(corrupt("some opcodes have no line"));
+    }
+
+    let mut columns = Vec::new();
+    for _ in 0..instructions.len() {
+        let start = reader.varint()?;
+        let len = reader.varint()?;
+        columns.push(start..start.saturating_add(len));
+    }
+
+    // Global slots can be different in the module loading the file, which can change the size
+    // of their opcodes. Everything after moves with them, so jumps get their distance again.
+    let ops = instructions
+        .iter()
+        .map(|(_, op)| {
+            let op = match *op {
+                OpCode::OpDefineGlobal(slot) => OpCode::OpDefineGlobal(global(globals, slot)?),
+                OpCode::OpGetGlobal(slot) => OpCode::OpGetGlobal(global(globals, slot)?),
+                OpCode::OpSetGlobal(slot) => OpCode::OpSetGlobal(global(globals, slot)?),
+                op => op,
+            };
+            check_constants(&op, &function.chunk.values, heap)?;
+            Ok(op)
+        })
+        .collect::<Result<Vec<OpCode>, BytecodeError>>()?;
+
+    let mut new_offsets = Vec::with_capacity(ops.len() + 1);
+    let mut new_offset = 0;
+    for op in &ops {
+        new_offsets.push(new_offset);
+        new_offset += op.size();
+    }
+    new_offsets.push(new_offset);
+
+    let new_offset_of = |old: usize| {
+        instructions
+            .binary_search_by_key(&old, |x| x.0)
+            .ok()
+            .or((old == code.len()).then_some(instructions.len()))
+            .map(|idx| new_offsets[idx])
+    };
+
+    for (idx, ((old_offset, _), op)) in instructions.iter().zip(ops).enumerate() {
+        let op = match op.jump_target(*old_offset).map(new_offset_of) {
+            Some(Some(target)) => {
+                let next = new_offsets[idx] + JUMP_SIZE;
+                let jump = match op {
+                    OpCode::OpLoop(_) => next - target,
+                    _ => target.checked_sub(next).unwrap_or(usize::MAX),
+                };
+                let jump =
+                    u16::try_from(jump).map_err(|_| corrupt("a jump goes too far once loaded"))?;
+
+                match op {
+                    OpCode::OpJump(_) => OpCode::OpJump(jump),
+                    OpCode::OpJumpIfFalse(_) => OpCode::OpJumpIfFalse(jump),
+                    _ => OpCode::OpLoop(jump),
+                }
+            }
+            Some(None) => return Err(corrupt("a jump lands outside of the code")),
+            None => op,
+        };
+
+        function
+            .chunk
+            .write_code(op, lines[idx], columns[idx].clone());
+    }
+
+    Ok(function)
+}
+
+fn global(globals: &[usize], slot: usize) -> Result<usize, BytecodeError> {
+    globals
+        .get(slot)
+        .copied()
+        .ok_or_else(|| corrupt("a global has no name"))
+}
+
+// Opcodes with a constant operand need the right kind of constant there, the VM doesn't check.
+fn check_constants(op: &OpCode, values: &[Value], heap: &Heap) -> Result<(), BytecodeError> {
+    let (idx, is_right) = match *op {
+        OpCode::OpConstant(idx) => (idx, true),
+        OpCode::OpClosure(idx) => (
+            idx,
+            matches!(values.get(idx), Some(Value::Obj(Obj::Function(_)))),
+        ),
+        OpCode::OpClass(idx)
+        | OpCode::OpSetProperty(idx)
+        | OpCode::OpGetProperty(idx)
+        | OpCode::OpField(idx)
+        | OpCode::OpMethod(idx)
+        | OpCode::OpInvoke(idx, _)
+        | OpCode::OpImport(idx) => (
+            idx,
+            matches!(values.get(idx), Some(Value::Obj(Obj::String(_)))),
+        ),
+        _ => return Ok(()),
+    };
+
+    match values.get(idx) {
+        Some(_) if is_right => Ok(()),
+        Some(value) => Err(corrupt(&format!(
+            "{} can't use the constant {}",
+            op,
+            heap.display(value)
+        ))),
+        None => Err(corrupt(&format!("{} uses a constant that isn't there", op))),
+    }
+}
+
+fn corrupt(message: &str) -> BytecodeError {
+    BytecodeError::Corrupt(message.to_string())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> Result<usize, BytecodeError> {
+        read_varint(self.bytes, &mut self.offset).ok_or_else(|| corrupt("the file ends early"))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| corrupt("the file ends early"))?;
+
+        let bytes = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let len = self.varint()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| corrupt("a string isn't valid UTF-8"))
+    }
+}
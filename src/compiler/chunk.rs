@@ -1,16 +1,19 @@
-use std::fmt::Display;
+use std::{fmt::Display, ops::Range, rc::Rc};
 
 use super::values::Value;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum OpCode {
     OpReturn,
     OpPop,
+    // Only emitted by the REPL, so bare expressions show their value
+    OpPrint,
     OpCall(u8),
 
-    OpJumpIfFalse(u8),
-    OpJump(u8),
-    OpLoop(u8),
+    OpJumpIfFalse(u16),
+    OpJump(u16),
+    OpLoop(u16),
 
     // Literal
     OpTrue,
@@ -21,6 +24,10 @@ pub enum OpCode {
     OpGreater,
     OpLess,
     OpEqual,
+    // Only made by the optimizer, each is the one above followed by OpNot
+    OpNotEqual,
+    OpGreaterEqual,
+    OpLessEqual,
 
     // Primary
     OpConstant(usize),
@@ -30,6 +37,12 @@ pub enum OpCode {
     OpGetLocal(usize),
     OpSetLocal(usize),
 
+    // Closures
+    OpClosure(usize),
+    OpGetUpvalue(usize),
+    OpSetUpvalue(usize),
+    OpCloseUpvalue,
+
     // Unary
     OpNegate,
     OpNot,
@@ -39,12 +52,281 @@ pub enum OpCode {
     OpSubtract,
     OpMultiply,
     OpDivide,
+    OpModulo,
+    OpFloorDivide,
+    OpPower,
 
     // Class
     OpClass(usize),
     OpSetProperty(usize),
     OpGetProperty(usize),
     OpField(usize),
+    // Builds an instance from `name = value` pairs left on the stack
+    OpNamedInstance(u8),
+    OpMethod(usize),
+    // Calls a method straight off the receiver, without making a bound method first
+    OpInvoke(usize, u8),
+
+    // List
+    OpBuildList(u8),
+    OpGetIndex,
+    OpSetIndex,
+
+    // Map
+    OpBuildMap(u8),
+
+    // Turns the values into strings and joins them, for interpolated strings
+    OpBuildString(u8),
+
+    // Loads the module at the path in the constant, running it the first time
+    OpImport(usize),
+}
+
+// Bytes a jump takes in a chunk: the opcode and a u16. Jumps always take this much, so they can
+// be patched once the code they jump over is known.
+pub const JUMP_SIZE: usize = 3;
+
+impl OpCode {
+    // What the operator gives for two numbers, so the VM and the optimizer always agree. None
+    // for opcodes that aren't arithmetic. Dividing by zero isn't checked here.
+    pub fn apply_numbers(&self, a: f64, b: f64) -> Option<f64> {
+        match self {
+            OpCode::OpAdd => Some(a + b),
+            OpCode::OpSubtract => Some(a - b),
+            OpCode::OpMultiply => Some(a * b),
+            OpCode::OpDivide => Some(a / b),
+            // Both round down, so -7 % 3 is 2 and -7 div 3 is -3
+            OpCode::OpModulo => Some(a - b * (a / b).floor()),
+            OpCode::OpFloorDivide => Some((a / b).floor()),
+            OpCode::OpPower => Some(a.powf(b)),
+            _ => None,
+        }
+    }
+
+    // Where a jump starting at `offset` goes, the VM has already read the jump when it jumps.
+    // None for anything that isn't a jump.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        match *self {
+            OpCode::OpJump(jump) | OpCode::OpJumpIfFalse(jump) => {
+                Some(offset + JUMP_SIZE + jump as usize)
+            }
+            OpCode::OpLoop(jump) => (offset + JUMP_SIZE).checked_sub(jump as usize),
+            _ => None,
+        }
+    }
+
+    // The index of the constant the opcode reads, None for opcodes that don't read one
+    pub fn constant_mut(&mut self) -> Option<&mut usize> {
+        match self {
+            OpCode::OpConstant(idx)
+            | OpCode::OpClosure(idx)
+            | OpCode::OpClass(idx)
+            | OpCode::OpSetProperty(idx)
+            | OpCode::OpGetProperty(idx)
+            | OpCode::OpField(idx)
+            | OpCode::OpMethod(idx)
+            | OpCode::OpInvoke(idx, _)
+            | OpCode::OpImport(idx) => Some(idx),
+            _ => None,
+        }
+    }
+
+    // How many bytes the opcode takes in a chunk
+    pub fn size(&self) -> usize {
+        1 + match *self {
+            OpCode::OpCall(_)
+            | OpCode::OpNamedInstance(_)
+            | OpCode::OpBuildList(_)
+            | OpCode::OpBuildMap(_)
+            | OpCode::OpBuildString(_) => 1,
+            OpCode::OpJumpIfFalse(_) | OpCode::OpJump(_) | OpCode::OpLoop(_) => 2,
+            OpCode::OpConstant(idx)
+            | OpCode::OpDefineGlobal(idx)
+            | OpCode::OpGetGlobal(idx)
+            | OpCode::OpSetGlobal(idx)
+            | OpCode::OpGetLocal(idx)
+            | OpCode::OpSetLocal(idx)
+            | OpCode::OpClosure(idx)
+            | OpCode::OpGetUpvalue(idx)
+            | OpCode::OpSetUpvalue(idx)
+            | OpCode::OpClass(idx)
+            | OpCode::OpSetProperty(idx)
+            | OpCode::OpGetProperty(idx)
+            | OpCode::OpField(idx)
+            | OpCode::OpMethod(idx)
+            | OpCode::OpImport(idx) => varint_size(idx),
+            OpCode::OpInvoke(idx, _) => varint_size(idx) + 1,
+            _ => 0,
+        }
+    }
+
+    // Reads the opcode at `offset` back out of `code`, with the offset of the one after it. None
+    // when the bytes aren't an opcode, which only happens for code that didn't come from a Chunk.
+    pub fn decode(code: &[u8], offset: usize) -> Option<(OpCode, usize)> {
+        let tag = *code.get(offset)?;
+        let mut offset = offset + 1;
+
+        let mut byte = || {
+            offset += 1;
+            code.get(offset - 1).copied()
+        };
+        let op = match tag {
+            0 => OpCode::OpReturn,
+            1 => OpCode::OpPop,
+            2 => OpCode::OpPrint,
+            3 => OpCode::OpCall(byte()?),
+            4..=6 => {
+                let jump = u16::from_be_bytes([byte()?, byte()?]);
+                match tag {
+                    4 => OpCode::OpJumpIfFalse(jump),
+                    5 => OpCode::OpJump(jump),
+                    _ => OpCode::OpLoop(jump),
+                }
+            }
+            7 => OpCode::OpTrue,
+            8 => OpCode::OpFalse,
+            9 => OpCode::OpNone,
+            10 => OpCode::OpGreater,
+            11 => OpCode::OpLess,
+            12 => OpCode::OpEqual,
+            13 => OpCode::OpNotEqual,
+            14 => OpCode::OpGreaterEqual,
+            15 => OpCode::OpLessEqual,
+            25 => OpCode::OpCloseUpvalue,
+            26 => OpCode::OpNegate,
+            27 => OpCode::OpNot,
+            28 => OpCode::OpAdd,
+            29 => OpCode::OpSubtract,
+            30 => OpCode::OpMultiply,
+            31 => OpCode::OpDivide,
+            32 => OpCode::OpModulo,
+            33 => OpCode::OpFloorDivide,
+            34 => OpCode::OpPower,
+            39 => OpCode::OpNamedInstance(byte()?),
+            42 => OpCode::OpBuildList(byte()?),
+            43 => OpCode::OpGetIndex,
+            44 => OpCode::OpSetIndex,
+            45 => OpCode::OpBuildMap(byte()?),
+            46 => OpCode::OpBuildString(byte()?),
+            _ => {
+                let idx = read_varint(code, &mut offset)?;
+                match tag {
+                    16 => OpCode::OpConstant(idx),
+                    17 => OpCode::OpDefineGlobal(idx),
+                    18 => OpCode::OpGetGlobal(idx),
+                    19 => OpCode::OpSetGlobal(idx),
+                    20 => OpCode::OpGetLocal(idx),
+                    21 => OpCode::OpSetLocal(idx),
+                    22 => OpCode::OpClosure(idx),
+                    23 => OpCode::OpGetUpvalue(idx),
+                    24 => OpCode::OpSetUpvalue(idx),
+                    35 => OpCode::OpClass(idx),
+                    36 => OpCode::OpSetProperty(idx),
+                    37 => OpCode::OpGetProperty(idx),
+                    38 => OpCode::OpField(idx),
+                    40 => OpCode::OpMethod(idx),
+                    41 => {
+                        let args_count = *code.get(offset)?;
+                        offset += 1;
+                        OpCode::OpInvoke(idx, args_count)
+                    }
+                    47 => OpCode::OpImport(idx),
+                    _ => return None,
+                }
+            }
+        };
+
+        Some((op, offset))
+    }
+
+    // The opcode's byte, the operands follow it
+    fn tag(&self) -> u8 {
+        match self {
+            OpCode::OpReturn => 0,
+            OpCode::OpPop => 1,
+            OpCode::OpPrint => 2,
+            OpCode::OpCall(_) => 3,
+            OpCode::OpJumpIfFalse(_) => 4,
+            OpCode::OpJump(_) => 5,
+            OpCode::OpLoop(_) => 6,
+            OpCode::OpTrue => 7,
+            OpCode::OpFalse => 8,
+            OpCode::OpNone => 9,
+            OpCode::OpGreater => 10,
+            OpCode::OpLess => 11,
+            OpCode::OpEqual => 12,
+            OpCode::OpNotEqual => 13,
+            OpCode::OpGreaterEqual => 14,
+            OpCode::OpLessEqual => 15,
+            OpCode::OpConstant(_) => 16,
+            OpCode::OpDefineGlobal(_) => 17,
+            OpCode::OpGetGlobal(_) => 18,
+            OpCode::OpSetGlobal(_) => 19,
+            OpCode::OpGetLocal(_) => 20,
+            OpCode::OpSetLocal(_) => 21,
+            OpCode::OpClosure(_) => 22,
+            OpCode::OpGetUpvalue(_) => 23,
+            OpCode::OpSetUpvalue(_) => 24,
+            OpCode::OpCloseUpvalue => 25,
+            OpCode::OpNegate => 26,
+            OpCode::OpNot => 27,
+            OpCode::OpAdd => 28,
+            OpCode::OpSubtract => 29,
+            OpCode::OpMultiply => 30,
+            OpCode::OpDivide => 31,
+            OpCode::OpModulo => 32,
+            OpCode::OpFloorDivide => 33,
+            OpCode::OpPower => 34,
+            OpCode::OpClass(_) => 35,
+            OpCode::OpSetProperty(_) => 36,
+            OpCode::OpGetProperty(_) => 37,
+            OpCode::OpField(_) => 38,
+            OpCode::OpNamedInstance(_) => 39,
+            OpCode::OpMethod(_) => 40,
+            OpCode::OpInvoke(_, _) => 41,
+            OpCode::OpBuildList(_) => 42,
+            OpCode::OpGetIndex => 43,
+            OpCode::OpSetIndex => 44,
+            OpCode::OpBuildMap(_) => 45,
+            OpCode::OpBuildString(_) => 46,
+            OpCode::OpImport(_) => 47,
+        }
+    }
+
+    fn encode(&self, code: &mut Vec<u8>) {
+        code.push(self.tag());
+
+        match *self {
+            OpCode::OpCall(count)
+            | OpCode::OpNamedInstance(count)
+            | OpCode::OpBuildList(count)
+            | OpCode::OpBuildMap(count)
+            | OpCode::OpBuildString(count) => code.push(count),
+            OpCode::OpJumpIfFalse(jump) | OpCode::OpJump(jump) | OpCode::OpLoop(jump) => {
+                code.extend_from_slice(&jump.to_be_bytes())
+            }
+            OpCode::OpConstant(idx)
+            | OpCode::OpDefineGlobal(idx)
+            | OpCode::OpGetGlobal(idx)
+            | OpCode::OpSetGlobal(idx)
+            | OpCode::OpGetLocal(idx)
+            | OpCode::OpSetLocal(idx)
+            | OpCode::OpClosure(idx)
+            | OpCode::OpGetUpvalue(idx)
+            | OpCode::OpSetUpvalue(idx)
+            | OpCode::OpClass(idx)
+            | OpCode::OpSetProperty(idx)
+            | OpCode::OpGetProperty(idx)
+            | OpCode::OpField(idx)
+            | OpCode::OpMethod(idx)
+            | OpCode::OpImport(idx) => write_varint(code, idx),
+            OpCode::OpInvoke(idx, args_count) => {
+                write_varint(code, idx);
+                code.push(args_count);
+            }
+            _ => {}
+        }
+    }
 }
 
 impl Display for OpCode {
@@ -53,26 +335,181 @@ impl Display for OpCode {
     }
 }
 
+// Indices are written 7 bits a byte, lowest first, with the top bit set on every byte but the
+// last. Most of them are small, so they take a single byte.
+pub(super) fn write_varint(code: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        code.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    code.push(value as u8);
+}
+
+pub(super) fn read_varint(code: &[u8], offset: &mut usize) -> Option<usize> {
+    let mut value: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *code.get(*offset)?;
+        *offset += 1;
+        if shift >= usize::BITS {
+            return None;
+        }
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+fn varint_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+// A line and where the code that came from it ends, the run starts where the one before ends
+#[derive(Debug, Clone, PartialEq)]
+struct LineRun {
+    line: usize,
+    end: usize,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
-    pub line: Vec<usize>,
+    // Each instruction is its opcode's byte followed by its operands, read with read_code
+    pub code: Vec<u8>,
+    // Lines of the code in order, most lines have several instructions so they're only stored once
+    lines: Vec<LineRun>,
+    // Runs of instructions pointing at the same columns of their line, only read back for errors
+    // so they're packed like operands: the bytes of code since the run before, the first column
+    // (starting at 1) and how many columns
+    columns: Vec<u8>,
+    // Where the last run starts and its columns, to know when the next one begins
+    last_columns: Option<(usize, Range<usize>)>,
     pub values: Vec<Value>,
+    // The code this chunk was compiled from, so errors can show the line they happened on
+    pub source: Rc<str>,
 }
 
 impl Chunk {
     pub fn new() -> Self {
         Chunk {
             code: Vec::new(),
-            line: Vec::new(),
+            lines: Vec::new(),
+            columns: Vec::new(),
+            last_columns: None,
             values: Vec::new(),
+            source: Rc::from(""),
+        }
+    }
+
+    // Gives back the offset the opcode starts at.
+    pub fn write_code(&mut self, code: OpCode, line: usize, columns: Range<usize>) -> usize {
+        let offset = self.code.len();
+        code.encode(&mut self.code);
+
+        let end = self.code.len();
+        match self.lines.last_mut() {
+            Some(run) if run.line == line => run.end = end,
+            _ => self.lines.push(LineRun { line, end }),
+        }
+        if self
+            .last_columns
+            .as_ref()
+            .is_none_or(|(_, last)| *last != columns)
+        {
+            let since = match self.last_columns {
+                Some((start, _)) => offset - start,
+                None => offset,
+            };
+            write_varint(&mut self.columns, since);
+            write_varint(&mut self.columns, columns.start);
+            write_varint(&mut self.columns, columns.len());
+            self.last_columns = Some((offset, columns));
+        }
+
+        offset
+    }
+
+    // The opcode at `offset` and the offset of the one after it.
+    pub fn read_code(&self, offset: usize) -> (OpCode, usize) {
+        OpCode::decode(&self.code, offset).expect("Chunks only hold opcodes from write_code")
+    }
+
+    // Every opcode with the offset it starts at
+    pub fn instructions(&self) -> impl Iterator<Item = (usize, OpCode)> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            if offset >= self.code.len() {
+                return None;
+            }
+            let start = offset;
+            let (code, next) = self.read_code(offset);
+            offset = next;
+            Some((start, code))
+        })
+    }
+
+    // Sets how far the jump starting at `offset` goes.
+    pub fn patch_jump(&mut self, offset: usize, jump: u16) {
+        self.code[offset + 1..offset + JUMP_SIZE].copy_from_slice(&jump.to_be_bytes());
+    }
+
+    // Line of the instruction that has the byte at `offset`
+    pub fn line_at(&self, offset: usize) -> usize {
+        let idx = self.lines.partition_point(|run| run.end <= offset);
+        match self.lines.get(idx).or(self.lines.last()) {
+            Some(run) => run.line,
+            None => 0,
         }
     }
 
-    pub fn write_code(&mut self, code: OpCode, line: usize) -> usize {
-        self.code.push(code);
-        self.line.push(line);
-        self.code.len() - 1
+    // Columns of the instruction that has the byte at `offset`. Goes through the runs from the
+    // start, use positions to get them for every instruction.
+    pub fn columns_at(&self, offset: usize) -> Range<usize> {
+        self.column_runs()
+            .take_while(|(start, _)| *start <= offset)
+            .last()
+            .map_or(0..0, |(_, columns)| columns)
+    }
+
+    // The line and columns of every instruction, in order
+    pub fn positions(&self) -> impl Iterator<Item = (usize, Range<usize>)> + '_ {
+        let mut runs = self.column_runs().peekable();
+        let mut columns = 0..0;
+        let mut line_idx = 0;
+
+        self.instructions().map(move |(offset, _)| {
+            while let Some((_, next)) = runs.next_if(|(start, _)| *start <= offset) {
+                columns = next;
+            }
+            while self
+                .lines
+                .get(line_idx)
+                .is_some_and(|run| run.end <= offset)
+            {
+                line_idx += 1;
+            }
+            let line = self.lines.get(line_idx).or(self.lines.last());
+
+            (line.map_or(0, |run| run.line), columns.clone())
+        })
+    }
+
+    // Each run of columns with the offset it starts at
+    fn column_runs(&self) -> impl Iterator<Item = (usize, Range<usize>)> + '_ {
+        let mut idx = 0;
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            offset += read_varint(&self.columns, &mut idx)?;
+            let start = read_varint(&self.columns, &mut idx)?;
+            let len = read_varint(&self.columns, &mut idx)?;
+            Some((offset, start..start + len))
+        })
     }
 
     pub fn add_value(&mut self, value: Value) -> usize {
@@ -80,7 +517,7 @@ impl Chunk {
         self.values.len() - 1
     }
 
-    pub fn get_const(&mut self, idx: usize) -> Value {
+    pub fn get_const(&self, idx: usize) -> Value {
         //match self.values[idx as usize] {
         //    Value::None | Value::Number(_) | Value::Boolean(_) => self.values[idx as usize].clone(),
         //    Value::Obj(ref mut obj) => {
@@ -117,6 +554,12 @@ impl Chunk {
         //        Value::Obj(new_obj)
         //    }
         //}
-        self.values[idx as usize].clone()
+        self.values[idx]
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::new()
     }
 }
@@ -0,0 +1,136 @@
+use std::{fmt::Display, ops::Range};
+
+use crate::scanner::{Token, TokenType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+// Something the Parser found wrong with the source. The caller decides how to show it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    // Char offsets of the token it points at, inside the source
+    pub span: Range<usize>,
+    // What the token looked like, None when it's the end of file or symbols the Scanner couldn't
+    // read
+    pub found: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(token: &Token, message: &str) -> Self {
+        let found = match token.token_type {
+            TokenType::Eof | TokenType::Error => None,
+            TokenType::NewLine => Some("new line".to_string()),
+            _ => Some(token.lexeme.clone()),
+        };
+
+        Diagnostic {
+            severity: Severity::Error,
+            message: message.to_string(),
+            line: token.line,
+            column: token.column,
+            span: token.span.clone(),
+            found,
+        }
+    }
+
+    // rustc style output: the message, followed by the line it happened on with a caret under
+    // the token.
+    pub fn render(&self, source: &str) -> String {
+        let columns = self.column..self.column + self.span.len().max(1);
+        format!("{}\n{}", self, render_snippet(source, self.line, columns))
+    }
+}
+
+// Shows the line from the source with carets under the given columns. Both compile and runtime
+// errors use this.
+pub fn render_snippet(source: &str, line: usize, columns: Range<usize>) -> String {
+    let line_str = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+    let line_len = line_str.chars().count();
+
+    // Tokens that go past the line, like strings over multiple lines, only get carets up to the
+    // end of it.
+    let start = columns.start.max(1);
+    let end = columns.end.min(line_len + 1).max(start + 1);
+
+    let gutter = " ".repeat(line.to_string().len());
+    // Tabs are kept so the carets line up with tab indented code
+    let padding: String = line_str
+        .chars()
+        .chain(std::iter::repeat(' '))
+        .take(start - 1)
+        .map(|x| if x == '\t' { '\t' } else { ' ' })
+        .collect();
+    let carets = "^".repeat(end - start);
+
+    format!("{gutter} |\n{line} | {line_str}\n{gutter} | {padding}{carets}")
+}
+
+// The longest run of calls looked for when folding a trace, like f calling g calling f again.
+const MAX_REPEATING_CALLS: usize = 8;
+
+// Deep recursion leaves the same calls in a trace over and over. Each run of calls that repeats
+// is kept once, followed by how many more times it came up.
+pub fn compact_calls(calls: &[String]) -> Vec<String> {
+    let mut compact = Vec::new();
+    let mut start = 0;
+
+    while start < calls.len() {
+        // The run that folds away the most lines, the shorter one when two fold the same
+        let (mut size, mut repeats) = (1, 0);
+        for run_size in 1..=MAX_REPEATING_CALLS.min(calls.len() - start) {
+            let run = &calls[start..start + run_size];
+            let mut run_repeats = 0;
+            while calls
+                .get(start + run_size * (run_repeats + 1)..)
+                .is_some_and(|rest| rest.starts_with(run))
+            {
+                run_repeats += 1;
+            }
+
+            if run_size * run_repeats > size * repeats {
+                (size, repeats) = (run_size, run_repeats);
+            }
+        }
+
+        compact.extend_from_slice(&calls[start..start + size]);
+        match (size, repeats) {
+            (_, 0) => {}
+            (1, _) => compact.push(format!("|   ... {} more times", repeats)),
+            _ => compact.push(format!(
+                "|   ... the last {} calls {} more times",
+                size, repeats
+            )),
+        }
+        start += size * (repeats + 1);
+    }
+
+    compact
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[line {}:{}] {}", self.line, self.column, self.severity)?;
+
+        match self.found {
+            Some(ref found) => write!(f, " at '{}'", found)?,
+            None if self.span.is_empty() => write!(f, " at end of file")?,
+            None => {}
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
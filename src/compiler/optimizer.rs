@@ -0,0 +1,218 @@
+use std::{mem, ops::Range};
+
+use super::{
+    chunk::{Chunk, OpCode, JUMP_SIZE},
+    values::Value,
+};
+
+// An opcode of the optimized chunk, with the position it came from
+struct Instruction {
+    code: OpCode,
+    line: usize,
+    columns: Range<usize>,
+    // Index of the instruction before optimizing, jumps need it to find where they went
+    old_idx: usize,
+    // Something jumps here, so it can't be folded into the opcode before it
+    is_target: bool,
+}
+
+// Rewrites the chunk into an equal one that does less work:
+// - arithmetic and negation on number constants is done here, `(-1 + 2) * 3` becomes 3
+// - a comparison followed by OpNot becomes one opcode, like OpLessEqual
+// - jumps landing on an OpJump go straight to where that one goes
+//
+// Every opcode keeps the line and columns of one it replaced, so errors still point at the same
+// place. Nothing gets folded across a jump target, since the code before it doesn't always run.
+pub fn optimize(chunk: &mut Chunk) {
+    let old: Vec<(usize, OpCode)> = chunk.instructions().collect();
+    let positions: Vec<(usize, Range<usize>)> = chunk.positions().collect();
+    let targets = jump_targets(&old, chunk.code.len());
+
+    let mut is_target = vec![false; old.len() + 1];
+    for target in targets.iter().flatten() {
+        is_target[*target] = true;
+    }
+
+    // Where each old opcode ended up, only right for jump targets and the end of the chunk
+    let mut new_idx = vec![0; old.len() + 1];
+    let mut instructions: Vec<Instruction> = Vec::with_capacity(old.len());
+
+    for (old_idx, ((_, code), (line, columns))) in old.iter().zip(positions).enumerate() {
+        new_idx[old_idx] = instructions.len();
+        instructions.push(Instruction {
+            code: *code,
+            line,
+            columns,
+            old_idx,
+            is_target: is_target[old_idx],
+        });
+
+        // A fold can make another one possible, like the constant made by -1 in -1 + 2
+        while fold(chunk, &mut instructions) {}
+    }
+    new_idx[old.len()] = instructions.len();
+    drop_unused_values(chunk, &mut instructions);
+
+    // Jumps take the same space however far they go, so where everything starts is known already
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in &instructions {
+        offsets.push(offset);
+        offset += instruction.code.size();
+    }
+    offsets.push(offset);
+
+    let mut optimized = Chunk::new();
+    optimized.values = mem::take(&mut chunk.values);
+    optimized.source = chunk.source.clone();
+
+    for (idx, instruction) in instructions.into_iter().enumerate() {
+        let code = match targets[instruction.old_idx] {
+            Some(old_target) => {
+                let target = offsets[new_idx[old_target]];
+                let next = offsets[idx] + JUMP_SIZE;
+
+                match instruction.code {
+                    OpCode::OpJump(_) => OpCode::OpJump((target - next) as u16),
+                    OpCode::OpJumpIfFalse(_) => OpCode::OpJumpIfFalse((target - next) as u16),
+                    OpCode::OpLoop(_) => OpCode::OpLoop((next - target) as u16),
+                    code => code,
+                }
+            }
+            None => instruction.code,
+        };
+        optimized.write_code(code, instruction.line, instruction.columns);
+    }
+
+    *chunk = optimized;
+}
+
+// The instruction each jump goes to, as an index into `old`. Jumps landing on an OpJump are
+// threaded to where that one goes.
+fn jump_targets(old: &[(usize, OpCode)], code_len: usize) -> Vec<Option<usize>> {
+    let target_of = |idx: usize| {
+        let (offset, code) = old[idx];
+        code.jump_target(offset)
+            .map(|target| old.partition_point(|x| x.0 < target))
+    };
+    let offset_of = |idx: usize| old.get(idx).map_or(code_len, |x| x.0);
+
+    (0..old.len())
+        .map(|idx| {
+            let mut target = target_of(idx)?;
+            if matches!(old[idx].1, OpCode::OpLoop(_)) {
+                return Some(target);
+            }
+
+            // OpJump only goes forward, so following them always ends
+            while let Some((_, OpCode::OpJump(_))) = old.get(target) {
+                let next = target_of(target).unwrap();
+                // Folding only makes code smaller, so a jump that fits here fits once optimized
+                if offset_of(next) - offset_of(idx) - JUMP_SIZE > u16::MAX as usize {
+                    break;
+                }
+                target = next;
+            }
+            Some(target)
+        })
+        .collect()
+}
+
+// Tries to replace the last few instructions with one, returns true when it did.
+fn fold(chunk: &mut Chunk, instructions: &mut Vec<Instruction>) -> bool {
+    let len = instructions.len();
+    let last = &instructions[len - 1];
+    if last.is_target {
+        return false;
+    }
+
+    let folded = match last.code {
+        OpCode::OpNot if len >= 2 && !instructions[len - 2].is_target => {
+            match instructions[len - 2].code {
+                OpCode::OpEqual => Some((2, OpCode::OpNotEqual)),
+                OpCode::OpLess => Some((2, OpCode::OpGreaterEqual)),
+                OpCode::OpGreater => Some((2, OpCode::OpLessEqual)),
+                OpCode::OpTrue => Some((2, OpCode::OpFalse)),
+                OpCode::OpFalse | OpCode::OpNone => Some((2, OpCode::OpTrue)),
+                OpCode::OpConstant(idx) if matches!(chunk.values[idx], Value::Number(_)) => {
+                    Some((2, OpCode::OpFalse))
+                }
+                _ => None,
+            }
+        }
+        OpCode::OpNegate if len >= 2 && !instructions[len - 2].is_target => {
+            number_constant(chunk, &instructions[len - 2].code)
+                .map(|num| (2, OpCode::OpConstant(chunk.add_value(Value::Number(-num)))))
+        }
+        operator if len >= 3 && !instructions[len - 2].is_target => {
+            let a = number_constant(chunk, &instructions[len - 3].code);
+            let b = number_constant(chunk, &instructions[len - 2].code);
+
+            let is_division = matches!(
+                operator,
+                OpCode::OpDivide | OpCode::OpModulo | OpCode::OpFloorDivide
+            );
+            match (a, b) {
+                // Left for the VM, so the error still happens when the code runs
+                (Some(_), Some(b)) if is_division && b == 0.0 => None,
+                (Some(a), Some(b)) => operator
+                    .apply_numbers(a, b)
+                    .map(|num| (3, OpCode::OpConstant(chunk.add_value(Value::Number(num))))),
+                _ => None,
+            }
+        }
+        _ => None,
+    };
+
+    let Some((count, code)) = folded else {
+        return false;
+    };
+
+    // The folded opcode takes the place of the first one, and errors point at the operator
+    let first = len - count;
+    let is_target = instructions[first].is_target;
+    let last = instructions.pop().unwrap();
+    instructions.truncate(first);
+    instructions.push(Instruction {
+        code,
+        line: last.line,
+        columns: last.columns,
+        old_idx: last.old_idx,
+        is_target,
+    });
+
+    true
+}
+
+// Folding leaves behind the constants it replaced, they're dropped so compiled files don't carry
+// them. The ones still used keep their order.
+fn drop_unused_values(chunk: &mut Chunk, instructions: &mut [Instruction]) {
+    let mut new_idx = vec![None; chunk.values.len()];
+    for instruction in instructions.iter_mut() {
+        if let Some(idx) = instruction.code.constant_mut() {
+            new_idx[*idx] = Some(0);
+        }
+    }
+
+    let mut values = Vec::new();
+    for (idx, value) in chunk.values.iter().enumerate() {
+        if new_idx[idx].is_some() {
+            new_idx[idx] = Some(values.len());
+            values.push(*value);
+        }
+    }
+    chunk.values = values;
+
+    for instruction in instructions {
+        if let Some(idx) = instruction.code.constant_mut() {
+            *idx = new_idx[*idx].unwrap();
+        }
+    }
+}
+
+fn number_constant(chunk: &Chunk, code: &OpCode) -> Option<f64> {
+    match *code {
+        OpCode::OpConstant(idx) => chunk.values[idx].as_number(),
+        _ => None,
+    }
+}
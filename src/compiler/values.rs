@@ -1,4 +1,12 @@
-use std::{cell::RefCell, collections::HashMap, fmt::Display, rc::Rc};
+use std::{collections::HashMap, fmt::Display, path::PathBuf};
+
+use crate::{
+    native_functions::Arity,
+    vm::{
+        context::{NativeContext, NativeError},
+        heap::Heap,
+    },
+};
 
 use super::chunk::Chunk;
 
@@ -10,7 +18,7 @@ use super::chunk::Chunk;
 */
 
 // The size is 16 bytes: Enum: 4 byte, Padding: 4 Byte, Largest Type: 8 byte
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
     // We can make this more efficent by making it f16 or f32 instead. However for simplicity we
     // won't do this
@@ -21,29 +29,128 @@ pub enum Value {
     Obj(Obj),
 }
 
-// For when I want to optimize Global Variables
-// TODO use after done
-#[allow(dead_code)]
-pub struct GlobalVar {
-    var_name: String,
-    global_idx: u8,
+impl Value {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(num) => Some(*num),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(bool) => Some(*bool),
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(num: f64) -> Self {
+        Value::Number(num)
+    }
 }
 
-// This is only 8 bytes max: Enum (4byte) + Box (8byte)
-#[derive(Debug, PartialEq, Clone)]
+impl From<bool> for Value {
+    fn from(bool: bool) -> Self {
+        Value::Boolean(bool)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => Value::None,
+        }
+    }
+}
+
+// Which slot each global of a module lives in. The compiler gives a name its slot the first time
+// it sees it, so the VM can find globals by index instead of hashing their name.
+#[derive(Debug, Clone, Default)]
+pub struct GlobalSlots {
+    // Keyed by the heap index of the variable's interned name
+    slots: HashMap<usize, usize>,
+    // The interned name of each slot, to report undefined variables and find natives
+    pub names: Vec<usize>,
+}
+
+impl GlobalSlots {
+    pub fn get(&self, name: usize) -> Option<usize> {
+        self.slots.get(&name).copied()
+    }
+
+    // The slot for `name`, giving it a new one if it has none yet
+    pub fn slot(&mut self, name: usize) -> usize {
+        if let Some(slot) = self.slots.get(&name) {
+            return *slot;
+        }
+
+        self.names.push(name);
+        self.slots.insert(name, self.names.len() - 1);
+        self.names.len() - 1
+    }
+}
+
+// Each variant holds the index of the object inside the VM's Heap. The tag is kept here so we
+// know what the object is without looking it up. Comparing two Obj compares their identity.
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Obj {
-    String(Rc<RefCell<StrObj>>),
-    Function(Rc<RefCell<FunctionObj>>),
-    NativeFn(Rc<RefCell<NativeFn>>),
-    Structs(Rc<RefCell<Structs>>),
-    Instance(Rc<RefCell<StructsInstance>>),
+    String(usize),
+    Function(usize),
+    Closure(usize),
+    NativeFn(usize),
+    Structs(usize),
+    Instance(usize),
+    BoundMethod(usize),
+    List(usize),
+    Map(usize),
+    Module(usize),
 }
 
-#[derive(Debug, Clone)]
+impl Obj {
+    pub fn idx(&self) -> usize {
+        match *self {
+            Obj::String(idx)
+            | Obj::Function(idx)
+            | Obj::Closure(idx)
+            | Obj::NativeFn(idx)
+            | Obj::Structs(idx)
+            | Obj::Instance(idx)
+            | Obj::BoundMethod(idx)
+            | Obj::List(idx)
+            | Obj::Map(idx)
+            | Obj::Module(idx) => idx,
+        }
+    }
+}
+
+// What actually lives inside a Heap slot.
+#[derive(Debug)]
+pub enum ObjData {
+    String(StrObj),
+    Function(FunctionObj),
+    Closure(ClosureObj),
+    NativeFn(NativeFn),
+    Structs(Structs),
+    Instance(StructsInstance),
+    BoundMethod(BoundMethodObj),
+    Upvalue(UpvalueObj),
+    List(ListObj),
+    Map(MapObj),
+    Module(ModuleObj),
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct Structs {
     pub name: String,
-    pub fields: HashMap<String, Value>,
-    pub is_marked: bool,
+    // Keyed by the heap index of the field's interned name
+    pub fields: HashMap<usize, Value>,
+    // The same names in the order they were declared, for positional initializers
+    pub field_order: Vec<usize>,
+    // Heap index of each method's ClosureObj, keyed the same way as fields
+    pub methods: HashMap<usize, usize>,
 }
 
 impl Structs {
@@ -51,111 +158,79 @@ impl Structs {
         Structs {
             name,
             fields: HashMap::new(),
-            is_marked: false,
+            field_order: Vec::new(),
+            methods: HashMap::new(),
         }
     }
 }
 
-impl Default for Structs {
-    fn default() -> Self {
-        Structs {
-            name: String::new(),
-            fields: HashMap::new(),
-            is_marked: false,
-        }
-    }
+#[derive(Debug)]
+pub struct StructsInstance {
+    // Heap index of the Structs this was made from
+    pub origin: usize,
+    pub fields: HashMap<usize, Value>,
 }
 
-impl Display for Structs {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut display_str = String::new();
-        display_str.push_str(&self.name);
-        display_str.push('{');
-        display_str.push('\t');
-
-        for (key, value) in &self.fields {
-            display_str.push_str(&key.to_string());
-            display_str.push(':');
-            display_str.push_str(&value.to_string());
-            display_str.push_str(", ");
-        }
-        display_str.push('\t');
-        display_str.push('}');
-
-        write!(f, "{}", display_str)
+impl StructsInstance {
+    pub fn new(origin: usize, fields: HashMap<usize, Value>) -> Self {
+        StructsInstance { origin, fields }
     }
 }
 
-impl PartialEq for Structs {
-    fn eq(&self, other: &Self) -> bool {
-        self.name.eq(&other.name)
+#[derive(Debug, Default)]
+pub struct ListObj {
+    pub items: Vec<Value>,
+}
+
+impl ListObj {
+    pub fn new(items: Vec<Value>) -> Self {
+        ListObj { items }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub struct StructsInstance {
-    origin: Rc<RefCell<Structs>>,
-    pub fields: HashMap<String, Value>,
-    pub is_marked: bool,
+// What a map is keyed by. Strings are interned so their heap index is enough, and numbers are
+// keyed by their bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Number(u64),
+    String(usize),
 }
 
-impl StructsInstance {
-    pub fn new(origin: Rc<RefCell<Structs>>) -> Self {
-        use std::borrow::Borrow;
-        let origin_hashmap = origin.clone();
-        let origin_hashmap: &RefCell<Structs> = origin_hashmap.borrow();
-        let origin_hashmap: &Structs = &origin_hashmap.borrow();
-        let fields = origin_hashmap.fields.clone();
-        StructsInstance {
-            origin,
-            fields,
-            is_marked: false,
-        }
-    }
+#[derive(Debug, Default)]
+pub struct MapObj {
+    // Kept in insertion order so printing and keys() are predictable
+    pub entries: Vec<(Value, Value)>,
+    index: HashMap<MapKey, usize>,
 }
 
-impl Display for StructsInstance {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use std::borrow::Borrow;
-        let origin: &RefCell<Structs> = self.origin.borrow();
+impl MapObj {
+    pub fn new() -> Self {
+        MapObj::default()
+    }
 
-        let mut display_str = String::new();
-        display_str.push_str(&origin.borrow().name);
-        display_str.push_str(" instance");
-        display_str.push('{');
-        display_str.push('\t');
+    pub fn get(&self, key: &MapKey) -> Option<Value> {
+        self.index.get(key).map(|idx| self.entries[*idx].1)
+    }
 
-        for (key, value) in &self.fields {
-            display_str.push_str(&key.to_string());
-            display_str.push(':');
-            display_str.push_str(&value.to_string());
-            display_str.push_str(", ");
+    pub fn insert(&mut self, key: MapKey, key_value: Value, value: Value) {
+        match self.index.get(&key) {
+            Some(idx) => self.entries[*idx].1 = value,
+            None => {
+                self.index.insert(key, self.entries.len());
+                self.entries.push((key_value, value));
+            }
         }
-        display_str.push('\t');
-        display_str.push('}');
-
-        write!(f, "{}", display_str)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct StrObj {
     pub name: String,
-    pub is_marked: bool,
 }
 
 impl StrObj {
     pub fn new(name: String) -> Self {
-        StrObj {
-            name,
-            is_marked: false,
-        }
-    }
-}
-
-impl PartialEq for StrObj {
-    fn eq(&self, other: &Self) -> bool {
-        self.name.eq(&other.name)
+        StrObj { name }
     }
 }
 
@@ -165,21 +240,13 @@ impl Display for StrObj {
     }
 }
 
-impl Default for StrObj {
-    fn default() -> Self {
-        StrObj {
-            name: String::new(),
-            is_marked: false,
-        }
-    }
-}
-
 #[derive(Debug, PartialEq, Clone)]
 pub struct FunctionObj {
     pub arity: u8,
     pub chunk: Chunk,
     pub name: Option<String>,
-    pub is_marked: bool,
+    // Tells OpClosure where to find each captured variable when the closure is created.
+    pub upvalues: Vec<UpvalueIdx>,
 }
 
 impl FunctionObj {
@@ -189,11 +256,121 @@ impl FunctionObj {
             chunk: Chunk::new(),
             // Consider doing &str
             name: None,
-            is_marked: false,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+impl Default for FunctionObj {
+    fn default() -> Self {
+        FunctionObj::new()
+    }
+}
+
+// is_local = true means the variable is a local of the directly enclosing function, and index is
+// its stack slot. Otherwise index points into the enclosing closure's own upvalues.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct UpvalueIdx {
+    pub index: usize,
+    pub is_local: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum UpvalueLocation {
+    // Still lives on the VM stack, at this index
+    Open(usize),
+    // The local went out of scope, so the value got moved in here
+    Closed(Value),
+}
+
+#[derive(Debug)]
+pub struct UpvalueObj {
+    pub location: UpvalueLocation,
+}
+
+impl UpvalueObj {
+    pub fn new(slot: usize) -> Self {
+        UpvalueObj {
+            location: UpvalueLocation::Open(slot),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClosureObj {
+    // Heap index of the FunctionObj
+    pub function: usize,
+    // Heap indexes of the UpvalueObj
+    pub upvalues: Vec<usize>,
+    // Heap index of the ModuleObj the closure was made in, its globals are the ones it sees
+    pub module: usize,
+}
+
+impl ClosureObj {
+    pub fn new(function: usize, upvalues: Vec<usize>, module: usize) -> Self {
+        ClosureObj {
+            function,
+            upvalues,
+            module,
         }
     }
 }
 
+// The globals of one file. Imports give back the module, and its globals are read off it like
+// fields.
+#[derive(Debug)]
+pub struct ModuleObj {
+    pub name: String,
+    // Where the file is, imports inside it are looked up next to it. None for code that didn't
+    // come from a file, like the REPL.
+    pub path: Option<PathBuf>,
+    pub slots: GlobalSlots,
+    // Indexed by slot, None until the global is defined
+    pub globals: Vec<Option<Value>>,
+}
+
+impl ModuleObj {
+    pub fn new(name: String, path: Option<PathBuf>) -> Self {
+        ModuleObj {
+            name,
+            path,
+            slots: GlobalSlots::default(),
+            globals: Vec::new(),
+        }
+    }
+
+    // The global called `name`, if it's defined
+    pub fn get(&self, name: usize) -> Option<Value> {
+        self.globals[self.slots.get(name)?]
+    }
+
+    pub fn define(&mut self, name: usize, value: Value) {
+        let slot = self.slots.slot(name);
+        self.globals.resize(self.slots.names.len(), None);
+        self.globals[slot] = Some(value);
+    }
+
+    // Takes back the slots after compiling, the new ones start out undefined
+    pub fn set_slots(&mut self, slots: GlobalSlots) {
+        self.globals.resize(slots.names.len(), None);
+        self.slots = slots;
+    }
+}
+
+// A method taken off an instance, so it still knows its receiver when called later.
+#[derive(Debug)]
+pub struct BoundMethodObj {
+    pub receiver: Value,
+    // Heap index of the method's ClosureObj
+    pub method: usize,
+}
+
+impl BoundMethodObj {
+    pub fn new(receiver: Value, method: usize) -> Self {
+        BoundMethodObj { receiver, method }
+    }
+}
+
 impl Display for FunctionObj {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(ref name) = self.name {
@@ -204,12 +381,13 @@ impl Display for FunctionObj {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+pub type NativeFnPtr = fn(&mut NativeContext, &[Value]) -> Result<Value, NativeError>;
+
+#[derive(Debug, Clone)]
 pub struct NativeFn {
     pub name: String,
-    pub arity: u8,
-    pub native_fn: fn(usize, &[Value]) -> Result<Value, &str>,
-    pub is_marked: bool,
+    pub arity: Arity,
+    pub native_fn: NativeFnPtr,
 }
 
 impl Display for NativeFn {
@@ -218,40 +396,132 @@ impl Display for NativeFn {
     }
 }
 
-impl Display for Value {
+// Values only hold indexes, so printing one needs the Heap to find what it points to.
+pub struct ValueDisplay<'a> {
+    pub(crate) value: &'a Value,
+    pub(crate) heap: &'a Heap,
+}
+
+impl Display for ValueDisplay<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut str = String::new();
+        write!(f, "{}", self.value_to_string(self.value, &mut Vec::new()))
+    }
+}
 
-        let concat_str = match self {
-            Self::Number(num) => {
+impl ValueDisplay<'_> {
+    // `path` holds the heap index of every list, map and instance we are inside of. Finding one
+    // of them again means the value contains itself, so it is cut off instead of printed forever.
+    fn value_to_string(&self, value: &Value, path: &mut Vec<usize>) -> String {
+        match value {
+            Value::Number(num) => {
                 format!("{}", num)
             }
-            Self::Boolean(bool) => {
+            Value::Boolean(bool) => {
                 format!("{}", bool)
             }
-            Self::None => {
-                format!("none")
-            }
-            Self::Obj(value_obj) => match value_obj {
-                Obj::String(string) => {
-                    format!("{}", string.borrow())
+            Value::None => "none".to_string(),
+            Value::Obj(value_obj) => match value_obj {
+                Obj::String(idx) => {
+                    format!("{}", self.heap.string(*idx))
+                }
+                Obj::Function(idx) => {
+                    format!("{}", self.heap.function(*idx))
                 }
-                Obj::Function(function) => {
-                    format!("{}", function.borrow())
+                Obj::Closure(idx) => {
+                    let closure = self.heap.closure(*idx);
+                    format!("{}", self.heap.function(closure.function))
                 }
-                Obj::NativeFn(function) => {
-                    format!("{}", function.borrow())
+                Obj::NativeFn(idx) => {
+                    format!("{}", self.heap.native_fn(*idx))
                 }
-                Obj::Structs(structs) => {
-                    format!("{}", structs.borrow())
+                Obj::Structs(idx) => {
+                    let structs = self.heap.structs(*idx);
+                    self.fields_to_string(
+                        *idx,
+                        &structs.name,
+                        &structs.fields,
+                        &structs.field_order,
+                        path,
+                    )
                 }
-                Obj::Instance(instance) => {
-                    format!("{}", instance.borrow())
+                Obj::Instance(idx) => {
+                    let instance = self.heap.instance(*idx);
+                    let structs = self.heap.structs(instance.origin);
+                    let name = format!("{} instance", structs.name);
+                    self.fields_to_string(*idx, &name, &instance.fields, &structs.field_order, path)
+                }
+                Obj::BoundMethod(idx) => {
+                    let closure = self.heap.closure(self.heap.bound_method(*idx).method);
+                    format!("{}", self.heap.function(closure.function))
+                }
+                Obj::List(idx) => {
+                    if path.contains(idx) {
+                        return "[...]".to_string();
+                    }
+
+                    path.push(*idx);
+                    let items = self.heap.list(*idx).items.iter();
+                    let items: Vec<String> = items.map(|x| self.value_to_string(x, path)).collect();
+                    path.pop();
+                    format!("[{}]", items.join(", "))
+                }
+                Obj::Map(idx) => {
+                    if path.contains(idx) {
+                        return "{...}".to_string();
+                    }
+
+                    path.push(*idx);
+                    let entries = self.heap.map(*idx).entries.iter();
+                    let entries: Vec<String> = entries
+                        .map(|(key, value)| {
+                            format!(
+                                "{}: {}",
+                                self.value_to_string(key, path),
+                                self.value_to_string(value, path)
+                            )
+                        })
+                        .collect();
+                    path.pop();
+                    format!("{{{}}}", entries.join(", "))
+                }
+                Obj::Module(idx) => {
+                    format!("<module {}>", self.heap.module(*idx).name)
                 }
             },
-        };
+        }
+    }
+
+    // Instances can point at each other, `idx` is checked against `path` like lists and maps
+    fn fields_to_string(
+        &self,
+        idx: usize,
+        name: &str,
+        fields: &HashMap<usize, Value>,
+        order: &[usize],
+        path: &mut Vec<usize>,
+    ) -> String {
+        if path.contains(&idx) {
+            return format!("{}{{...}}", name);
+        }
+
+        path.push(idx);
+        let mut display_str = String::new();
+        display_str.push_str(name);
+        display_str.push('{');
+        display_str.push('\t');
+
+        // In the order they were declared, the HashMap's order changes from run to run
+        let fields = order.iter().filter_map(|key| Some((key, fields.get(key)?)));
+        for (key, value) in fields {
+            display_str.push_str(&self.heap.string(*key).name);
+            display_str.push(':');
+            display_str.push_str(&self.value_to_string(value, path));
+            display_str.push_str(", ");
+        }
+        display_str.push('\t');
+        display_str.push('}');
+        path.pop();
 
-        str.push_str(&concat_str);
-        write!(f, "{}", str)
+        display_str
     }
 }
@@ -1,63 +1,148 @@
-use crate::compiler::chunk::{Chunk, OpCode};
+use std::fmt::Write;
 
-pub fn disassemble_chunk(chunk: &Chunk, name: String) {
-    println!("=== {} ===", name);
+use crate::{
+    compiler::{
+        chunk::{Chunk, OpCode},
+        values::{FunctionObj, Obj, Value},
+    },
+    scanner::{Scanner, TokenType},
+    vm::heap::Heap,
+};
 
-    for i in 0..chunk.code.len() {
-        disaseemble_code(&chunk, i);
+// Every chunk of the function, then the functions declared inside it, in the order their
+// constants appear. `globals` are the names of the global slots, so globals show their name.
+pub fn disassemble_function(heap: &Heap, function: &FunctionObj, globals: &[usize]) -> String {
+    let mut out = String::new();
+    write_function(&mut out, heap, function, globals);
+    out
+}
+
+fn write_function(out: &mut String, heap: &Heap, function: &FunctionObj, globals: &[usize]) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    let _ = writeln!(out, "=== {} ===", function);
+
+    let chunk = &function.chunk;
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offset = write_code(out, heap, chunk, globals, offset);
+    }
+
+    for value in &chunk.values {
+        if let Value::Obj(Obj::Function(idx)) = value {
+            write_function(out, heap, heap.function(*idx), globals);
+        }
     }
 }
 
-pub fn disaseemble_code(chunk: &Chunk, offset: usize) {
+// Prints the opcode at `offset` and gives back the offset of the next one.
+pub fn disaseemble_code(heap: &Heap, chunk: &Chunk, globals: &[usize], offset: usize) -> usize {
+    let mut out = String::new();
+    let next = write_code(&mut out, heap, chunk, globals, offset);
+    print!("{}", out);
+    next
+}
+
+fn write_code(
+    out: &mut String,
+    heap: &Heap,
+    chunk: &Chunk,
+    globals: &[usize],
+    offset: usize,
+) -> usize {
     if chunk.code.len() <= offset {
-        return;
+        return offset;
     }
 
-    print!("{:04} ", offset);
+    let _ = write!(out, "{:04} ", offset);
 
-    if offset > 0 && chunk.line[offset] == chunk.line[offset - 1] {
-        print!("   | ");
+    if offset > 0 && chunk.line_at(offset) == chunk.line_at(offset - 1) {
+        out.push_str("   | ");
     } else {
-        print!("{:4} ", chunk.line[offset]);
+        let _ = write!(out, "{:4} ", chunk.line_at(offset));
     }
 
-    let code = &chunk.code[offset];
-    match code {
-        OpCode::OpConstant(loc) => {
-            println!("{} {:10} {}", code, loc, chunk.values[*loc as usize]);
-        }
-        OpCode::OpDefineGlobal(loc) => {
-            println!("{} {:10} {}", code, loc, chunk.values[*loc as usize]);
-        }
-        OpCode::OpGetGlobal(loc) => {
-            println!("{} {:10} {}", code, loc, chunk.values[*loc as usize]);
-        }
-        OpCode::OpSetGlobal(loc) => {
-            println!("{} {:10} {}", code, loc, chunk.values[*loc as usize]);
-        }
-        OpCode::OpGetLocal(loc) => {
-            println!("{} {:10} ", code, loc);
-        }
-        OpCode::OpSetLocal(loc) => {
-            println!("{} {:10} ", code, loc);
-        }
-        OpCode::OpJumpIfFalse(loc) => {
-            println!("{} {:10} ", code, loc);
-        }
-        OpCode::OpLoop(loc) => {
-            println!("{} {:10} ", code, loc);
+    let (code, next) = chunk.read_code(offset);
+    let name = opcode_name(&code);
+    // Strings are quoted, otherwise "" and " " don't show up
+    let constant = |idx: usize| match chunk.values.get(idx) {
+        Some(Value::Obj(Obj::String(string))) => format!("{:?}", heap.string(*string).name),
+        Some(value) => heap.display(value).to_string(),
+        None => "?".to_string(),
+    };
+    let global = |slot: usize| match globals.get(slot) {
+        Some(global) => heap.string(*global).name.clone(),
+        None => "?".to_string(),
+    };
+
+    let _ = match code {
+        OpCode::OpConstant(idx)
+        | OpCode::OpClosure(idx)
+        | OpCode::OpClass(idx)
+        | OpCode::OpSetProperty(idx)
+        | OpCode::OpGetProperty(idx)
+        | OpCode::OpField(idx)
+        | OpCode::OpMethod(idx)
+        | OpCode::OpImport(idx) => writeln!(out, "{:16} {:4} {}", name, idx, constant(idx)),
+        OpCode::OpInvoke(idx, args_count) => writeln!(
+            out,
+            "{:16} {:4} {} ({} args)",
+            name,
+            idx,
+            constant(idx),
+            args_count
+        ),
+        OpCode::OpDefineGlobal(slot) | OpCode::OpGetGlobal(slot) | OpCode::OpSetGlobal(slot) => {
+            writeln!(out, "{:16} {:4} {}", name, slot, global(slot))
         }
-        OpCode::OpCall(loc) => {
-            println!("{} {:10} ", code, loc);
+        // Where the jump lands, so it doesn't have to be counted by hand
+        OpCode::OpJump(jump) | OpCode::OpJumpIfFalse(jump) | OpCode::OpLoop(jump) => {
+            let target = code.jump_target(offset).unwrap_or(0);
+            writeln!(out, "{:16} {:4} -> {:04}", name, jump, target)
         }
+        OpCode::OpGetLocal(idx)
+        | OpCode::OpSetLocal(idx)
+        | OpCode::OpGetUpvalue(idx)
+        | OpCode::OpSetUpvalue(idx) => writeln!(out, "{:16} {:4}", name, idx),
+        OpCode::OpCall(count)
+        | OpCode::OpNamedInstance(count)
+        | OpCode::OpBuildList(count)
+        | OpCode::OpBuildMap(count)
+        | OpCode::OpBuildString(count) => writeln!(out, "{:16} {:4}", name, count),
+        _ => writeln!(out, "{}", name),
+    };
 
-        OpCode::OpClass(loc) => {
-            println!("{} {:10} ", code, loc);
-        }
+    next
+}
+
+// OpConstant for OpConstant(3), the operands are printed on their own
+fn opcode_name(code: &OpCode) -> String {
+    let debug = format!("{:?}", code);
+    match debug.split_once('(') {
+        Some((name, _)) => name.to_string(),
+        None => debug,
+    }
+}
+
+// One line for every token the scanner gives back: where it starts, its type and its text.
+pub fn list_tokens(source: &str) -> String {
+    let mut out = String::new();
+    let mut scanner = Scanner::new(source.to_string());
+
+    loop {
+        let token = scanner.scan_token();
+        let position = format!("{}:{}", token.line, token.column);
+        let _ = writeln!(
+            out,
+            "{:8} {:16} {:?}",
+            position,
+            token.token_type.to_string(),
+            token.lexeme
+        );
 
-        // Add individal codes here if you want to debug
-        _ => {
-            println!("{}", chunk.code[offset]);
+        if token.token_type == TokenType::Eof {
+            return out;
         }
     }
 }
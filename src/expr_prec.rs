@@ -10,6 +10,7 @@ pub struct Precdence {
     pub term: u8,
     pub factor: u8,
     pub unary: u8,
+    pub exponent: u8,
     pub call: u8,
     pub instance: u8,
 }
@@ -24,21 +25,26 @@ pub const PRECEDENCE: Precdence = Precdence {
     term: 6,
     factor: 7,
     unary: 8,
-    call: 9,
-    instance: 9,
+    // Above unary so -2 ^ 2 is -(2 ^ 2)
+    exponent: 9,
+    call: 10,
+    instance: 10,
 };
 
 pub fn get_precedence(token_type: TokenType) -> u8 {
     match token_type {
         TokenType::Minus | TokenType::Plus => PRECEDENCE.term,
-        TokenType::Slash | TokenType::Star => PRECEDENCE.factor,
+        TokenType::Slash | TokenType::Star | TokenType::Percent | TokenType::Div => {
+            PRECEDENCE.factor
+        }
+        TokenType::Caret | TokenType::StarStar => PRECEDENCE.exponent,
         TokenType::BangEqual | TokenType::EqualEqual => PRECEDENCE.equality,
         TokenType::Greater | TokenType::GreaterEqual | TokenType::Less | TokenType::LessEqual => {
             PRECEDENCE.comparison
         }
         TokenType::And => PRECEDENCE.and,
         TokenType::Or => PRECEDENCE.or,
-        TokenType::LeftParen | TokenType::Dot => PRECEDENCE.call,
+        TokenType::LeftParen | TokenType::Dot | TokenType::LeftBracket => PRECEDENCE.call,
         TokenType::LeftBrace => PRECEDENCE.instance,
         _ => PRECEDENCE.none,
     }
@@ -57,10 +63,15 @@ pub enum ParseFn {
     Variable,
     Literal,
     String,
+    Interpolation,
 
     Call,
     Dot,
     Instance,
+
+    List,
+    Index,
+    Map,
 }
 
 pub struct ParseRule {
@@ -83,7 +94,7 @@ pub fn get_parse_rule(token_type: TokenType) -> ParseRule {
             precedence: get_precedence(token_type),
         },
         TokenType::LeftBrace => ParseRule {
-            prefix_rule: None,
+            prefix_rule: Some(ParseFn::Map),
             infix_rule: Some(ParseFn::Instance),
             precedence: get_precedence(token_type),
         },
@@ -92,6 +103,16 @@ pub fn get_parse_rule(token_type: TokenType) -> ParseRule {
             infix_rule: None,
             precedence: get_precedence(token_type),
         },
+        TokenType::LeftBracket => ParseRule {
+            prefix_rule: Some(ParseFn::List),
+            infix_rule: Some(ParseFn::Index),
+            precedence: get_precedence(token_type),
+        },
+        TokenType::RightBracket => ParseRule {
+            prefix_rule: None,
+            infix_rule: None,
+            precedence: get_precedence(token_type),
+        },
 
         // Symbols
         TokenType::Comma => ParseRule {
@@ -124,6 +145,26 @@ pub fn get_parse_rule(token_type: TokenType) -> ParseRule {
             infix_rule: Some(ParseFn::Binary),
             precedence: get_precedence(token_type),
         },
+        TokenType::StarStar => ParseRule {
+            prefix_rule: None,
+            infix_rule: Some(ParseFn::Binary),
+            precedence: get_precedence(token_type),
+        },
+        TokenType::Percent => ParseRule {
+            prefix_rule: None,
+            infix_rule: Some(ParseFn::Binary),
+            precedence: get_precedence(token_type),
+        },
+        TokenType::Caret => ParseRule {
+            prefix_rule: None,
+            infix_rule: Some(ParseFn::Binary),
+            precedence: get_precedence(token_type),
+        },
+        TokenType::Div => ParseRule {
+            prefix_rule: None,
+            infix_rule: Some(ParseFn::Binary),
+            precedence: get_precedence(token_type),
+        },
         TokenType::Semicolon => ParseRule {
             prefix_rule: None,
             infix_rule: None,
@@ -202,6 +243,11 @@ pub fn get_parse_rule(token_type: TokenType) -> ParseRule {
             infix_rule: None,
             precedence: get_precedence(token_type),
         },
+        TokenType::Interpolation => ParseRule {
+            prefix_rule: Some(ParseFn::Interpolation),
+            infix_rule: None,
+            precedence: get_precedence(token_type),
+        },
         TokenType::Number => ParseRule {
             prefix_rule: Some(ParseFn::Number),
             infix_rule: None,
@@ -267,6 +313,21 @@ pub fn get_parse_rule(token_type: TokenType) -> ParseRule {
             infix_rule: None,
             precedence: get_precedence(token_type),
         },
+        TokenType::Import => ParseRule {
+            prefix_rule: None,
+            infix_rule: None,
+            precedence: get_precedence(token_type),
+        },
+        TokenType::Break => ParseRule {
+            prefix_rule: None,
+            infix_rule: None,
+            precedence: get_precedence(token_type),
+        },
+        TokenType::Continue => ParseRule {
+            prefix_rule: None,
+            infix_rule: None,
+            precedence: get_precedence(token_type),
+        },
 
         // Keywords
         TokenType::Do => ParseRule {
@@ -305,7 +366,7 @@ pub fn get_parse_rule(token_type: TokenType) -> ParseRule {
             infix_rule: None,
             precedence: get_precedence(token_type),
         },
-        TokenType::EOF => ParseRule {
+        TokenType::Eof => ParseRule {
             prefix_rule: None,
             infix_rule: None,
             precedence: get_precedence(token_type),
@@ -0,0 +1,47 @@
+// The Bite compiler and VM as a library. The `Thesis` binary (run, repl, learn) is built on top of
+// this, and other tools can use it to run Bite code without going through the command line.
+//
+//     let mut vm = bite::VM::new();
+//     vm.interpret("let x = 1 + 2\n".to_string())?;
+//     assert_eq!(vm.get_global("x"), Some(bite::Value::Number(3.0)));
+//
+// Only what is re-exported below is public, the compiler and VM internals stay in the crate.
+
+pub(crate) mod compiler;
+mod debug;
+mod expr_prec;
+mod native_functions;
+mod scanner;
+mod test;
+pub(crate) mod vm;
+
+pub use compiler::{
+    bytecode::{is_bytecode, BytecodeError},
+    diagnostic::{Diagnostic, Severity},
+    values::{NativeFnPtr, Value},
+};
+pub use native_functions::Arity;
+pub use vm::{
+    context::{NativeContext, NativeError},
+    heap::Heap,
+    InterpretError, VM,
+};
+
+// Only checks that the source compiles, nothing gets run.
+pub fn compile(source: &str) -> Result<(), Vec<Diagnostic>> {
+    let mut heap = Heap::new();
+    let mut parser = compiler::Parser::new(&mut heap);
+    parser.compile(source.to_string()).map(|_| ())
+}
+
+// The tokens the scanner makes from the source, one per line.
+pub fn tokens(source: &str) -> String {
+    debug::list_tokens(source)
+}
+
+// Runs the source in a new VM. The VM is handed back so its globals can be looked at.
+pub fn interpret(source: &str) -> Result<VM, InterpretError> {
+    let mut vm = VM::new();
+    vm.interpret(source.to_string())?;
+    Ok(vm)
+}
@@ -1,46 +1,71 @@
 use std::{
-    env, fs, process,
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
     sync::{Arc, RwLock},
 };
 
+use bite::{InterpretError, VM};
 use biteling::{current_stage, start_file_listener, start_user_input};
 use stage_problems::StageInfo;
-use vm::{InterpretError, VM};
 
 mod biteling;
-mod compiler;
-mod debug;
-mod expr_prec;
 mod init_stages;
-mod native_functions;
 mod printer;
-mod scanner;
 mod stage_problems;
-mod test;
-mod vm;
+
+const USAGE: &str = "\
+Usage:
+<placeholder> run <file name>.txt [--no-optimize] [--trace]
+<placeholder> compile <file name>.txt [<output>.bitec] [--no-optimize]
+<placeholder> disasm <file name>.txt [--no-optimize]
+<placeholder> tokens <file name>.txt
+<placeholder> repl [--no-optimize] [--trace]
+<placeholder> learn";
+
+// Options that can go anywhere after the command
+const FLAGS: &[&str] = &["--no-optimize", "--trace"];
 
 fn main() {
-    if env::args().len() > 4 {
+    let (flags, args): (Vec<String>, Vec<String>) = env::args().partition(|x| x.starts_with("--"));
+
+    if args.len() > 4 {
         eprintln!("You entered too many arguments");
         process::exit(1);
     } else {
-        let args: Vec<String> = env::args().collect();
-
         if args.len() <= 1 {
-            eprintln!(
-                "\
-Unknown command. Usage:
-<placeholder> run <file name>.txt
-<placeholder> learn"
-            );
+            eprintln!("Unknown command. {}", USAGE);
+            process::exit(1);
+        }
+
+        if let Some(flag) = flags.iter().find(|x| !FLAGS.contains(&x.as_str())) {
+            eprintln!("Unknown option {}\n{}", flag, USAGE);
             process::exit(1);
         }
 
+        let mut vm = VM::new();
+        vm.set_optimize(!flags.iter().any(|x| x == "--no-optimize"));
+        vm.set_trace(flags.iter().any(|x| x == "--trace"));
+
         let cmd = &args[1];
 
         match cmd.as_str() {
-            "run" => {
-                read_file(&args[2]);
+            "run" if args.len() > 2 => {
+                read_file(vm, &args[2]);
+            }
+            "compile" if args.len() > 2 => {
+                compile_file(vm, &args[2], args.get(3));
+            }
+            "disasm" if args.len() > 2 => {
+                disassemble_file(vm, &args[2]);
+            }
+            "tokens" if args.len() > 2 => {
+                let source_str = String::from_utf8_lossy(&read_or_exit(&args[2])).into_owned();
+                print!("{}", bite::tokens(&source_str));
+            }
+            "repl" => {
+                repl(vm);
             }
             "learn" => {
                 let stages = Arc::new(RwLock::new(StageInfo::new()));
@@ -62,32 +87,149 @@ Unknown command. Usage:
                 println!("Goodbye!");
             }
             _ => {
-                eprintln!(
-                    "\
-Unknown command. Usage:
-<placeholder> run <file name>.txt
-<placeholder> learn"
-                );
+                eprintln!("Unknown command. {}", USAGE);
                 process::exit(1);
             }
         }
     }
 }
 
-fn read_file(path: &String) {
-    let source_str =
-        fs::read_to_string(path).unwrap_or_else(|_| panic!("Error Reading File. Path: {}", path));
+fn read_file(mut vm: VM, path: &str) {
+    let bytes = read_or_exit(path);
+
+    // Files made by `compile` run without going through the compiler
+    if bite::is_bytecode(&bytes) {
+        let res = vm.interpret_bytecode_file(&bytes, Path::new(path));
+        exit_on_error(res.map(|_| ()), "");
+        return;
+    }
+
+    let source_str = String::from_utf8_lossy(&bytes).into_owned();
 
     //let source_str = String::from("\"str1🔥\" == \"str2🔥\"\n1 + 1");
 
-    let mut vm = VM::new();
-    match vm.interpret(source_str) {
+    let res = vm.interpret_file(source_str.clone(), Path::new(path));
+    exit_on_error(res.map(|_| ()), &source_str);
+}
+
+// Writes the compiled file next to the source unless told where, run then takes either one.
+fn compile_file(mut vm: VM, path: &str, output: Option<&String>) {
+    let source_str = String::from_utf8_lossy(&read_or_exit(path)).into_owned();
+
+    let bytes = match vm.compile_bytecode(source_str.clone()) {
+        Ok(bytes) => bytes,
+        Err(err) => return exit_on_error(Err(err), &source_str),
+    };
+
+    let output = match output {
+        Some(output) => PathBuf::from(output),
+        None => Path::new(path).with_extension("bitec"),
+    };
+    if let Err(err) = fs::write(&output, bytes) {
+        eprintln!("Error Writing File. Path: {}: {}", output.display(), err);
+        process::exit(74);
+    }
+}
+
+// Works on compiled files too, to see what they hold without the source.
+fn disassemble_file(mut vm: VM, path: &str) {
+    let bytes = read_or_exit(path);
+
+    let (res, source_str) = match bite::is_bytecode(&bytes) {
+        true => (vm.disassemble_bytecode(&bytes), String::new()),
+        false => {
+            let source_str = String::from_utf8_lossy(&bytes).into_owned();
+            (vm.disassemble(source_str.clone()), source_str)
+        }
+    };
+
+    match res {
+        Ok(listing) => print!("{}", listing),
+        Err(err) => exit_on_error(Err(err), &source_str),
+    }
+}
+
+// A file that can't be read ends the program, saying which one and why.
+fn read_or_exit(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|err| {
+        eprintln!("Error Reading File. Path: {}: {}", path, err);
+        process::exit(74);
+    })
+}
+
+fn exit_on_error(res: Result<(), InterpretError>, source_str: &str) {
+    match res {
         Ok(_) => {}
         Err(err) => match err {
-            InterpretError::CompileError => {
+            InterpretError::CompileError(diagnostics) => {
+                for diagnostic in diagnostics {
+                    eprintln!("{}\n", diagnostic.render(source_str));
+                }
+                process::exit(65);
+            }
+            InterpretError::IncompleteInput => {
                 process::exit(65);
             }
             InterpretError::RuntimeError => process::exit(70),
+            InterpretError::BytecodeError(err) => {
+                eprintln!("{}", err);
+                process::exit(65);
+            }
         },
     }
 }
+
+fn repl(mut vm: VM) {
+    println!("Bite REPL. Press Ctrl-D to exit.");
+
+    let mut source_str = String::new();
+
+    loop {
+        if source_str.is_empty() {
+            print!("> ");
+        } else {
+            print!("... ");
+        }
+        let _ = io::stdout().flush();
+
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            // Ctrl-D
+            Ok(0) => {
+                println!();
+                break;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("Error reading input: {}", err);
+                break;
+            }
+        }
+
+        // An empty line while a block is still open means the user is done, so the errors for
+        // the unfinished input get shown instead of waiting forever.
+        let give_up = !source_str.is_empty() && line.trim().is_empty();
+
+        source_str.push_str(&line);
+        if !source_str.ends_with('\n') {
+            source_str.push('\n');
+        }
+
+        let res = if give_up {
+            vm.interpret(source_str.clone())
+        } else {
+            vm.interpret_repl(source_str.clone())
+        };
+
+        match res {
+            Err(InterpretError::IncompleteInput) => continue,
+            Err(InterpretError::CompileError(diagnostics)) => {
+                for diagnostic in diagnostics {
+                    eprintln!("{}\n", diagnostic.render(&source_str));
+                }
+            }
+            _ => {}
+        }
+        source_str.clear();
+    }
+}
@@ -1,42 +1,504 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    cell::Cell,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use crate::compiler::values::{NativeFn, Value};
+use crate::{
+    compiler::values::{NativeFn, NativeFnPtr, Obj, Value},
+    vm::{
+        context::{NativeContext, NativeError},
+        heap::Heap,
+    },
+};
 
-fn make_native(
-    name: &str,
-    native_fn: fn(usize, &[Value]) -> Result<Value, &str>,
-    arity: u8,
-) -> NativeFn {
+// How many arguments a native takes. Natives taking AtLeast get the rest as extra values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arity {
+    Exactly(u8),
+    AtLeast(u8),
+}
+
+impl Arity {
+    // The error to report when `name` gets called with `args` arguments, if they don't fit.
+    pub fn check(&self, name: &str, args: u8) -> Option<String> {
+        match *self {
+            Arity::Exactly(arity) if arity != args => Some(format!(
+                "{} Expected {} arguments but got {}",
+                name, arity, args
+            )),
+            Arity::AtLeast(arity) if args < arity => Some(format!(
+                "{} expects at least {} arguments but got {}",
+                name, arity, args
+            )),
+            _ => None,
+        }
+    }
+}
+
+fn make_native(name: &str, native_fn: NativeFnPtr, arity: Arity) -> NativeFn {
     NativeFn {
         name: name.to_string(),
         native_fn,
         arity,
-        is_marked: false,
     }
 }
 
 pub fn get_all_natives() -> Vec<NativeFn> {
     vec![
-        make_native("clock", clock, 0),
-        make_native("print", print, 255),
+        make_native("clock", clock, Arity::Exactly(0)),
+        make_native("print", print, Arity::AtLeast(0)),
+        make_native("input", input, Arity::Exactly(1)),
+        make_native("read_line", read_line, Arity::Exactly(0)),
+        make_native("len", len, Arity::Exactly(1)),
+        make_native("push", push, Arity::Exactly(2)),
+        make_native("pop", pop, Arity::Exactly(1)),
+        make_native("keys", keys, Arity::Exactly(1)),
+        make_native("values", values, Arity::Exactly(1)),
+        make_native("has", has, Arity::Exactly(2)),
+        make_native("str", str, Arity::Exactly(1)),
+        make_native("num", num, Arity::Exactly(1)),
+        make_native("type", type_of, Arity::Exactly(1)),
+        make_native("floor", floor, Arity::Exactly(1)),
+        make_native("ceil", ceil, Arity::Exactly(1)),
+        make_native("round", round, Arity::Exactly(1)),
+        make_native("sqrt", sqrt, Arity::Exactly(1)),
+        make_native("abs", abs, Arity::Exactly(1)),
+        make_native("min", min, Arity::AtLeast(1)),
+        make_native("max", max, Arity::AtLeast(1)),
+        make_native("random", random, Arity::Exactly(0)),
+        make_native("seed", seed, Arity::Exactly(1)),
+        make_native("upper", upper, Arity::Exactly(1)),
+        make_native("lower", lower, Arity::Exactly(1)),
+        make_native("split", split, Arity::Exactly(2)),
+        make_native("contains", contains, Arity::Exactly(2)),
+        make_native("substr", substr, Arity::Exactly(3)),
+        make_native("map", map, Arity::Exactly(2)),
+        make_native("filter", filter, Arity::Exactly(2)),
+        make_native("sort", sort, Arity::AtLeast(1)),
     ]
 }
 
-fn print(_args: usize, values: &[Value]) -> Result<Value, &str> {
+fn print(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let heap = ctx.heap();
     let output_str = values
         .iter()
-        .map(|x| x.to_string())
+        .map(|x| heap.display(x).to_string())
         .collect::<Vec<_>>()
         .join(", ");
-    println!("{output_str}");
+    if writeln!(ctx.output(), "{output_str}").is_err() {
+        return Err("Error writing to the terminal".into());
+    }
 
     Ok(Value::None)
 }
 
-fn clock(_args: usize, _values: &[Value]) -> Result<Value, &str> {
+fn clock(_ctx: &mut NativeContext, _values: &[Value]) -> Result<Value, NativeError> {
     let secs = SystemTime::now().duration_since(UNIX_EPOCH);
     match secs {
         Ok(sec) => Ok(Value::Number(sec.as_secs_f64())),
-        Err(_err) => Err("Error getting seconds"),
+        Err(_err) => Err("Error getting seconds".into()),
+    }
+}
+
+fn len(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let heap = ctx.heap();
+    match values[0] {
+        Value::Obj(Obj::List(list)) => Ok(Value::Number(heap.list(list).items.len() as f64)),
+        Value::Obj(Obj::Map(map)) => Ok(Value::Number(heap.map(map).entries.len() as f64)),
+        Value::Obj(Obj::String(string)) => Ok(Value::Number(
+            heap.string(string).name.chars().count() as f64,
+        )),
+        _ => Err("len() expects a list, map or string".into()),
+    }
+}
+
+fn push(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    match values[0] {
+        Value::Obj(Obj::List(list)) => {
+            ctx.heap_mut().list_mut(list).items.push(values[1]);
+            Ok(Value::None)
+        }
+        _ => Err("push() expects a list as its first argument".into()),
+    }
+}
+
+fn pop(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    match values[0] {
+        Value::Obj(Obj::List(list)) => match ctx.heap_mut().list_mut(list).items.pop() {
+            Some(value) => Ok(value),
+            None => Err("pop() called on an empty list".into()),
+        },
+        _ => Err("pop() expects a list".into()),
+    }
+}
+
+fn keys(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    match values[0] {
+        Value::Obj(Obj::Map(map)) => {
+            let keys = ctx.heap().map(map).entries.iter().map(|x| x.0).collect();
+            Ok(ctx.new_list(keys))
+        }
+        _ => Err("keys() expects a map".into()),
+    }
+}
+
+fn values(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    match values[0] {
+        Value::Obj(Obj::Map(map)) => {
+            let values = ctx.heap().map(map).entries.iter().map(|x| x.1).collect();
+            Ok(ctx.new_list(values))
+        }
+        _ => Err("values() expects a map".into()),
+    }
+}
+
+fn has(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let heap = ctx.heap();
+    match values[0] {
+        Value::Obj(Obj::Map(map)) => match heap.map_key(&values[1]) {
+            Some(key) => Ok(Value::Boolean(heap.map(map).get(&key).is_some())),
+            None => Err("Map keys must be strings or numbers".into()),
+        },
+        _ => Err("has() expects a map as its first argument".into()),
+    }
+}
+
+// ****************************     Input     ***************************
+
+fn input(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let prompt = ctx.heap().display(&values[0]).to_string();
+    let output = ctx.output();
+    if write!(output, "{}", prompt).and_then(|_| output.flush()).is_err() {
+        return Err("Error writing to the terminal".into());
+    }
+
+    read_line(ctx, &[])
+}
+
+// Gives back none once there is nothing left to read.
+fn read_line(ctx: &mut NativeContext, _values: &[Value]) -> Result<Value, NativeError> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => Ok(Value::None),
+        Ok(_) => Ok(ctx.new_string(line.trim_end_matches(['\n', '\r']))),
+        Err(_err) => Err("Error reading from the terminal".into()),
+    }
+}
+
+// ****************************     Conversions     ***************************
+
+fn str(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let string = ctx.heap().display(&values[0]).to_string();
+    Ok(ctx.new_string(&string))
+}
+
+// Gives back none when the string isn't a number, so it can be checked.
+fn num(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    match values[0] {
+        Value::Number(num) => Ok(Value::Number(num)),
+        Value::Obj(Obj::String(string)) => {
+            let num = ctx.heap().string(string).name.trim().parse::<f64>().ok();
+            Ok(Value::from(num.filter(|x| x.is_finite())))
+        }
+        _ => Err("num() expects a string or number".into()),
+    }
+}
+
+fn type_of(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let name = match values[0] {
+        Value::Number(_) => "number",
+        Value::Boolean(_) => "boolean",
+        Value::None => "none",
+        Value::Obj(obj) => match obj {
+            Obj::String(_) => "string",
+            Obj::Function(_) | Obj::Closure(_) | Obj::NativeFn(_) | Obj::BoundMethod(_) => {
+                "function"
+            }
+            Obj::Structs(_) => "struct",
+            Obj::Instance(_) => "instance",
+            Obj::List(_) => "list",
+            Obj::Map(_) => "map",
+            Obj::Module(_) => "module",
+        },
+    };
+
+    Ok(ctx.new_string(name))
+}
+
+// ****************************     Math     ***************************
+
+fn number_arg(value: &Value, msg: &str) -> Result<f64, NativeError> {
+    value.as_number().ok_or_else(|| msg.into())
+}
+
+fn floor(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let num = number_arg(&values[0], "floor() expects a number")?;
+    Ok(Value::Number(num.floor()))
+}
+
+fn ceil(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let num = number_arg(&values[0], "ceil() expects a number")?;
+    Ok(Value::Number(num.ceil()))
+}
+
+// Halves round away from zero, so round(2.5) is 3 and round(-2.5) is -3
+fn round(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let num = number_arg(&values[0], "round() expects a number")?;
+    Ok(Value::Number(num.round()))
+}
+
+fn sqrt(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let num = number_arg(&values[0], "sqrt() expects a number")?;
+    if num < 0.0 {
+        return Err("sqrt() can't take a negative number".into());
+    }
+    Ok(Value::Number(num.sqrt()))
+}
+
+fn abs(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let num = number_arg(&values[0], "abs() expects a number")?;
+    Ok(Value::Number(num.abs()))
+}
+
+fn min(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let mut smallest = number_arg(&values[0], "min() expects numbers")?;
+    for value in &values[1..] {
+        smallest = smallest.min(number_arg(value, "min() expects numbers")?);
+    }
+    Ok(Value::Number(smallest))
+}
+
+fn max(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let mut largest = number_arg(&values[0], "max() expects numbers")?;
+    for value in &values[1..] {
+        largest = largest.max(number_arg(value, "max() expects numbers")?);
+    }
+    Ok(Value::Number(largest))
+}
+
+// ****************************     Random     ***************************
+
+thread_local! {
+    // xorshift64* state, never 0
+    static RANDOM_STATE: Cell<u64> = Cell::new(initial_seed());
+}
+
+fn initial_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos() as u64)
+        .unwrap_or(0);
+    nanos | 1
+}
+
+// A number from 0 up to, but not including, 1
+fn random(_ctx: &mut NativeContext, _values: &[Value]) -> Result<Value, NativeError> {
+    let num = RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    });
+
+    // The top 53 bits fit exactly in a f64
+    Ok(Value::Number((num >> 11) as f64 / (1u64 << 53) as f64))
+}
+
+// The same seed always gives the same random numbers afterwards
+fn seed(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let num = number_arg(&values[0], "seed() expects a number")?;
+    // Spreads the seed out so nearby seeds don't start out alike
+    let mixed = num.to_bits().wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    RANDOM_STATE.with(|state| state.set(mixed | 1));
+    Ok(Value::None)
+}
+
+// ****************************     Strings     ***************************
+
+fn string_arg<'a>(heap: &'a Heap, value: &Value, msg: &str) -> Result<&'a str, NativeError> {
+    heap.as_str(value).ok_or_else(|| msg.into())
+}
+
+fn upper(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let string = string_arg(ctx.heap(), &values[0], "upper() expects a string")?.to_uppercase();
+    Ok(ctx.new_string(&string))
+}
+
+fn lower(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let string = string_arg(ctx.heap(), &values[0], "lower() expects a string")?.to_lowercase();
+    Ok(ctx.new_string(&string))
+}
+
+// An empty separator splits the string into its characters
+fn split(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let string = string_arg(ctx.heap(), &values[0], "split() expects a string to split")?;
+    let separator = string_arg(ctx.heap(), &values[1], "split() expects a string separator")?;
+
+    let parts: Vec<String> = if separator.is_empty() {
+        string.chars().map(|x| x.to_string()).collect()
+    } else {
+        string.split(separator).map(|x| x.to_string()).collect()
+    };
+
+    let items = parts.iter().map(|x| ctx.new_string(x)).collect();
+    Ok(ctx.new_list(items))
+}
+
+fn contains(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let heap = ctx.heap();
+    let string = string_arg(heap, &values[0], "contains() expects a string to search")?;
+    let part = string_arg(heap, &values[1], "contains() expects a string to look for")?;
+    Ok(Value::Boolean(string.contains(part)))
+}
+
+// substr(string, start, length), counted in characters. The length can go past the end.
+fn substr(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let string = string_arg(ctx.heap(), &values[0], "substr() expects a string")?;
+    let start = number_arg(&values[1], "substr() expects a number for the start")?;
+    let length = number_arg(&values[2], "substr() expects a number for the length")?;
+
+    if start < 0.0 || length < 0.0 || start.fract() != 0.0 || length.fract() != 0.0 {
+        return Err("substr() expects whole numbers that aren't negative".into());
+    }
+    if start as usize > string.chars().count() {
+        return Err("substr() start is past the end of the string".into());
+    }
+
+    let string: String = string
+        .chars()
+        .skip(start as usize)
+        .take(length as usize)
+        .collect();
+    Ok(ctx.new_string(&string))
+}
+
+// ****************************     Lists     ***************************
+
+fn list_arg(value: &Value, msg: &str) -> Result<usize, NativeError> {
+    match value {
+        Value::Obj(Obj::List(list)) => Ok(*list),
+        _ => Err(msg.into()),
+    }
+}
+
+fn is_truthy(value: Value) -> bool {
+    !matches!(value, Value::None | Value::Boolean(false))
+}
+
+// map(list, fn) gives back a new list with fn called on every item. The list is read as it goes,
+// so changes fn makes to it show up like they would in a loop.
+fn map(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let list = list_arg(&values[0], "map() expects a list as its first argument")?;
+    let Value::Obj(Obj::List(mapped)) = ctx.new_list(Vec::new()) else {
+        unreachable!()
+    };
+
+    let mut i = 0;
+    while let Some(item) = ctx.heap().list(list).items.get(i).copied() {
+        let value = ctx.call(values[1], &[item])?;
+        ctx.heap_mut().list_mut(mapped).items.push(value);
+        i += 1;
+    }
+
+    Ok(Value::Obj(Obj::List(mapped)))
+}
+
+// filter(list, fn) gives back a new list of the items fn returned something truthy for
+fn filter(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let list = list_arg(&values[0], "filter() expects a list as its first argument")?;
+    let Value::Obj(Obj::List(kept)) = ctx.new_list(Vec::new()) else {
+        unreachable!()
+    };
+
+    let mut i = 0;
+    while let Some(item) = ctx.heap().list(list).items.get(i).copied() {
+        if is_truthy(ctx.call(values[1], &[item])?) {
+            ctx.heap_mut().list_mut(kept).items.push(item);
+        }
+        i += 1;
+    }
+
+    Ok(Value::Obj(Obj::List(kept)))
+}
+
+// sort(list) or sort(list, less) gives back a sorted copy. Without `less` it sorts numbers or
+// strings, with it `less(a, b)` says whether a goes before b. Items that are equal keep their
+// order.
+fn sort(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let list = list_arg(&values[0], "sort() expects a list as its first argument")?;
+    let less = match values.len() {
+        1 => None,
+        2 => Some(values[1]),
+        args => return Err(format!("sort Expected 1 or 2 arguments but got {}", args).into()),
+    };
+
+    // The copy keeps the items alive, even if `less` takes them out of the list
+    let items = ctx.heap().list(list).items.clone();
+    let Value::Obj(Obj::List(sorted)) = ctx.new_list(items.clone()) else {
+        unreachable!()
+    };
+
+    let items = merge_sort(ctx, items, less)?;
+    ctx.heap_mut().list_mut(sorted).items = items;
+
+    Ok(Value::Obj(Obj::List(sorted)))
+}
+
+// Merge sort instead of sort_by, `less` can be anything and sort_by may panic when it isn't a
+// proper ordering.
+fn merge_sort(
+    ctx: &mut NativeContext,
+    mut items: Vec<Value>,
+    less: Option<Value>,
+) -> Result<Vec<Value>, NativeError> {
+    if items.len() <= 1 {
+        return Ok(items);
+    }
+
+    let right = items.split_off(items.len() / 2);
+    let left = merge_sort(ctx, items, less)?;
+    let right = merge_sort(ctx, right, less)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut i, mut j) = (0, 0);
+    while i < left.len() && j < right.len() {
+        // Only takes from the right when it's strictly less, so equal items stay in order
+        if is_less(ctx, right[j], left[i], less)? {
+            merged.push(right[j]);
+            j += 1;
+        } else {
+            merged.push(left[i]);
+            i += 1;
+        }
+    }
+    merged.extend_from_slice(&left[i..]);
+    merged.extend_from_slice(&right[j..]);
+
+    Ok(merged)
+}
+
+fn is_less(
+    ctx: &mut NativeContext,
+    a: Value,
+    b: Value,
+    less: Option<Value>,
+) -> Result<bool, NativeError> {
+    if let Some(less) = less {
+        return Ok(is_truthy(ctx.call(less, &[a, b])?));
+    }
+
+    let heap = ctx.heap();
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => Ok(a < b),
+        _ => match (heap.as_str(&a), heap.as_str(&b)) {
+            (Some(a), Some(b)) => Ok(a < b),
+            _ => Err(
+                "sort() can only compare numbers or strings, pass a function to sort anything else"
+                    .into(),
+            ),
+        },
     }
 }
@@ -23,7 +23,7 @@ pub fn center_text(text: &str, offset: u16) -> String {
         String::new()
     };
 
-    String::from(num_empty_space + text)
+    num_empty_space + text
 }
 
 pub fn center_multi_line_text(text: &mut String) {
@@ -49,8 +49,8 @@ pub fn center_multi_line_text(text: &mut String) {
     }
 
     let mut offset = 0;
-    for i in 0..idx.len() - 1 {
-        text.insert_str(idx[i] + offset, &empty_space);
+    for start in &idx[..idx.len() - 1] {
+        text.insert_str(start + offset, &empty_space);
         offset += empty_space.len();
     }
 }
@@ -86,7 +86,7 @@ fn center_hint(hint: &str) -> String {
 
     let mut counter = 0;
     let mut is_end = 0;
-    for i in 0..chars.len() {
+    for char in chars {
         if counter % 40 == 0 {
             is_end += 1;
             if is_end % 2 == 0 {
@@ -98,7 +98,7 @@ fn center_hint(hint: &str) -> String {
                 new_hint.push(' ');
             }
         }
-        if chars[i] == '\n' {
+        if char == '\n' {
             let fill_amount = 40 - (counter % 40);
             new_hint.push_str(&" ".repeat(fill_amount));
             new_hint.push(' ');
@@ -107,11 +107,11 @@ fn center_hint(hint: &str) -> String {
             counter = 0;
             is_end = 0;
         } else {
-            if !chars[i].is_ascii() {
+            if !char.is_ascii() {
                 counter += 1;
             }
             counter += 1;
-            new_hint.push(chars[i]);
+            new_hint.push(char);
         }
     }
 
@@ -134,8 +134,8 @@ fn center_hint(hint: &str) -> String {
     }
 
     let mut offset = 0;
-    for i in 0..idx.len() - 1 {
-        new_hint.insert_str(idx[i] + offset, &empty_space);
+    for start in &idx[..idx.len() - 1] {
+        new_hint.insert_str(start + offset, &empty_space);
         offset += empty_space.len();
     }
 
@@ -160,8 +160,8 @@ fn center_dog(dog: &mut String) {
         String::new()
     };
 
-    for i in 0..idx.len() - 1 {
-        dog.insert_str(idx[i] + offset, &empty_space);
+    for start in &idx[..idx.len() - 1] {
+        dog.insert_str(start + offset, &empty_space);
         offset += empty_space.len();
     }
 }
@@ -179,7 +179,7 @@ pub fn print_msg(success: bool, msg: &str, stages: Arc<RwLock<StageInfo>>) {
     }
 }
 
-fn failure_output(msg: &str, stage: &String) {
+fn failure_output(msg: &str, stage: &str) {
     print!(
         "\
  \x1B[38;5;124m
@@ -205,8 +205,8 @@ fn failure_output(msg: &str, stage: &String) {
     );
 }
 
-fn success_output(msg: &str, stage: &String) {
-    let mut centered_text = format!(
+fn success_output(msg: &str, stage: &str) {
+    let mut centered_text = String::from(
         "\
 \n
 \x1B[38;5;160m##    ## ##     ## ########   #######   ######
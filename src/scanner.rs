@@ -1,4 +1,4 @@
-use std::fmt::Display;
+use std::{fmt::Display, ops::Range};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[allow(dead_code)]
@@ -8,6 +8,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
 
     // Symbols
     Comma,
@@ -16,6 +18,9 @@ pub enum TokenType {
     Plus,
     Slash,
     Star,
+    StarStar,
+    Percent,
+    Caret,
     Semicolon,
     Colon,
 
@@ -34,9 +39,14 @@ pub enum TokenType {
     And,
     Or,
 
+    // Integer division, since // starts a comment
+    Div,
+
     // Literals
     Identifier,
     String,
+    // The part of a string before a `{`, an expression and more of the string follow it
+    Interpolation,
     Number,
 
     // Boolean values
@@ -59,6 +69,8 @@ pub enum TokenType {
     For,
     While,
     Do,
+    Break,
+    Continue,
 
     // Ending Keyword for functions, loops, etc
     End,
@@ -66,6 +78,9 @@ pub enum TokenType {
     // Variable declaration
     Let,
 
+    // Modules
+    Import,
+
     // Return statement
     Return,
 
@@ -76,7 +91,7 @@ pub enum TokenType {
     NewLine,
 
     Error,
-    EOF,
+    Eof,
 }
 
 impl Display for TokenType {
@@ -90,14 +105,26 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: usize,
+    // Starts at 1, counted in chars
+    pub column: usize,
+    // Char offsets of the token inside the source
+    pub span: Range<usize>,
 }
 
 impl Token {
-    pub fn new(token_type: TokenType, lexeme: String, line: usize) -> Self {
+    pub fn new(
+        token_type: TokenType,
+        lexeme: String,
+        line: usize,
+        column: usize,
+        span: Range<usize>,
+    ) -> Self {
         Token {
             token_type,
             lexeme,
             line,
+            column,
+            span,
         }
     }
 }
@@ -117,6 +144,21 @@ pub struct Scanner {
     start: usize,
     current: usize,
     line: usize,
+    // Where the current line begins, used to work out columns
+    line_start: usize,
+    // One entry for every `{` inside a string we are in
+    interpolations: Vec<Interpolation>,
+}
+
+// An expression inside a string, started by `{`
+#[derive(Clone)]
+struct Interpolation {
+    // The braces opened since, the `}` that brings it back to 0 goes back to scanning the string
+    depth: usize,
+    // Where the `{` is, errors point at it when the expression never ends
+    line: usize,
+    column: usize,
+    offset: usize,
 }
 
 impl Scanner {
@@ -129,6 +171,8 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            interpolations: Vec::new(),
         }
     }
 
@@ -141,11 +185,56 @@ impl Scanner {
             token_type,
             self.source[self.start..self.current].iter().collect(),
             self.line,
+            self.column(),
+            self.start..self.current,
         )
     }
 
     fn make_error_token(&self, msg: String) -> Token {
-        Token::new(TokenType::Error, msg, self.line)
+        Token::new(
+            TokenType::Error,
+            msg,
+            self.line,
+            self.column(),
+            self.start..self.current,
+        )
+    }
+
+    // Points at the `{` still open when the source ends. They're all dropped, so the next token
+    // is the end of file.
+    fn unterminated_interpolation(&mut self) -> Token {
+        let open = self.interpolations.pop().unwrap();
+        self.interpolations.clear();
+
+        Token::new(
+            TokenType::Error,
+            "Unterminated interpolation, expected '}'".to_string(),
+            open.line,
+            open.column,
+            open.offset..open.offset + 1,
+        )
+    }
+
+    // Column of the token being scanned. Tokens that cross lines, like strings, use the line they
+    // end on.
+    fn column(&self) -> usize {
+        self.start.saturating_sub(self.line_start) + 1
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.line_start = self.current;
+    }
+
+    // Scans the token after the one the Parser is looking at, without moving past it.
+    pub fn peek_token(&mut self) -> Token {
+        let saved = (self.start, self.current, self.line, self.line_start);
+        // Peeking at a `{` or `}` inside an interpolated string changes these too
+        let interpolations = self.interpolations.clone();
+        let token = self.scan_token();
+        (self.start, self.current, self.line, self.line_start) = saved;
+        self.interpolations = interpolations;
+        token
     }
 
     pub fn advance(&mut self) -> char {
@@ -202,16 +291,54 @@ impl Scanner {
         }
     }
 
+    // Starts after the opening quote, or after the `}` ending an interpolated expression.
+    // Escapes are only checked here, the Parser decodes them with unescape.
     fn make_string(&mut self) -> Token {
+        let mut unknown_escape: Option<char> = None;
+
         while !self.is_at_end() && self.peek() != '"' {
-            self.advance();
+            match self.advance() {
+                '\n' => self.new_line(),
+                '\\' if !self.is_at_end() => {
+                    let escaped = self.advance();
+                    if escaped == '\n' {
+                        self.new_line();
+                    }
+                    if escape_char(escaped).is_none() && unknown_escape.is_none() {
+                        unknown_escape = Some(escaped);
+                    }
+                }
+                '{' => {
+                    if let Some(escaped) = unknown_escape {
+                        return self
+                            .make_error_token(format!("Unknown escape sequence '\\{}'", escaped));
+                    }
+
+                    self.interpolations.push(Interpolation {
+                        depth: 0,
+                        line: self.line,
+                        column: self.current - self.line_start,
+                        offset: self.current - 1,
+                    });
+                    return self.make_token(TokenType::Interpolation);
+                }
+                _ => {}
+            }
         }
 
         if self.is_at_end() {
+            // A string inside `{}` running to the end means the `{` was never closed, `"{"`
+            if !self.interpolations.is_empty() {
+                return self.unterminated_interpolation();
+            }
             return self.make_error_token("Unterminated String".to_string());
         }
 
         self.advance();
+
+        if let Some(escaped) = unknown_escape {
+            return self.make_error_token(format!("Unknown escape sequence '\\{}'", escaped));
+        }
         self.make_token(TokenType::String)
     }
 
@@ -247,47 +374,41 @@ impl Scanner {
 
         match char {
             'a' => return self.cmp_char(&['n', 'd'], TokenType::And),
-            'd' => return self.cmp_char(&['o'], TokenType::Do),
-            'i' => return self.cmp_char(&['f'], TokenType::If),
+            'b' => return self.cmp_char(&['r', 'e', 'a', 'k'], TokenType::Break),
+            'c' => return self.cmp_char(&['o', 'n', 't', 'i', 'n', 'u', 'e'], TokenType::Continue),
+            'i' => match self.peek_at(self.start + 1) {
+                'm' => return self.cmp_char(&['m', 'p', 'o', 'r', 't'], TokenType::Import),
+                _ => return self.cmp_char(&['f'], TokenType::If),
+            },
             'l' => return self.cmp_char(&['e', 't'], TokenType::Let),
             'n' => return self.cmp_char(&['o', 'n', 'e'], TokenType::None),
             'o' => return self.cmp_char(&['r'], TokenType::Or),
             'r' => return self.cmp_char(&['e', 't', 'u', 'r', 'n'], TokenType::Return),
             's' => return self.cmp_char(&['t', 'r', 'u', 'c', 't'], TokenType::Struct),
             'w' => return self.cmp_char(&['h', 'i', 'l', 'e'], TokenType::While),
-            'e' => {
-                if self.current - self.start > 1 {
-                    match self.peek_at(self.start + 1) {
-                        'l' => return self.cmp_char(&['l', 's', 'e'], TokenType::Else),
-                        'n' => return self.cmp_char(&['n', 'd'], TokenType::End),
-                        _ => {}
-                    }
-                }
-            }
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.peek_at(self.start + 1) {
-                        'h' => return self.cmp_char(&['h', 'e', 'n'], TokenType::Then),
-                        'r' => return self.cmp_char(&['r', 'u', 'e'], TokenType::True),
-                        _ => {}
-                    }
+            // cmp_char checks the length, so a lone 'd' is still an identifier
+            'd' => match self.peek_at(self.start + 1) {
+                'i' => return self.cmp_char(&['i', 'v'], TokenType::Div),
+                _ => return self.cmp_char(&['o'], TokenType::Do),
+            },
+            'e' if self.current - self.start > 1 => match self.peek_at(self.start + 1) {
+                'l' => return self.cmp_char(&['l', 's', 'e'], TokenType::Else),
+                'n' => return self.cmp_char(&['n', 'd'], TokenType::End),
+                _ => {}
+            },
+            't' if self.current - self.start > 1 => match self.peek_at(self.start + 1) {
+                'h' => return self.cmp_char(&['h', 'e', 'n'], TokenType::Then),
+                'r' => return self.cmp_char(&['r', 'u', 'e'], TokenType::True),
+                _ => {}
+            },
+            'f' if self.current - self.start > 1 => match self.peek_at(self.start + 1) {
+                'a' => return self.cmp_char(&['a', 'l', 's', 'e'], TokenType::False),
+                'o' => return self.cmp_char(&['o', 'r'], TokenType::For),
+                'u' => {
+                    return self.cmp_char(&['u', 'n', 'c', 't', 'i', 'o', 'n'], TokenType::Function)
                 }
-            }
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.peek_at(self.start + 1) {
-                        'a' => return self.cmp_char(&['a', 'l', 's', 'e'], TokenType::False),
-                        'o' => return self.cmp_char(&['o', 'r'], TokenType::For),
-                        'u' => {
-                            return self.cmp_char(
-                                &['u', 'n', 'c', 't', 'i', 'o', 'n'],
-                                TokenType::Function,
-                            )
-                        }
-                        _ => {}
-                    }
-                }
-            }
+                _ => {}
+            },
             _ => {}
         }
 
@@ -299,8 +420,8 @@ impl Scanner {
             return TokenType::Identifier;
         }
 
-        for i in 0..(self.current - self.start) - 1 {
-            if chars[i] != self.peek_at(self.start + 1 + i) {
+        for (i, char) in chars.iter().enumerate() {
+            if *char != self.peek_at(self.start + 1 + i) {
                 return TokenType::Identifier;
             }
         }
@@ -313,7 +434,10 @@ impl Scanner {
         self.start = self.current;
 
         if self.is_at_end() {
-            return self.make_token(TokenType::EOF);
+            if !self.interpolations.is_empty() {
+                return self.unterminated_interpolation();
+            }
+            return self.make_token(TokenType::Eof);
         }
 
         let char = self.advance();
@@ -327,19 +451,46 @@ impl Scanner {
         match char {
             '(' => return self.make_token(TokenType::LeftParen),
             ')' => return self.make_token(TokenType::RightParen),
-            '{' => return self.make_token(TokenType::LeftBrace),
-            '}' => return self.make_token(TokenType::RightBrace),
+            '{' => {
+                if let Some(interpolation) = self.interpolations.last_mut() {
+                    interpolation.depth += 1;
+                }
+                return self.make_token(TokenType::LeftBrace);
+            }
+            '}' => {
+                match self.interpolations.last_mut() {
+                    Some(Interpolation { depth: 0, .. }) => {
+                        self.interpolations.pop();
+                        return self.make_string();
+                    }
+                    Some(interpolation) => interpolation.depth -= 1,
+                    None => {}
+                }
+                return self.make_token(TokenType::RightBrace);
+            }
+            '[' => return self.make_token(TokenType::LeftBracket),
+            ']' => return self.make_token(TokenType::RightBracket),
             ',' => return self.make_token(TokenType::Comma),
             '.' => return self.make_token(TokenType::Dot),
             '+' => return self.make_token(TokenType::Plus),
             '-' => return self.make_token(TokenType::Minus),
-            '*' => return self.make_token(TokenType::Star),
+            '*' => {
+                if self.match_next('*') {
+                    return self.make_token(TokenType::StarStar);
+                }
+                return self.make_token(TokenType::Star);
+            }
+            '%' => return self.make_token(TokenType::Percent),
+            '^' => return self.make_token(TokenType::Caret),
             '/' => return self.make_token(TokenType::Slash),
             ';' => return self.make_token(TokenType::Semicolon),
+            ':' => return self.make_token(TokenType::Colon),
 
             '\n' => {
-                self.line += 1;
-                return self.make_token(TokenType::NewLine);
+                // The new line belongs to the line it ends
+                let token = self.make_token(TokenType::NewLine);
+                self.new_line();
+                return token;
             }
 
             '!' => {
@@ -371,6 +522,37 @@ impl Scanner {
             _ => {}
         }
 
-        return self.make_token(TokenType::Error);
+        self.make_error_token(format!("Unreadable symbol '{}'", char))
     }
 }
+
+fn escape_char(escaped: char) -> Option<char> {
+    match escaped {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        '0' => Some('\0'),
+        '\\' | '"' | '{' | '}' => Some(escaped),
+        _ => None,
+    }
+}
+
+// Turns the escape sequences in a string's text into the characters they stand for. The Scanner
+// already rejected unknown ones.
+pub fn unescape(text: &str) -> String {
+    let mut string = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(char) = chars.next() {
+        if char != '\\' {
+            string.push(char);
+            continue;
+        }
+
+        if let Some(escaped) = chars.next() {
+            string.push(escape_char(escaped).unwrap_or(escaped));
+        }
+    }
+
+    string
+}
@@ -0,0 +1,113 @@
+use super::Output;
+use crate::{Arity, InterpretError, NativeContext, NativeError, Value, VM};
+
+fn double(_ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    match values[0].as_number() {
+        Some(num) => Ok(Value::from(num * 2.0)),
+        None => Err(format!("double() can't double {:?}", values[0]).into()),
+    }
+}
+
+fn shout(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    match ctx.heap().as_str(&values[0]) {
+        Some(string) => Ok(Value::from(string.ends_with('!'))),
+        None => Err("shout() expects a string".into()),
+    }
+}
+
+// apply(f, args...) calls f with the rest of the arguments, then joins the result with a new
+// string so something gets allocated after the call.
+fn apply(ctx: &mut NativeContext, values: &[Value]) -> Result<Value, NativeError> {
+    let result = ctx.call(values[0], &values[1..])?;
+    let string = format!("{}?", ctx.heap().display(&result));
+    Ok(ctx.new_string(&string))
+}
+
+#[test]
+fn register_native() {
+    let mut vm = VM::new();
+    vm.register_native("double", Arity::Exactly(1), double);
+    vm.register_native("shout", Arity::Exactly(1), shout);
+
+    let greeting = vm.new_string("hi!");
+    vm.set_global("greeting", greeting);
+    vm.set_global("start", Value::from(21.0));
+
+    assert!(vm
+        .interpret("let result = double(start)\nlet loud = shout(greeting)\n".to_string())
+        .is_ok());
+    assert_eq!(vm.get_global("result"), Some(Value::Number(42.0)));
+    assert_eq!(vm.get_global("loud").and_then(|x| x.as_bool()), Some(true));
+
+    assert!(matches!(
+        vm.interpret("double(greeting)\n".to_string()),
+        Err(InterpretError::RuntimeError)
+    ));
+}
+
+#[test]
+fn native_calls_back_into_bite() {
+    let mut vm = VM::new();
+    vm.register_native("apply", Arity::AtLeast(1), apply);
+
+    let source_str = "\
+function add(a, b) do
+    return a + b
+end
+let sum = apply(add, 1, 2)
+let nested = apply(apply, add, 3, 4)
+";
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+
+    let sum = vm.get_global("sum").unwrap();
+    assert_eq!(vm.heap().display(&sum).to_string(), "3?");
+    let nested = vm.get_global("nested").unwrap();
+    assert_eq!(vm.heap().display(&nested).to_string(), "7??");
+
+    // Errors inside the callback stop the whole script, and the VM can still run afterwards
+    assert!(matches!(
+        vm.interpret("function bad() do\n    return 1 / 0\nend\napply(bad)\n".to_string()),
+        Err(InterpretError::RuntimeError)
+    ));
+    assert!(matches!(
+        vm.interpret("apply()\n".to_string()),
+        Err(InterpretError::RuntimeError)
+    ));
+    assert!(vm
+        .interpret("let again = apply(add, 2, 2)\n".to_string())
+        .is_ok());
+}
+
+#[test]
+fn compile_and_interpret() {
+    assert!(crate::compile("let x = 1\n").is_ok());
+
+    let diagnostics = crate::compile("let x = \n").unwrap_err();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].line, 1);
+
+    let vm = crate::interpret("let x = [1, 2, 3]\nlet y = len(x)\n").unwrap();
+    assert_eq!(vm.get_global("y"), Some(Value::Number(3.0)));
+    assert_eq!(Value::from(None::<f64>), Value::None);
+}
+
+#[test]
+fn capture_output() {
+    let output = Output::default();
+    let errors = Output::default();
+    let mut vm = VM::new();
+    vm.set_output(output.clone());
+    vm.set_error_output(errors.clone());
+
+    assert!(vm.interpret("print(\"hi\", 1 + 2)\n".to_string()).is_ok());
+    assert!(vm.interpret_repl("[1, 2]\n".to_string()).is_ok());
+    assert_eq!(output.text(), "hi, 3\n[1, 2]\n");
+    assert_eq!(errors.text(), "");
+
+    assert!(matches!(
+        vm.interpret("let x = 1 + none\n".to_string()),
+        Err(InterpretError::RuntimeError)
+    ));
+    assert!(errors.text().starts_with("> Program Start\n"));
+    assert!(errors.text().contains("let x = 1 + none"));
+}
@@ -0,0 +1,54 @@
+use super::{assert_global, assert_runtime_error};
+use crate::compiler::values::Value;
+
+#[test]
+fn modulo() {
+    assert_global("let x = 10 % 3\n", "x", Value::Number(1.0));
+    assert_global("let x = -7 % 3\n", "x", Value::Number(2.0));
+    assert_global("let x = 7 % -3\n", "x", Value::Number(-2.0));
+    assert_global("let x = 5.5 % 2\n", "x", Value::Number(1.5));
+
+    let source_str = "\
+let evens = 0
+for let i = 1, i <= 10, i = i + 1 do
+    if i % 2 == 0 then
+        evens = evens + 1
+    end
+end
+";
+    assert_global(source_str, "evens", Value::Number(5.0));
+}
+
+#[test]
+fn integer_division() {
+    assert_global("let x = 7 div 2\n", "x", Value::Number(3.0));
+    assert_global("let x = -7 div 2\n", "x", Value::Number(-4.0));
+    assert_global("let x = 9 div 3 * 2\n", "x", Value::Number(6.0));
+    // Still a comment
+    assert_global("let x = 7 // 2\n", "x", Value::Number(7.0));
+}
+
+#[test]
+fn exponent() {
+    assert_global("let x = 2 ^ 10\n", "x", Value::Number(1024.0));
+    assert_global("let x = 2 ** 3\n", "x", Value::Number(8.0));
+    assert_global("let x = 2 ^ 3 ^ 2\n", "x", Value::Number(512.0));
+    assert_global("let x = -2 ^ 2\n", "x", Value::Number(-4.0));
+    assert_global("let x = 2 * 3 ^ 2\n", "x", Value::Number(18.0));
+    assert_global("let x = 4 ^ -1\n", "x", Value::Number(0.25));
+}
+
+#[test]
+fn left_associative() {
+    assert_global("let x = 10 - 2 - 3\n", "x", Value::Number(5.0));
+    assert_global("let x = 16 / 4 / 2\n", "x", Value::Number(2.0));
+    assert_global("let x = 20 % 7 % 4\n", "x", Value::Number(2.0));
+}
+
+#[test]
+fn divide_by_zero() {
+    assert_runtime_error("let x = 1 / 0\n");
+    assert_runtime_error("let x = 1 % 0\n");
+    assert_runtime_error("let x = 1 div 0\n");
+    assert_runtime_error("let x = 0 / 0\n");
+}
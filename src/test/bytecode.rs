@@ -0,0 +1,166 @@
+use crate::{
+    compiler::{
+        bytecode::{self, BytecodeError, MAGIC, VERSION},
+        values::{GlobalSlots, Value},
+        Parser,
+    },
+    vm::{heap::Heap, InterpretError, VM},
+};
+
+const PROGRAM: &str = "
+struct Point {
+    x, y
+    function sum() do
+        return self.x + self.y
+    end
+}
+function adder(n) do
+    function add(x) do
+        return x + n
+    end
+    return add
+end
+let p = Point{1, 2}
+let total = 0
+for let i = 0, i < 4, i = i + 1 do
+    if i == 2 then
+        continue
+    end
+    total = total + adder(i)(p.sum())
+end
+let text = \"total {total}\"
+let big = 2 ^ 70
+let items = [1.5, -0.25, none, true]
+";
+
+fn compile(source_str: &str) -> Vec<u8> {
+    let mut vm = VM::new();
+    vm.compile_bytecode(source_str.to_string()).unwrap()
+}
+
+#[test]
+fn runs_the_same_as_source() {
+    let bytes = compile(PROGRAM);
+    assert!(bytecode::is_bytecode(&bytes));
+
+    let mut from_source = VM::new();
+    assert!(from_source.interpret(PROGRAM.to_string()).is_ok());
+    let mut from_bytes = VM::new();
+    assert!(from_bytes.interpret_bytecode(&bytes).is_ok());
+
+    for name in ["total", "big"] {
+        assert_eq!(from_bytes.get_global(name), from_source.get_global(name));
+    }
+    for name in ["text", "items"] {
+        let expected = from_source.get_global(name).unwrap();
+        let value = from_bytes.get_global(name).unwrap();
+        assert_eq!(
+            from_bytes.heap().display(&value).to_string(),
+            from_source.heap().display(&expected).to_string()
+        );
+    }
+}
+
+#[test]
+fn keeps_lines_and_columns() {
+    let mut heap = Heap::new();
+    let mut parser = Parser::new(&mut heap);
+    let function = parser.compile(PROGRAM.to_string()).unwrap();
+    let slots = parser.take_global_slots();
+    let bytes = bytecode::save(&heap, &function, &slots).unwrap();
+
+    let mut loaded_heap = Heap::new();
+    let loaded = bytecode::load(&mut loaded_heap, &mut GlobalSlots::default(), &bytes).unwrap();
+
+    assert_eq!(loaded.chunk.code, function.chunk.code);
+    for (offset, _) in function.chunk.instructions() {
+        assert_eq!(loaded.chunk.line_at(offset), function.chunk.line_at(offset));
+        assert_eq!(
+            loaded.chunk.columns_at(offset),
+            function.chunk.columns_at(offset)
+        );
+    }
+    assert!(loaded.chunk.source.is_empty());
+}
+
+#[test]
+fn fits_into_existing_globals() {
+    // Enough globals already there that the file's ones need wider operands, which moves jumps
+    let mut vm = VM::new();
+    let mut setup = String::new();
+    for i in 0..200 {
+        setup.push_str(&format!("let g{} = {}\n", i, i));
+    }
+    assert!(vm.interpret(setup).is_ok());
+
+    assert!(vm.interpret_bytecode(&compile(PROGRAM)).is_ok());
+    let mut plain = VM::new();
+    assert!(plain.interpret(PROGRAM.to_string()).is_ok());
+    assert_eq!(vm.get_global("total"), plain.get_global("total"));
+    assert_eq!(vm.get_global("g150"), Some(Value::Number(150.0)));
+
+    // Globals the file shares with the VM are the same variable
+    assert!(vm
+        .interpret_bytecode(&compile("total = total + g1\n"))
+        .is_ok());
+    assert_eq!(
+        vm.get_global("total").and_then(|x| x.as_number()),
+        plain
+            .get_global("total")
+            .and_then(|x| x.as_number())
+            .map(|x| x + 1.0)
+    );
+}
+
+#[test]
+fn reports_errors() {
+    let mut vm = VM::new();
+    let res = vm.interpret_bytecode(&compile("let a = 1\nlet b = a + missing\n"));
+    assert!(matches!(res, Err(InterpretError::RuntimeError)));
+
+    let res = VM::new().compile_bytecode("let a = \n".to_string());
+    assert!(matches!(res, Err(InterpretError::CompileError(_))));
+}
+
+#[test]
+fn rejects_bad_files() {
+    let bytes = compile(PROGRAM);
+    let load = |bytes: &[u8]| {
+        bytecode::load(&mut Heap::new(), &mut GlobalSlots::default(), bytes).map(|_| ())
+    };
+
+    assert_eq!(load(b"let x = 1\n"), Err(BytecodeError::NotBytecode));
+
+    let mut old = bytes.clone();
+    old[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_be_bytes());
+    assert_eq!(load(&old), Err(BytecodeError::WrongVersion(VERSION + 1)));
+
+    // Cut off anywhere, the file is reported instead of crashing
+    for len in MAGIC.len()..bytes.len() {
+        assert!(
+            matches!(load(&bytes[..len]), Err(BytecodeError::Corrupt(_))),
+            "{}",
+            len
+        );
+    }
+
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert!(matches!(load(&longer), Err(BytecodeError::Corrupt(_))));
+
+    let mut vm = VM::new();
+    let res = vm.interpret_bytecode(&bytes[..bytes.len() - 1]);
+    assert!(matches!(res, Err(InterpretError::BytecodeError(_))));
+
+    // A file that fails to load leaves the module's globals as they were
+    let mut heap = Heap::new();
+    let mut slots = GlobalSlots::default();
+    slots.slot(heap.intern("keep"));
+    let before = slots.names.clone();
+    for len in [MAGIC.len() + 3, bytes.len() / 2, bytes.len() - 1] {
+        assert!(bytecode::load(&mut heap, &mut slots, &bytes[..len]).is_err());
+        assert_eq!(slots.names, before, "{}", len);
+    }
+    assert!(bytecode::load(&mut heap, &mut slots, &bytes).is_ok());
+    assert!(slots.names.len() > before.len());
+}
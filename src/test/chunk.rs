@@ -0,0 +1,111 @@
+use crate::{
+    compiler::chunk::{Chunk, OpCode, JUMP_SIZE},
+    vm::VM,
+};
+
+#[test]
+fn encodes_every_operand() {
+    let codes = [
+        OpCode::OpReturn,
+        OpCode::OpCall(255),
+        OpCode::OpJumpIfFalse(u16::MAX),
+        OpCode::OpLoop(258),
+        OpCode::OpConstant(0),
+        OpCode::OpConstant(127),
+        OpCode::OpConstant(128),
+        OpCode::OpGetGlobal(300),
+        OpCode::OpSetLocal(1 << 40),
+        OpCode::OpInvoke(20_000, 3),
+        OpCode::OpBuildString(7),
+        OpCode::OpImport(usize::MAX),
+        OpCode::OpLessEqual,
+    ];
+
+    let mut chunk = Chunk::new();
+    let mut offsets = Vec::new();
+    for code in codes {
+        offsets.push(chunk.write_code(code, 1, 1..2));
+    }
+
+    let decoded: Vec<(usize, OpCode)> = chunk.instructions().collect();
+    let expected: Vec<(usize, OpCode)> = offsets.into_iter().zip(codes).collect();
+    assert_eq!(decoded, expected);
+
+    let size: usize = codes.iter().map(|x| x.size()).sum();
+    assert_eq!(chunk.code.len(), size);
+    assert_eq!(OpCode::OpReturn.size(), 1);
+    assert_eq!(OpCode::OpConstant(127).size(), 2);
+    assert_eq!(OpCode::OpConstant(128).size(), 3);
+    assert_eq!(OpCode::OpJump(0).size(), JUMP_SIZE);
+}
+
+#[test]
+fn finds_lines_and_columns() {
+    let mut chunk = Chunk::new();
+    chunk.write_code(OpCode::OpConstant(0), 1, 9..10);
+    chunk.write_code(OpCode::OpDefineGlobal(0), 1, 5..6);
+    let second = chunk.write_code(OpCode::OpGetGlobal(200), 2, 9..10);
+    chunk.write_code(OpCode::OpPop, 4, 1..2);
+
+    assert_eq!(chunk.line_at(0), 1);
+    assert_eq!(chunk.line_at(3), 1);
+    // Every byte of an instruction points back at it
+    for offset in second..second + 3 {
+        assert_eq!(chunk.line_at(offset), 2);
+        assert_eq!(chunk.columns_at(offset), 9..10);
+    }
+    assert_eq!(chunk.line_at(chunk.code.len() - 1), 4);
+    assert_eq!(chunk.columns_at(2), 5..6);
+
+    let jump = chunk.write_code(OpCode::OpJump(u16::MAX), 4, 1..2);
+    chunk.patch_jump(jump, 0x1234);
+    assert_eq!(
+        chunk.read_code(jump),
+        (OpCode::OpJump(0x1234), jump + JUMP_SIZE)
+    );
+}
+
+#[test]
+fn positions_match_each_instruction() {
+    let mut chunk = Chunk::new();
+    chunk.write_code(OpCode::OpGetGlobal(0), 1, 1..4);
+    // The same columns one after the other share a run
+    chunk.write_code(OpCode::OpGetGlobal(1000), 1, 1..4);
+    chunk.write_code(OpCode::OpCall(0), 1, 1..4);
+    chunk.write_code(OpCode::OpPop, 2, 300..302);
+    chunk.write_code(OpCode::OpNone, 2, 1..4);
+
+    let positions: Vec<(usize, std::ops::Range<usize>)> = chunk.positions().collect();
+    assert_eq!(
+        positions,
+        vec![(1, 1..4), (1, 1..4), (1, 1..4), (2, 300..302), (2, 1..4)]
+    );
+    for ((offset, _), (line, columns)) in chunk.instructions().zip(positions) {
+        assert_eq!(chunk.line_at(offset), line);
+        assert_eq!(chunk.columns_at(offset), columns);
+    }
+}
+
+#[test]
+fn runs_wide_operands() {
+    // Enough globals and constants that their indices take two bytes
+    let mut source_str = String::new();
+    for i in 0..200 {
+        source_str.push_str(&format!("let g{} = {}.5\n", i, i));
+    }
+    source_str.push_str("let total = 0\n");
+    source_str.push_str("for let i = 0, i < 3, i = i + 1 do\n");
+    for i in (0..200).step_by(10) {
+        source_str.push_str(&format!("    total = total + g{}\n", i));
+    }
+    source_str.push_str("end\n");
+
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str).is_ok());
+
+    let expected: f64 = (0..200).step_by(10).map(|x| x as f64 + 0.5).sum::<f64>() * 3.0;
+    assert_eq!(
+        vm.get_global("total").and_then(|x| x.as_number()),
+        Some(expected)
+    );
+}
@@ -0,0 +1,76 @@
+use super::assert_global;
+use crate::compiler::values::Value;
+
+#[test]
+fn counter_closure() {
+    let source_str = "\
+function make_counter() do
+    let count = 0
+    function inc() do
+        count = count + 1
+        return count
+    end
+    return inc
+end
+let counter = make_counter()
+counter()
+counter()
+let result = counter()
+let other = make_counter()()
+";
+    assert_global(source_str, "result", Value::Number(3.0));
+    assert_global(source_str, "other", Value::Number(1.0));
+}
+
+#[test]
+fn nested_capture() {
+    let source_str = "\
+function outer(x) do
+    function middle() do
+        function inner() do
+            return x * 2
+        end
+        return inner
+    end
+    return middle()
+end
+let result = outer(21)()
+";
+    assert_global(source_str, "result", Value::Number(42.0));
+}
+
+#[test]
+fn shared_upvalue() {
+    let source_str = "\
+let get = none
+let set = none
+do
+    let a = 1
+    function getter() do
+        return a
+    end
+    function setter(value) do
+        a = value
+    end
+    get = getter
+    set = setter
+end
+set(10)
+let result = get()
+";
+    assert_global(source_str, "result", Value::Number(10.0));
+}
+
+#[test]
+fn pass_function() {
+    let source_str = "\
+function apply(f, value) do
+    return f(value)
+end
+function double(n) do
+    return n * 2
+end
+let result = apply(double, 4)
+";
+    assert_global(source_str, "result", Value::Number(8.0));
+}
@@ -0,0 +1,123 @@
+use super::assert_global;
+use crate::{
+    compiler::{values::Value, Parser},
+    vm::{heap::Heap, VM},
+};
+
+#[test]
+fn long_loop_body() {
+    let mut source_str = String::from("let total = 0\nlet i = 0\nwhile i < 3 do\n");
+    source_str.push_str(&"    total = total + 1\n".repeat(300));
+    source_str.push_str("    i = i + 1\nend\nif total > 0 then\n");
+    source_str.push_str(&"    total = total + 1\n".repeat(300));
+    source_str.push_str("end\n");
+
+    assert_global(&source_str, "total", Value::Number(1200.0));
+}
+
+#[test]
+fn loop_body_too_large() {
+    let mut source_str = String::from("let total = 0\nwhile total < 3 do\n");
+    source_str.push_str(&"    total = total + 1\n".repeat(20000));
+    source_str.push_str("end\n");
+
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str).is_err());
+}
+
+#[test]
+fn break_and_continue() {
+    let source_str = "\
+let total = 0
+for let i = 0, i < 10, i = i + 1 do
+    let skip = i == 3
+    if skip then
+        continue
+    end
+    if i == 6 then
+        break
+    end
+    total = total + i
+end
+let n = 0
+while true do
+    let a = 1
+    n = n + a
+    if n > 4 then
+        break
+    end
+end
+";
+    assert_global(source_str, "total", Value::Number(12.0));
+    assert_global(source_str, "n", Value::Number(5.0));
+}
+
+#[test]
+fn break_nested_loops() {
+    let source_str = "\
+let pairs = 0
+for let i = 0, i < 5, i = i + 1 do
+    let j = 0
+    while true do
+        j = j + 1
+        if j > i then
+            break
+        end
+        if j == 2 then
+            continue
+        end
+        pairs = pairs + 1
+    end
+end
+let after = 7
+function first_over(limit) do
+    let i = 0
+    while true do
+        let doubled = i * 2
+        if doubled > limit then
+            return doubled
+        end
+        i = i + 1
+    end
+end
+let result = first_over(9) + after
+";
+    // j counts 1..=i but skips 2, so pairs = 0 + 1 + 1 + 2 + 3
+    assert_global(source_str, "pairs", Value::Number(7.0));
+    assert_global(source_str, "result", Value::Number(17.0));
+}
+
+#[test]
+fn break_closes_captured_locals() {
+    let source_str = "\
+let getters = []
+for let i = 0, i < 5, i = i + 1 do
+    let value = i * 10
+    function get() do
+        return value
+    end
+    push(getters, get)
+    if i == 2 then
+        break
+    end
+end
+let result = getters[0]() + getters[2]()
+";
+    assert_global(source_str, "result", Value::Number(20.0));
+}
+
+#[test]
+fn break_outside_loop() {
+    for (source_str, message) in [
+        ("break\n", "Can't use 'break' outside of a loop"),
+        (
+            "while true do\nfunction f() do\ncontinue\nend\nend\n",
+            "Can't use 'continue' outside of a loop",
+        ),
+    ] {
+        let mut heap = Heap::new();
+        let mut parser = Parser::new(&mut heap);
+        let diagnostics = parser.compile(source_str.to_string()).unwrap_err();
+        assert_eq!(diagnostics[0].message, message);
+    }
+}
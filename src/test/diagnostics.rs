@@ -0,0 +1,97 @@
+use crate::{
+    compiler::{
+        chunk::OpCode,
+        diagnostic::{render_snippet, Diagnostic, Severity},
+        Parser,
+    },
+    vm::heap::Heap,
+};
+
+fn compile_errors(source_str: &str) -> Vec<Diagnostic> {
+    let mut heap = Heap::new();
+    let mut parser = Parser::new(&mut heap);
+    match parser.compile(source_str.to_string()) {
+        Ok(_) => panic!("Expected {:?} to fail compiling", source_str),
+        Err(diagnostics) => diagnostics,
+    }
+}
+
+#[test]
+fn reports_position() {
+    let diagnostics = compile_errors("let x = 1\nlet y = (x + 2\n");
+    assert_eq!(diagnostics.len(), 1);
+
+    let diagnostic = &diagnostics[0];
+    assert_eq!(diagnostic.severity, Severity::Error);
+    assert_eq!(diagnostic.message, "Expected ) here.");
+    assert_eq!(diagnostic.line, 2);
+    assert_eq!(diagnostic.column, 15);
+    assert_eq!(diagnostic.span, 24..25);
+    assert_eq!(diagnostic.found, Some("new line".to_string()));
+}
+
+#[test]
+fn reports_every_statement() {
+    let diagnostics = compile_errors("let = 1\nlet b = 2\nlet c = )\n");
+    assert_eq!(diagnostics.len(), 2);
+    assert_eq!(diagnostics[0].line, 1);
+    assert_eq!(diagnostics[0].found, Some("=".to_string()));
+    assert_eq!(diagnostics[1].line, 3);
+    assert_eq!(diagnostics[1].column, 9);
+}
+
+#[test]
+fn reports_end_of_file() {
+    let diagnostics = compile_errors("while true do\nprint(1)\n");
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].found, None);
+    assert_eq!(
+        diagnostics[0].to_string(),
+        "[line 3:1] Error at end of file: Expected Closing End keyword here"
+    );
+}
+
+#[test]
+fn reports_unreadable_symbols() {
+    let diagnostics = compile_errors("let x = 1 $ 2\n");
+    assert_eq!(diagnostics[0].message, "Unreadable symbol '$'");
+    assert_eq!(diagnostics[0].column, 11);
+    assert_eq!(diagnostics[0].span, 10..11);
+}
+
+#[test]
+fn renders_snippet() {
+    let source_str = "let x = 1\nlet y = x +\n";
+    let diagnostics = compile_errors(source_str);
+    assert_eq!(
+        diagnostics[0].render(source_str),
+        "\
+[line 2:12] Error at 'new line': Expected Expression
+  |
+2 | let y = x +
+  |            ^"
+    );
+
+    assert_eq!(
+        render_snippet("\tprint(a + b)\n", 1, 9..10),
+        "  |\n1 | \tprint(a + b)\n  | \t       ^"
+    );
+    assert_eq!(
+        render_snippet("let s = 1\nlet t = s\n", 2, 9..10),
+        "  |\n2 | let t = s\n  |         ^"
+    );
+}
+
+#[test]
+fn records_opcode_columns() {
+    let mut heap = Heap::new();
+    let mut parser = Parser::new(&mut heap);
+    // Otherwise the whole expression gets folded into one constant
+    parser.set_optimize(false);
+    let function = parser.compile("let x = -(1 + 2)\n".to_string()).unwrap();
+
+    let chunk = &function.chunk;
+    let offset_of = |code: OpCode| chunk.instructions().find(|x| x.1 == code).unwrap().0;
+    assert_eq!(chunk.columns_at(offset_of(OpCode::OpAdd)), 13..14);
+    assert_eq!(chunk.columns_at(offset_of(OpCode::OpNegate)), 9..10);
+}
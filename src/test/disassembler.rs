@@ -0,0 +1,97 @@
+use crate::vm::{InterpretError, VM};
+
+const PROGRAM: &str = "
+let total = 0
+function counter(step) do
+    let count = 0
+    function next() do
+        count = count + step
+        return count
+    end
+    return next
+end
+while total < 10 do
+    total = total + counter(2)()
+end
+let done = !(total == 10)
+";
+
+#[test]
+fn lists_nested_functions() {
+    let listing = VM::new().disassemble(PROGRAM.to_string()).unwrap();
+
+    let headers: Vec<&str> = listing.lines().filter(|x| x.starts_with("===")).collect();
+    assert_eq!(
+        headers,
+        [
+            "=== <script> ===",
+            "=== <fn counter> ===",
+            "=== <fn next> ==="
+        ]
+    );
+    assert!(listing.contains("OpDefineGlobal      0 total"));
+    assert!(listing.contains("OpClosure"));
+    assert!(listing.contains("OpGetUpvalue"));
+    assert!(listing.contains("OpNotEqual"));
+}
+
+#[test]
+fn resolves_jump_targets() {
+    let listing = VM::new().disassemble(PROGRAM.to_string()).unwrap();
+    let offsets: Vec<&str> = listing
+        .lines()
+        .filter_map(|x| x.split_whitespace().next())
+        .collect();
+
+    let targets: Vec<&str> = listing
+        .lines()
+        .filter_map(|x| x.split_once("-> "))
+        .map(|x| x.1)
+        .collect();
+    // The while loop jumps out once and loops back once
+    assert_eq!(targets.len(), 2);
+    for target in targets {
+        assert!(offsets.contains(&target), "{}", target);
+    }
+}
+
+#[test]
+fn follows_the_optimizer_setting() {
+    let mut vm = VM::new();
+    vm.set_optimize(false);
+    let listing = vm.disassemble(PROGRAM.to_string()).unwrap();
+    assert!(listing.contains("OpNot"));
+    assert!(!listing.contains("OpNotEqual"));
+}
+
+#[test]
+fn reads_compiled_files() {
+    let bytes = VM::new().compile_bytecode(PROGRAM.to_string()).unwrap();
+    let listing = VM::new().disassemble_bytecode(&bytes).unwrap();
+    assert_eq!(listing, VM::new().disassemble(PROGRAM.to_string()).unwrap());
+
+    let res = VM::new().disassemble("let x = \n".to_string());
+    assert!(matches!(res, Err(InterpretError::CompileError(_))));
+}
+
+#[test]
+fn lists_tokens() {
+    let listing = crate::tokens("let x = \"a {1}\"\n");
+    let lines: Vec<&str> = listing.lines().collect();
+
+    assert_eq!(lines[0], "1:1      Let              \"let\"");
+    assert_eq!(lines[1], "1:5      Identifier       \"x\"");
+    assert!(lines.iter().any(|x| x.contains("NewLine          \"\\n\"")));
+    assert!(lines.last().unwrap().contains("Eof"));
+}
+
+#[test]
+fn traces_without_changing_results() {
+    let mut vm = VM::new();
+    vm.set_trace(true);
+    assert!(vm.interpret(PROGRAM.to_string()).is_ok());
+    assert_eq!(
+        vm.get_global("total").and_then(|x| x.as_number()),
+        Some(10.0)
+    );
+}
@@ -0,0 +1,135 @@
+use crate::{compiler::values::Value, vm::VM};
+
+#[test]
+fn collects_instance_cycles() {
+    let source_str = "\
+struct Node { next, value }
+let keep = Node {}
+keep.value = 0
+let i = 0
+while i < 20000 do
+    let a = Node {}
+    let b = Node {}
+    a.next = b
+    b.next = a
+    a.value = i
+    keep.value = keep.value + a.value
+    i = i + 1
+end
+let total = keep.value
+";
+    let mut vm = VM::new();
+    // The natives are already in the heap
+    let natives = vm.object_count();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+    assert_eq!(vm.get_global("total"), Some(Value::Number(199990000.0)));
+
+    // Only the struct, the kept instance and the interned names of the globals and fields
+    // (Node, keep, i, total, next, value) are still reachable.
+    vm.collect_garbage();
+    assert_eq!(vm.object_count(), natives + 2 + 6);
+}
+
+#[test]
+fn keeps_captured_values() {
+    let source_str = "\
+struct Box { value }
+function make_getter(value) do
+    let box = Box {}
+    box.value = value
+    function get() do
+        return box.value
+    end
+    return get
+end
+let getter = make_getter(42)
+let i = 0
+while i < 20000 do
+    let garbage = Box {}
+    garbage.value = make_getter(i)
+    i = i + 1
+end
+let result = getter()
+";
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+    assert_eq!(vm.get_global("result"), Some(Value::Number(42.0)));
+}
+
+#[test]
+fn keeps_map_entries() {
+    let source_str = "\
+struct Box { value }
+let map = {}
+for let i = 0, i < 100, i = i + 1 do
+    let box = Box {}
+    box.value = i
+    map[i] = box
+end
+let i = 0
+while i < 20000 do
+    let garbage = {\"a\": [i], \"b\": Box {}}
+    i = i + 1
+end
+let result = map[99].value
+";
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+    vm.collect_garbage();
+    assert_eq!(vm.get_global("result"), Some(Value::Number(99.0)));
+}
+
+#[test]
+fn keeps_native_results() {
+    // Every callback makes garbage, so collections happen while map and sort are still holding
+    // onto the lists they are building.
+    let source_str = "\
+struct Box { value }
+function boxed(x) do
+    for let i = 0, i < 200, i = i + 1 do
+        let garbage = [Box {}, str(i)]
+    end
+    let box = Box {}
+    box.value = x
+    return box
+end
+function less(a, b) do
+    let garbage = [str(a.value), Box {}]
+    return a.value < b.value
+end
+let numbers = []
+for let i = 0, i < 200, i = i + 1 do
+    push(numbers, (i * 7) % 200)
+end
+let sorted = sort(map(numbers, boxed), less)
+let total = 0
+for let i = 0, i < len(sorted), i = i + 1 do
+    if sorted[i].value == i then
+        total = total + 1
+    end
+end
+";
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+    assert_eq!(vm.get_global("total"), Some(Value::Number(200.0)));
+}
+
+#[test]
+fn prints_instance_cycles() {
+    let source_str = "\
+struct Node { next }
+let a = Node {}
+let b = Node {}
+a.next = b
+b.next = a
+let text = str(a)
+";
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+
+    let text = vm.get_global("text").unwrap();
+    assert_eq!(
+        vm.heap().as_str(&text),
+        Some("Node instance{\tnext:Node instance{\tnext:Node instance{...}, \t}, \t}")
+    );
+}
@@ -0,0 +1,78 @@
+use crate::{
+    compiler::values::Value,
+    vm::{InterpretError, VM},
+};
+
+#[test]
+fn forward_references() {
+    let source_str = "
+function get() do
+    return later * 2
+end
+function set(value) do
+    later = value
+end
+let later = 4
+let before = get()
+set(10)
+let after = get()
+";
+
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+    assert_eq!(vm.get_global("before"), Some(Value::Number(8.0)));
+    assert_eq!(vm.get_global("after"), Some(Value::Number(20.0)));
+    assert_eq!(vm.get_global("later"), Some(Value::Number(10.0)));
+}
+
+#[test]
+fn undefined_globals() {
+    let sources = [
+        "let x = missing\n",
+        "missing = 1\n",
+        "let y = x\nlet x = 1\n",
+        "function f() do\nreturn later\nend\nf()\nlet later = 1\n",
+    ];
+
+    for source_str in sources {
+        let mut vm = VM::new();
+        let res = vm.interpret(source_str.to_string());
+        assert!(
+            matches!(res, Err(InterpretError::RuntimeError)),
+            "{}",
+            source_str
+        );
+    }
+}
+
+#[test]
+fn slots_last_across_inputs() {
+    let mut vm = VM::new();
+    vm.set_global("start", Value::Number(1.0));
+    assert!(vm
+        .interpret_repl("function bump() do\ncount = count + start\nend\n".to_string())
+        .is_ok());
+    // `count` only got a slot so far, calling before it's defined still fails
+    assert!(vm.interpret_repl("bump()\n".to_string()).is_err());
+    assert!(vm.interpret_repl("let count = 0\n".to_string()).is_ok());
+    assert!(vm.interpret_repl("bump()\nbump()\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("count"), Some(Value::Number(2.0)));
+
+    vm.set_global("start", Value::Number(5.0));
+    assert!(vm.interpret_repl("bump()\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("count"), Some(Value::Number(7.0)));
+}
+
+#[test]
+fn natives_and_shadowing() {
+    let mut vm = VM::new();
+    assert!(vm
+        .interpret("let a = len([1, 2])\nlet len = 10\nlet b = len + 1\n".to_string())
+        .is_ok());
+    assert_eq!(vm.get_global("a"), Some(Value::Number(2.0)));
+    assert_eq!(vm.get_global("b"), Some(Value::Number(11.0)));
+
+    // Assigning to a native without `let` is still an error, natives aren't globals
+    let mut vm = VM::new();
+    assert!(vm.interpret("len = 1\n".to_string()).is_err());
+}
@@ -0,0 +1,75 @@
+use super::{assert_global, assert_runtime_error};
+use crate::{compiler::values::Value, vm::VM};
+
+#[test]
+fn index_list_literal() {
+    let source_str = "\
+let list = [1, 2 + 3, 4]
+let result = list[1] + list[2]
+let empty = len([])
+";
+    assert_global(source_str, "result", Value::Number(9.0));
+    assert_global(source_str, "empty", Value::Number(0.0));
+}
+
+#[test]
+fn set_index() {
+    let source_str = "\
+let list = [
+    1,
+    2,
+    3,
+]
+list[0] = list[0] + 10
+let nested = [[1, 2], [3, 4]]
+nested[1][0] = 30
+let result = list[0] + nested[1][0]
+";
+    assert_global(source_str, "result", Value::Number(41.0));
+}
+
+#[test]
+fn push_and_pop() {
+    let source_str = "\
+let list = []
+for let i = 0, i < 5, i = i + 1 do
+    push(list, i * 2)
+end
+let last = pop(list)
+let length = len(list)
+";
+    assert_global(source_str, "last", Value::Number(8.0));
+    assert_global(source_str, "length", Value::Number(4.0));
+}
+
+#[test]
+fn index_out_of_bounds() {
+    assert_runtime_error("let list = [1, 2]\nlet x = list[2]\n");
+    assert_runtime_error("let list = [1, 2]\nlet x = list[0 - 1]\n");
+    assert_runtime_error("let list = [1, 2]\nlist[5] = 1\n");
+}
+
+#[test]
+fn index_errors() {
+    assert_runtime_error("let list = [1, 2]\nlet x = list[0.5]\n");
+    assert_runtime_error("let x = 5\nlet y = x[0]\n");
+    assert_runtime_error("let x = pop([])\n");
+}
+
+#[test]
+fn print_list_containing_itself() {
+    let source_str = "\
+let list = [1]
+push(list, list)
+let text = str(list)
+let nested = str([list, list])
+";
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+
+    let text = vm.get_global("text").unwrap();
+    assert_eq!(vm.heap().as_str(&text), Some("[1, [...]]"));
+    // Seeing the same list twice side by side isn't a cycle
+    let nested = vm.get_global("nested").unwrap();
+    assert_eq!(vm.heap().as_str(&nested), Some("[[1, [...]], [1, [...]]]"));
+}
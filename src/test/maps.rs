@@ -0,0 +1,78 @@
+use super::{assert_global, assert_runtime_error};
+use crate::{compiler::values::Value, vm::VM};
+
+#[test]
+fn map_literal() {
+    let source_str = "\
+let map = {
+    \"one\": 1,
+    2: 20,
+}
+let result = map[\"one\"] + map[2] + map.one
+let length = len(map)
+let empty = len({})
+";
+    assert_global(source_str, "result", Value::Number(22.0));
+    assert_global(source_str, "length", Value::Number(2.0));
+    assert_global(source_str, "empty", Value::Number(0.0));
+}
+
+#[test]
+fn set_entries() {
+    let source_str = "\
+let map = {}
+map[\"a\"] = 1
+map.b = 2
+map[\"a\"] = map[\"a\"] + 10
+let key = \"b\"
+let result = map.a + map[key]
+let length = len(map)
+";
+    assert_global(source_str, "result", Value::Number(13.0));
+    assert_global(source_str, "length", Value::Number(2.0));
+}
+
+#[test]
+fn iterate_keys() {
+    let source_str = "\
+let map = {\"a\": 1, \"b\": 2, \"c\": 3}
+let ks = keys(map)
+let total = 0
+for let i = 0, i < len(ks), i = i + 1 do
+    total = total + map[ks[i]]
+end
+let vs = values(map)
+let last = vs[2]
+let found = has(map, \"b\")
+let missing = has(map, \"z\")
+";
+    assert_global(source_str, "total", Value::Number(6.0));
+    assert_global(source_str, "last", Value::Number(3.0));
+    assert_global(source_str, "found", Value::Boolean(true));
+    assert_global(source_str, "missing", Value::Boolean(false));
+}
+
+#[test]
+fn map_errors() {
+    assert_runtime_error("let map = {}\nlet x = map[\"a\"]\n");
+    assert_runtime_error("let map = {}\nmap[true] = 1\n");
+    assert_runtime_error("let map = {none: 1}\n");
+}
+
+#[test]
+fn print_map_containing_itself() {
+    let source_str = "\
+let map = {\"a\": 1}
+map[\"self\"] = map
+map[\"list\"] = [map]
+let text = str(map)
+";
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+
+    let text = vm.get_global("text").unwrap();
+    assert_eq!(
+        vm.heap().as_str(&text),
+        Some("{a: 1, self: {...}, list: [{...}]}")
+    );
+}
@@ -0,0 +1,130 @@
+use super::{assert_global, assert_runtime_error};
+use crate::{
+    compiler::{values::Value, Parser},
+    vm::{heap::Heap, VM},
+};
+
+const POINT: &str = "\
+struct Point {
+    x, y
+    function move(dx, dy) do
+        self.x = self.x + dx
+        self.y = self.y + dy
+        return self
+    end
+    function sum() do
+        return self.x + self.y
+    end
+}
+";
+
+#[test]
+fn invokes_methods() {
+    let source_str = format!(
+        "{}{}",
+        POINT,
+        "\
+let p = Point { 1, 2 }
+let result = p.move(1, 2).move(10, 0).sum()
+"
+    );
+    assert_global(&source_str, "result", Value::Number(16.0));
+}
+
+#[test]
+fn binds_receiver() {
+    let source_str = format!(
+        "{}{}",
+        POINT,
+        "\
+let p = Point { 1, 2 }
+let q = Point { 0, 0 }
+let sum = p.sum
+let move = q.move
+move(5, 5)
+let result = sum() * 100 + q.sum()
+"
+    );
+    assert_global(&source_str, "result", Value::Number(310.0));
+}
+
+#[test]
+fn captures_self() {
+    let source_str = "\
+struct Counter {
+    count
+    function incrementer() do
+        function inc() do
+            self.count = self.count + 1
+            return self.count
+        end
+        return inc
+    end
+}
+let c = Counter { 0 }
+let inc = c.incrementer()
+inc()
+inc()
+let result = c.count
+";
+    assert_global(source_str, "result", Value::Number(2.0));
+}
+
+#[test]
+fn calls_fields_and_methods() {
+    let source_str = "\
+struct Greeter {
+    greet
+    function hello() do
+        return 1
+    end
+}
+function field() do
+    return 2
+end
+let g = Greeter { field }
+let result = g.greet() * 10 + g.hello()
+let map = {\"call\": field}
+let from_map = map.call()
+";
+    assert_global(source_str, "result", Value::Number(21.0));
+    assert_global(source_str, "from_map", Value::Number(2.0));
+}
+
+#[test]
+fn keeps_bound_methods() {
+    let source_str = format!(
+        "{}{}",
+        POINT,
+        "\
+let p = Point { 1, 2 }
+let kept = p.sum
+let i = 0
+while i < 20000 do
+    let garbage = Point { i, i }.move
+    i = i + 1
+end
+let result = kept()
+"
+    );
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str).is_ok());
+    vm.collect_garbage();
+    assert_eq!(vm.get_global("result"), Some(Value::Number(3.0)));
+}
+
+#[test]
+fn method_errors() {
+    let source_str = format!("{}{}", POINT, "let p = Point { 1, 2 }\np.missing()\n");
+    assert_runtime_error(&source_str);
+
+    let source_str = format!("{}{}", POINT, "let p = Point { 1, 2 }\np.sum(1)\n");
+    assert_runtime_error(&source_str);
+
+    let mut heap = Heap::new();
+    let mut parser = Parser::new(&mut heap);
+    let diagnostics = parser
+        .compile("function f() do\nreturn self\nend\n".to_string())
+        .unwrap_err();
+    assert_eq!(diagnostics[0].message, "Can't use self outside of a method");
+}
@@ -1,2 +1,100 @@
 #[cfg(test)]
+use std::{cell::RefCell, io, io::Write, rc::Rc};
+
+#[cfg(test)]
+use crate::{
+    compiler::values::Value,
+    vm::{InterpretError, VM},
+};
+
+#[cfg(test)]
+mod api;
+#[cfg(test)]
+mod arithmetic;
+#[cfg(test)]
+mod bytecode;
+#[cfg(test)]
+mod chunk;
+#[cfg(test)]
+mod closures;
+#[cfg(test)]
+mod control_flow;
+#[cfg(test)]
+mod diagnostics;
+#[cfg(test)]
+mod disassembler;
+#[cfg(test)]
 mod expressions;
+#[cfg(test)]
+mod gc;
+#[cfg(test)]
+mod globals;
+#[cfg(test)]
+mod lists;
+#[cfg(test)]
+mod maps;
+#[cfg(test)]
+mod methods;
+#[cfg(test)]
+mod modules;
+#[cfg(test)]
+mod natives;
+#[cfg(test)]
+mod optimizer;
+#[cfg(test)]
+mod repl;
+#[cfg(test)]
+mod stack_overflow;
+#[cfg(test)]
+mod strings;
+#[cfg(test)]
+mod structs;
+
+// Runs the source in a new VM, then checks what the global ended up as
+#[cfg(test)]
+fn assert_global(source_str: &str, name: &str, expected_value: Value) {
+    let mut vm = VM::new();
+    assert!(
+        vm.interpret(source_str.to_string()).is_ok(),
+        "{}",
+        source_str
+    );
+    assert_eq!(vm.get_global(name), Some(expected_value), "{}", source_str);
+}
+
+// The source has to compile, then fail while running
+#[cfg(test)]
+fn assert_runtime_error(source_str: &str) {
+    let mut vm = VM::new();
+    assert!(
+        matches!(
+            vm.interpret(source_str.to_string()),
+            Err(InterpretError::RuntimeError)
+        ),
+        "{}",
+        source_str
+    );
+}
+
+// Collects what the VM writes so tests can look at it
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Output {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::Output;
+use crate::{
+    compiler::values::Value,
+    vm::{InterpretError, VM},
+};
+
+// A folder of source files that gets removed once the test is done
+struct Project {
+    dir: PathBuf,
+}
+
+impl Project {
+    fn new(name: &str, files: &[(&str, &str)]) -> Self {
+        let dir = std::env::temp_dir().join(format!("bite-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        for (path, source_str) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source_str).unwrap();
+        }
+
+        Project { dir }
+    }
+
+    fn run(&self, path: &str) -> (VM, Result<Value, InterpretError>) {
+        let path = self.dir.join(path);
+        let source_str = fs::read_to_string(&path).unwrap();
+
+        let mut vm = VM::new();
+        let res = vm.interpret_file(source_str, Path::new(&path));
+        (vm, res)
+    }
+
+    // Runs the file expecting it to fail, and hands back the error report
+    fn run_error(&self, path: &str) -> String {
+        let path = self.dir.join(path);
+        let source_str = fs::read_to_string(&path).unwrap();
+
+        let errors = Output::default();
+        let mut vm = VM::new();
+        vm.set_error_output(errors.clone());
+        let res = vm.interpret_file(source_str, Path::new(&path));
+        assert!(matches!(res, Err(InterpretError::RuntimeError)));
+        errors.text()
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn imports_globals() {
+    let project = Project::new(
+        "imports-globals",
+        &[
+            (
+                "main.bite",
+                "\
+import \"utils\"
+let x = 2
+let sum = utils.add(x, utils.x)
+let scoped = utils.get_x()
+let shown = str(utils)
+let kind = type(utils)
+",
+            ),
+            (
+                "utils.bite",
+                "\
+let x = 40
+function add(a, b) do
+    return a + b
+end
+function get_x() do
+    return x
+end
+",
+            ),
+        ],
+    );
+
+    let (vm, res) = project.run("main.bite");
+    assert!(res.is_ok());
+    assert_eq!(vm.get_global("sum"), Some(Value::Number(42.0)));
+    // The module sees its own x, not the one of the script
+    assert_eq!(vm.get_global("scoped"), Some(Value::Number(40.0)));
+    assert_eq!(vm.get_global("x"), Some(Value::Number(2.0)));
+
+    let shown = vm.get_global("shown").unwrap();
+    assert_eq!(vm.heap().display(&shown).to_string(), "<module utils>");
+    let kind = vm.get_global("kind").unwrap();
+    assert_eq!(vm.heap().display(&kind).to_string(), "module");
+}
+
+#[test]
+fn runs_each_module_once() {
+    let project = Project::new(
+        "runs-once",
+        &[
+            (
+                "main.bite",
+                "\
+import \"a\"
+import \"b\"
+import \"shared\"
+let same = a.shared == b.shared
+let count = len(shared.items)
+",
+            ),
+            ("a.bite", "import \"shared\"\npush(shared.items, 1)\n"),
+            ("b.txt", "import \"shared.bite\"\npush(shared.items, 2)\n"),
+            ("shared.bite", "let items = []\n"),
+        ],
+    );
+
+    let (vm, res) = project.run("main.bite");
+    assert!(res.is_ok());
+    assert_eq!(vm.get_global("same"), Some(Value::Boolean(true)));
+    assert_eq!(vm.get_global("count"), Some(Value::Number(2.0)));
+}
+
+#[test]
+fn resolves_relative_to_importer() {
+    let project = Project::new(
+        "relative",
+        &[
+            (
+                "main.bite",
+                "\
+import \"lib/shapes\" as s
+let p = s.Point(1, 2)
+let total = s.total(p)
+",
+            ),
+            (
+                "lib/shapes.bite",
+                "\
+import \"../lib/math\"
+struct Point { x, y }
+function total(p) do
+    return math.add(p.x, p.y)
+end
+",
+            ),
+            (
+                "lib/math.txt",
+                "function add(a, b) do\n    return a + b\nend\n",
+            ),
+        ],
+    );
+
+    let (vm, res) = project.run("main.bite");
+    assert!(res.is_ok());
+    assert_eq!(vm.get_global("total"), Some(Value::Number(3.0)));
+}
+
+#[test]
+fn import_errors() {
+    let project = Project::new(
+        "import-errors",
+        &[
+            ("cycle.bite", "import \"a\"\n"),
+            ("a.bite", "import \"b\"\n"),
+            ("b.bite", "import \"a\"\n"),
+            ("self.bite", "import \"self\"\n"),
+            ("missing.bite", "import \"nope\"\n"),
+            ("broken.bite", "import \"bad\"\n"),
+            ("bad.bite", "let = 1\n"),
+            (
+                "private.bite",
+                "import \"peek\"\nlet secret = 1\nlet x = peek.get()\n",
+            ),
+            ("peek.bite", "function get() do\n    return secret\nend\n"),
+            ("unknown.bite", "import \"a_value\"\nlet x = a_value.y\n"),
+            ("a_value.bite", "let x = 1\n"),
+            ("assign.bite", "import \"a_value\"\na_value.x = 2\n"),
+        ],
+    );
+
+    for path in [
+        "cycle.bite",
+        "self.bite",
+        "missing.bite",
+        "broken.bite",
+        "private.bite",
+        "unknown.bite",
+        "assign.bite",
+    ] {
+        let (_, res) = project.run(path);
+        assert!(matches!(res, Err(InterpretError::RuntimeError)), "{}", path);
+    }
+
+    // Each module gets its own line, so the trace spells out the cycle
+    let report = project.run_error("cycle.bite");
+    assert!(report.contains("| [line 1] in <script>\n"), "{}", report);
+    assert!(report.contains("| [line 1] in <module a>\n"), "{}", report);
+    assert!(report.contains("| [line 1] in <module b>: "), "{}", report);
+
+    // The name has to come from somewhere
+    assert!(crate::compile("import \"my-utils\"\n").is_err());
+    assert!(crate::compile("import \"my-utils\" as utils\n").is_ok());
+    assert!(crate::compile("import utils\n").is_err());
+}
+
+#[test]
+fn imports_compiled_files() {
+    let project = Project::new(
+        "compiled",
+        &[(
+            "main.bite",
+            "import \"solution\"\nlet answer = solution.solve(20)\n",
+        )],
+    );
+
+    // Only the compiled file is there, like a shipped reference solution
+    let bytes = VM::new()
+        .compile_bytecode("function solve(n) do\nreturn n * 2 + 2\nend\n".to_string())
+        .unwrap();
+    fs::write(project.dir.join("solution.bitec"), bytes).unwrap();
+
+    let (vm, res) = project.run("main.bite");
+    assert!(res.is_ok());
+    assert_eq!(vm.get_global("answer"), Some(Value::Number(42.0)));
+
+    fs::write(project.dir.join("solution.bitec"), b"\x7fBITE broken").unwrap();
+    let (_, res) = project.run("main.bite");
+    assert!(matches!(res, Err(InterpretError::RuntimeError)));
+}
@@ -0,0 +1,148 @@
+use super::{assert_global, assert_runtime_error};
+use crate::{compiler::values::Value, vm::VM};
+
+fn interpret(source_str: &str) -> VM {
+    let mut vm = VM::new();
+    assert!(
+        vm.interpret(source_str.to_string()).is_ok(),
+        "{}",
+        source_str
+    );
+    vm
+}
+
+fn assert_string(source_str: &str, name: &str, expected: &str) {
+    let vm = interpret(source_str);
+    let value = vm.get_global(name).unwrap();
+    assert_eq!(vm.heap().display(&value).to_string(), expected);
+}
+
+#[test]
+fn conversions() {
+    assert_string("let x = str(12) + \"!\"\n", "x", "12!");
+    assert_string("let x = str([1, \"a\"])\n", "x", "[1, a]");
+    assert_global("let x = num(\" 3.5 \")\n", "x", Value::Number(3.5));
+    assert_global("let x = num(\"abc\")\n", "x", Value::None);
+    assert_global("let x = num(4)\n", "x", Value::Number(4.0));
+    assert_runtime_error("num([])\n");
+
+    let source_str = "\
+struct P { x }
+function f() do
+end
+let types = [type(1), type(true), type(none), type(\"a\"), type(f), type(print), type(P), type(P {}), type([]), type({})]
+";
+    assert_string(
+        source_str,
+        "types",
+        "[number, boolean, none, string, function, function, struct, instance, list, map]",
+    );
+}
+
+#[test]
+fn math() {
+    assert_global("let x = floor(2.7) + ceil(2.1)\n", "x", Value::Number(5.0));
+    assert_global(
+        "let x = round(2.5) + round(-2.5)\n",
+        "x",
+        Value::Number(0.0),
+    );
+    assert_global("let x = sqrt(16) + abs(-3)\n", "x", Value::Number(7.0));
+    assert_global(
+        "let x = min(3, 1, 2) * 10 + max(3, 1, 2)\n",
+        "x",
+        Value::Number(13.0),
+    );
+    assert_global("let x = max(5)\n", "x", Value::Number(5.0));
+
+    assert_runtime_error("sqrt(-1)\n");
+    assert_runtime_error("floor(\"1\")\n");
+    assert_runtime_error("min()\n");
+    assert_runtime_error("max(1, \"2\")\n");
+    assert_runtime_error("abs(1, 2)\n");
+}
+
+#[test]
+fn seeded_random() {
+    let source_str = "\
+seed(42)
+let first = [random(), random(), random()]
+seed(42)
+let same = true
+let in_range = true
+for let i = 0, i < 3, i = i + 1 do
+    let value = random()
+    same = same and value == first[i]
+    in_range = in_range and value >= 0 and value < 1
+end
+seed(43)
+let different = random() != first[0]
+";
+    assert_global(source_str, "same", Value::Boolean(true));
+    assert_global(source_str, "in_range", Value::Boolean(true));
+    assert_global(source_str, "different", Value::Boolean(true));
+}
+
+#[test]
+fn string_helpers() {
+    assert_string("let x = upper(\"abc\") + lower(\"DEF\")\n", "x", "ABCdef");
+    assert_string("let x = split(\"a,b,,c\", \",\")\n", "x", "[a, b, , c]");
+    assert_string("let x = split(\"hé\", \"\")\n", "x", "[h, é]");
+    assert_global(
+        "let x = contains(\"hello\", \"ell\")\n",
+        "x",
+        Value::Boolean(true),
+    );
+    assert_global(
+        "let x = contains(\"hello\", \"z\")\n",
+        "x",
+        Value::Boolean(false),
+    );
+    assert_string("let x = substr(\"hello\", 1, 3)\n", "x", "ell");
+    assert_string("let x = substr(\"héllo\", 1, 100)\n", "x", "éllo");
+    assert_string("let x = substr(\"abc\", 3, 1)\n", "x", "");
+
+    assert_runtime_error("substr(\"abc\", 4, 1)\n");
+    assert_runtime_error("substr(\"abc\", -1, 1)\n");
+    assert_runtime_error("substr(\"abc\", 0.5, 1)\n");
+    assert_runtime_error("upper(1)\n");
+    assert_runtime_error("split(\"abc\")\n");
+}
+
+#[test]
+fn higher_order() {
+    let source_str = "\
+function double(x) do
+    return x * 2
+end
+function is_even(x) do
+    return x % 2 == 0
+end
+function longer(a, b) do
+    return len(a) > len(b)
+end
+let numbers = [3, 1, 2]
+let doubled = map(numbers, double)
+let evens = filter([1, 2, 3, 4], is_even)
+let sorted = sort(numbers)
+let words = sort([\"pear\", \"fig\", \"banana\", \"kiwi\"])
+let by_length = sort([\"pear\", \"fig\", \"banana\", \"kiwi\"], longer)
+let strs = map([1, true], str)
+";
+    assert_string(source_str, "doubled", "[6, 2, 4]");
+    assert_string(source_str, "evens", "[2, 4]");
+    assert_string(source_str, "sorted", "[1, 2, 3]");
+    // sort gives back a copy
+    assert_string(source_str, "numbers", "[3, 1, 2]");
+    assert_string(source_str, "words", "[banana, fig, kiwi, pear]");
+    // Equal lengths keep their order
+    assert_string(source_str, "by_length", "[banana, pear, kiwi, fig]");
+    assert_string(source_str, "strs", "[1, true]");
+
+    assert_runtime_error("map(1, str)\n");
+    assert_runtime_error("map([1], 2)\n");
+    assert_runtime_error("sort([1, \"a\"])\n");
+    assert_runtime_error("sort([1], str, 3)\n");
+    assert_runtime_error("function bad(x) do\n    return x / 0\nend\nmap([1], bad)\n");
+    assert_runtime_error("min()\n");
+}
@@ -0,0 +1,157 @@
+use crate::{
+    compiler::{chunk::OpCode, values::FunctionObj, Parser},
+    vm::{heap::Heap, VM},
+};
+
+fn compile(source_str: &str, optimize: bool) -> FunctionObj {
+    let mut heap = Heap::new();
+    let mut parser = Parser::new(&mut heap);
+    parser.set_optimize(optimize);
+    parser.compile(source_str.to_string()).unwrap()
+}
+
+fn codes(function: &FunctionObj) -> Vec<OpCode> {
+    function.chunk.instructions().map(|x| x.1).collect()
+}
+
+// Every global has to come out the same with and without the optimizer
+fn assert_same_results(source_str: &str, names: &[&str]) {
+    let mut optimized = VM::new();
+    assert!(optimized.interpret(source_str.to_string()).is_ok());
+
+    let mut plain = VM::new();
+    plain.set_optimize(false);
+    assert!(plain.interpret(source_str.to_string()).is_ok());
+
+    for name in names {
+        let left = optimized.get_global(name).unwrap();
+        let right = plain.get_global(name).unwrap();
+        assert_eq!(
+            optimized.heap().display(&left).to_string(),
+            plain.heap().display(&right).to_string(),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn folds_constants() {
+    let function = compile("let x = (-1 + 2) * 3 ^ 2 % 4\n", true);
+    let chunk = &function.chunk;
+    let code = codes(&function);
+
+    assert!(code
+        .iter()
+        .all(|x| !matches!(x, OpCode::OpNegate | OpCode::OpAdd | OpCode::OpMultiply)));
+    assert!(matches!(code[0], OpCode::OpConstant(_)));
+    if let OpCode::OpConstant(idx) = code[0] {
+        assert_eq!(chunk.values[idx].as_number(), Some(1.0));
+    }
+    // The constants that were folded away aren't kept
+    assert_eq!(chunk.values.len(), 1);
+
+    // Constants after the folded ones still find theirs
+    let function = compile(
+        "let x = 2 * 3
+let y = \"name\"
+let z = 5 - 1
+",
+        true,
+    );
+    let values = &function.chunk.values;
+    assert_eq!(values.len(), 3);
+    let numbers: Vec<Option<f64>> = values.iter().map(|x| x.as_number()).collect();
+    assert_eq!(numbers, vec![None, Some(6.0), Some(4.0)]);
+    assert_same_results(
+        "let x = 2 * 3\nlet y = \"name\"\nlet z = 5 - 1\n",
+        &["x", "y", "z"],
+    );
+
+    // Left for the VM, so it's still a runtime error
+    let function = compile("let x = 1 / 0\n", true);
+    assert!(codes(&function).contains(&OpCode::OpDivide));
+    let mut vm = VM::new();
+    assert!(vm.interpret("let x = 1 / 0\n".to_string()).is_err());
+}
+
+#[test]
+fn fuses_comparisons() {
+    let source_str = "let a = 1\nlet b = a != 2\nlet c = a >= 2\nlet d = a <= 2\nlet e = !true\n";
+    let function = compile(source_str, true);
+    let code = codes(&function);
+
+    assert!(code.contains(&OpCode::OpNotEqual));
+    assert!(code.contains(&OpCode::OpGreaterEqual));
+    assert!(code.contains(&OpCode::OpLessEqual));
+    assert!(!code.contains(&OpCode::OpNot));
+
+    assert_same_results(source_str, &["b", "c", "d", "e"]);
+}
+
+#[test]
+fn keeps_line_information() {
+    let source_str = "let a = 1\nlet b = a >= 2 + 3\n";
+    let plain = compile(source_str, false);
+    let optimized = compile(source_str, true);
+
+    let line_of = |function: &FunctionObj, code: OpCode| {
+        let chunk = &function.chunk;
+        let (offset, _) = chunk.instructions().find(|x| x.1 == code).unwrap();
+        (chunk.line_at(offset), chunk.columns_at(offset))
+    };
+    assert_eq!(
+        line_of(&plain, OpCode::OpLess),
+        line_of(&optimized, OpCode::OpGreaterEqual)
+    );
+
+    // The folded 2 + 3 points at the +
+    let instructions: Vec<(usize, OpCode)> = optimized.chunk.instructions().collect();
+    let (plus, code) = instructions[instructions.len() - 5];
+    assert!(matches!(code, OpCode::OpConstant(_)));
+    assert_eq!(optimized.chunk.line_at(plus), 2);
+    assert_eq!(optimized.chunk.columns_at(plus), 16..17);
+}
+
+#[test]
+fn keeps_behaviour() {
+    let source_str = "\
+let inf = 10 ^ 1000
+let nan = inf - inf
+let nan_checks = [nan < 1, nan <= 1, nan > 1, nan >= 1, nan == nan, nan != nan]
+let total = 0
+for let i = 0, i < 10, i = i + 1 do
+    if i == 2 then
+        continue
+    end
+    if i >= 8 - 1 then
+        if i > 7 then
+            break
+        else
+            total = total + 100
+        end
+    else
+        total = total + i * (1 + 1)
+    end
+end
+let picked = false or 2 - 1 and -(3)
+let nested = none
+if true then
+    if false then
+        nested = 1
+    else
+        if 1 <= 2 then
+            nested = 2
+        end
+    end
+else
+    nested = 3
+end
+";
+    assert_same_results(source_str, &["nan_checks", "total", "picked", "nested"]);
+
+    // The nested ifs end in jumps to jumps, those get shortened
+    let plain = compile(source_str, false);
+    let optimized = compile(source_str, true);
+    assert!(optimized.chunk.code.len() < plain.chunk.code.len());
+}
@@ -0,0 +1,52 @@
+use crate::{
+    compiler::values::Value,
+    vm::{InterpretError, VM},
+};
+
+#[test]
+fn globals_persist() {
+    let mut vm = VM::new();
+    assert!(vm.interpret_repl("let x = 5\n".to_string()).is_ok());
+    assert!(vm
+        .interpret_repl("function double(a) do\n".to_string())
+        .is_err());
+    assert!(vm
+        .interpret_repl("function double(a) do\nreturn a * 2\nend\n".to_string())
+        .is_ok());
+    assert!(vm.interpret_repl("let y = double(x)\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("y"), Some(Value::Number(10.0)));
+}
+
+#[test]
+fn detects_unfinished_input() {
+    let unfinished = [
+        "if true then\n",
+        "while false do\nlet a = 1\n",
+        "function f() do\n",
+        "let list = [1,\n",
+        "let map = {\n",
+    ];
+
+    for source_str in unfinished {
+        let mut vm = VM::new();
+        let res = vm.interpret_repl(source_str.to_string());
+        assert!(
+            matches!(res, Err(InterpretError::IncompleteInput)),
+            "{}",
+            source_str
+        );
+    }
+
+    let mut vm = VM::new();
+    let res = vm.interpret_repl("let x = )\n".to_string());
+    assert!(matches!(res, Err(InterpretError::CompileError(_))));
+}
+
+#[test]
+fn recovers_after_runtime_error() {
+    let mut vm = VM::new();
+    assert!(vm.interpret_repl("let x = 1\n".to_string()).is_ok());
+    assert!(vm.interpret_repl("x + missing\n".to_string()).is_err());
+    assert!(vm.interpret_repl("x = x + 1\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("x"), Some(Value::Number(2.0)));
+}
@@ -0,0 +1,148 @@
+use crate::{
+    compiler::{diagnostic::compact_calls, values::Value},
+    vm::{InterpretError, VM},
+};
+
+const COUNTDOWN: &str = "
+function down(n) do
+    if n == 0 then
+        return 0
+    end
+    return down(n - 1) + 1
+end
+";
+
+#[test]
+fn stops_runaway_recursion() {
+    let mut vm = VM::new();
+    let res = vm.interpret(
+        "function fib(n) do\nreturn fib(n - 1) + fib(n - 2)\nend\nfib(10)\n".to_string(),
+    );
+    assert!(matches!(res, Err(InterpretError::RuntimeError)));
+
+    // Mutual recursion counts the same
+    let source_str = "\
+function ping(n) do
+    return pong(n + 1)
+end
+function pong(n) do
+    return ping(n + 1)
+end
+ping(0)
+";
+    assert!(matches!(
+        vm.interpret(source_str.to_string()),
+        Err(InterpretError::RuntimeError)
+    ));
+
+    // The VM is fine afterwards
+    assert!(vm.interpret("let after = 1 + 2\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("after"), Some(Value::Number(3.0)));
+}
+
+#[test]
+fn frame_limit_can_change() {
+    let mut vm = VM::new();
+    vm.set_max_frames(10);
+    assert!(vm.interpret(COUNTDOWN.to_string()).is_ok());
+
+    // The script takes one frame, down(8) nests 9 calls
+    assert!(vm.interpret("let deep = down(8)\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("deep"), Some(Value::Number(8.0)));
+    assert!(vm.interpret("let deeper = down(9)\n".to_string()).is_err());
+
+    // Deeper than the default is fine when allowed
+    let mut vm = VM::new();
+    vm.set_max_frames(5000);
+    assert!(vm
+        .interpret(format!("{}let deep = down(4000)\n", COUNTDOWN))
+        .is_ok());
+    assert_eq!(vm.get_global("deep"), Some(Value::Number(4000.0)));
+}
+
+#[test]
+fn stack_limit_can_change() {
+    let mut vm = VM::new();
+    vm.set_max_frames(usize::MAX);
+    vm.set_max_stack(200);
+    assert!(vm
+        .interpret(format!("{}let shallow = down(20)\n", COUNTDOWN))
+        .is_ok());
+    assert!(vm.interpret("let deep = down(500)\n".to_string()).is_err());
+}
+
+#[test]
+fn stops_recursion_through_natives() {
+    let mut vm = VM::new();
+    let source_str = "\
+function nest(x) do
+    return map([x], nest)
+end
+nest(1)
+";
+    assert!(matches!(
+        vm.interpret(source_str.to_string()),
+        Err(InterpretError::RuntimeError)
+    ));
+
+    // Nesting a few levels still works
+    let source_str = "\
+function nest(x) do
+    if x == 0 then
+        return 0
+    end
+    return map([x - 1], nest)[0] + 1
+end
+let depth = nest(30)
+";
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+    assert_eq!(vm.get_global("depth"), Some(Value::Number(30.0)));
+}
+
+#[test]
+fn zero_limits_still_run_the_script() {
+    let mut vm = VM::new();
+    vm.set_max_frames(0);
+    vm.set_max_stack(0);
+    assert!(vm.interpret("let result = 1 + 2\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("result"), Some(Value::Number(3.0)));
+
+    // Any call is one frame too many
+    assert!(matches!(
+        vm.interpret(format!("{}down(0)\n", COUNTDOWN)),
+        Err(InterpretError::RuntimeError)
+    ));
+}
+
+#[test]
+fn compact_repeating_calls() {
+    let calls = |lines: &[&str]| -> Vec<String> { lines.iter().map(|x| x.to_string()).collect() };
+
+    // Recursion of one function
+    let trace = calls(&["script", "fib", "fib", "fib", "fib"]);
+    assert_eq!(
+        compact_calls(&trace),
+        calls(&["script", "fib", "|   ... 3 more times"])
+    );
+
+    // Mutual recursion repeats the pair, even when it stops half way through
+    let mut trace = calls(&["script"]);
+    for _ in 0..500 {
+        trace.extend(calls(&["ping", "pong"]));
+    }
+    trace.push("ping".to_string());
+    assert_eq!(
+        compact_calls(&trace),
+        calls(&[
+            "script",
+            "ping",
+            "pong",
+            "|   ... the last 2 calls 499 more times",
+            "ping"
+        ])
+    );
+
+    // Nothing repeats
+    let trace = calls(&["script", "a", "b", "a"]);
+    assert_eq!(compact_calls(&trace), trace);
+}
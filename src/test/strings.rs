@@ -0,0 +1,154 @@
+use crate::{
+    compiler::values::Value,
+    vm::{InterpretError, VM},
+};
+
+fn interpret(source_str: &str) -> VM {
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+    vm
+}
+
+#[test]
+fn concatenation_makes_new_string() {
+    let vm = interpret("let a = \"x\"\nlet b = a + \"y\"\nlet c = a\n");
+
+    let a = vm.get_global("a").unwrap();
+    assert_eq!(vm.heap().as_str(&a), Some("x"));
+    assert_eq!(vm.heap().as_str(&vm.get_global("b").unwrap()), Some("xy"));
+    assert_eq!(vm.get_global("c"), Some(a));
+}
+
+#[test]
+fn equal_strings_are_interned() {
+    let source_str = "\
+let a = \"ab\"
+let b = \"a\" + \"b\"
+let same = a == b
+let different = a == \"ba\"
+";
+    let vm = interpret(source_str);
+    assert_eq!(vm.get_global("same"), Some(Value::Boolean(true)));
+    assert_eq!(vm.get_global("different"), Some(Value::Boolean(false)));
+    assert_eq!(vm.get_global("a"), vm.get_global("b"));
+}
+
+#[test]
+fn concatenated_map_keys() {
+    let source_str = "\
+let map = {\"key\": 1}
+let name = \"k\" + \"ey\"
+map[name] = map[name] + 1
+let result = map.key
+";
+    let vm = interpret(source_str);
+    assert_eq!(vm.get_global("result"), Some(Value::Number(2.0)));
+}
+
+#[test]
+fn strings_survive_collection() {
+    let source_str = "\
+struct Box { value }
+let box = Box {}
+box.value = \"kept\" + \"!\"
+let i = 0
+while i < 20000 do
+    let garbage = \"a\" + \"b\"
+    i = i + 1
+end
+let result = box.value == \"kept!\"
+";
+    let mut vm = interpret(source_str);
+    vm.collect_garbage();
+    assert_eq!(vm.get_global("result"), Some(Value::Boolean(true)));
+
+    let value = vm.new_string("kept!");
+    assert!(vm.interpret("let again = box.value\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("again"), Some(value));
+}
+
+fn assert_string(source_str: &str, name: &str, expected: &str) {
+    let vm = interpret(source_str);
+    let value = vm.get_global(name).unwrap();
+    assert_eq!(vm.heap().as_str(&value), Some(expected));
+}
+
+#[test]
+fn escape_sequences() {
+    assert_string(
+        r#"let s = "tab\tline\nquote\" slash\\ brace\{\}"
+"#,
+        "s",
+        "tab\tline\nquote\" slash\\ brace{}",
+    );
+
+    let mut vm = VM::new();
+    match vm.interpret("let s = \"bad \\q\"\n".to_string()) {
+        Err(InterpretError::CompileError(diagnostics)) => {
+            assert_eq!(diagnostics[0].message, "Unknown escape sequence '\\q'");
+            assert_eq!(diagnostics[0].column, 9);
+        }
+        _ => panic!("Expected a compile error"),
+    }
+}
+
+#[test]
+fn interpolation() {
+    let source_str = r#"
+let name = "Kevin"
+let age = 20
+let s = "Hello {name}, you are {age}"
+let math = "{age + 1} {age * 2}{"!"}"
+let values = "{[1, none]} {{"a": true}["a"]} {"nested {name}"}"
+let plain = "{name}"
+"#;
+    assert_string(source_str, "s", "Hello Kevin, you are 20");
+    assert_string(source_str, "math", "21 40!");
+    assert_string(source_str, "values", "[1, none] true nested Kevin");
+    assert_string(source_str, "plain", "Kevin");
+
+    let vm = interpret(source_str);
+    assert_eq!(vm.get_global("plain"), vm.get_global("name"));
+}
+
+#[test]
+fn interpolated_struct() {
+    // Making the instance peeks past its braces, which are inside the string's `{}`
+    let source_str = r#"
+struct P { x }
+let x = 1
+let s = "p={P { x }} done"
+let named = "{P { x = 2 }.x}"
+"#;
+    assert_string(source_str, "s", "p=P instance{\tx:1, \t} done");
+    assert_string(source_str, "named", "2");
+}
+
+#[test]
+fn interpolation_errors() {
+    let mut vm = VM::new();
+    assert!(matches!(
+        vm.interpret("let s = \"{1 2}\"\n".to_string()),
+        Err(InterpretError::CompileError(_))
+    ));
+
+    let mut vm = VM::new();
+    assert!(matches!(
+        vm.interpret("let s = \"abc {1\n".to_string()),
+        Err(InterpretError::CompileError(_))
+    ));
+
+    // Points at the `{` that was never closed, not past the end of the source
+    let mut vm = VM::new();
+    match vm.interpret("print(\"{\")\n".to_string()) {
+        Err(InterpretError::CompileError(diagnostics)) => {
+            assert_eq!(diagnostics.len(), 1);
+            assert_eq!(
+                diagnostics[0].message,
+                "Unterminated interpolation, expected '}'"
+            );
+            assert_eq!((diagnostics[0].line, diagnostics[0].column), (1, 8));
+        }
+        _ => panic!("Expected a compile error"),
+    }
+}
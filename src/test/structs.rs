@@ -0,0 +1,136 @@
+use super::{assert_global, assert_runtime_error};
+use crate::{
+    compiler::{diagnostic::Diagnostic, values::Value, Parser},
+    vm::{heap::Heap, InterpretError, VM},
+};
+
+fn compile_errors(source_str: &str) -> Vec<Diagnostic> {
+    let mut heap = Heap::new();
+    let mut parser = Parser::new(&mut heap);
+    match parser.compile(source_str.to_string()) {
+        Ok(_) => panic!("Expected {:?} to fail compiling", source_str),
+        Err(diagnostics) => diagnostics,
+    }
+}
+
+#[test]
+fn positional_fields() {
+    let source_str = "\
+struct Point { x, y }
+let p = Point { 1, 2 }
+let result = p.x * 10 + p.y
+";
+    assert_global(source_str, "result", Value::Number(12.0));
+}
+
+#[test]
+fn named_fields() {
+    let source_str = "\
+struct Point { x, y }
+let p = Point {
+    y = 2,
+    x = 1,
+}
+let q = Point { x = p.y, y = p.x }
+let result = p.x * 10 + p.y + q.x * 1000
+";
+    assert_global(source_str, "result", Value::Number(2012.0));
+}
+
+#[test]
+fn empty_initializer() {
+    let source_str = "\
+struct Point { x, y }
+let p = Point {}
+let result = p.x == none and p.y == none
+";
+    assert_global(source_str, "result", Value::Boolean(true));
+}
+
+#[test]
+fn checks_fields_when_compiling() {
+    let diagnostics = compile_errors("struct Point { x, y }\nlet p = Point { x = 1, z = 2 }\n");
+    assert_eq!(diagnostics[0].message, "Unknown field z");
+    assert_eq!(diagnostics[0].line, 2);
+
+    let diagnostics = compile_errors("struct Point { x, y }\nlet p = Point { x = 1 }\n");
+    assert_eq!(diagnostics[0].message, "Missing field y");
+
+    let diagnostics = compile_errors("struct Point { x, y }\nlet p = Point { 1, 2, 3 }\n");
+    assert_eq!(diagnostics[0].message, "Expected 2 fields but got 3");
+
+    let diagnostics = compile_errors("struct Point { x, y }\nlet p = Point { x = 1, x = 2 }\n");
+    assert_eq!(diagnostics[0].message, "Field x is already given");
+
+    let diagnostics = compile_errors("struct Point { x, x }\n");
+    assert_eq!(diagnostics[0].message, "Field x is already declared");
+}
+
+#[test]
+fn checks_fields_when_running() {
+    // The Struct is only known at runtime here
+    assert_runtime_error(
+        "\
+struct Point { x, y }
+function make(kind) do
+    return kind { x = 1, z = 2 }
+end
+make(Point)
+",
+    );
+    assert_runtime_error(
+        "\
+struct Point { x, y }
+function make(kind) do
+    return kind { 1 }
+end
+make(Point)
+",
+    );
+    assert_runtime_error("let p = 5\nlet q = p { x = 1 }\n");
+
+    // A local with the same name hides the global Struct
+    let source_str = "\
+struct Point { x, y }
+struct Single { value }
+function make() do
+    let Point = Single
+    return Point { value = 7 }
+end
+let result = make().value
+";
+    assert_global(source_str, "result", Value::Number(7.0));
+}
+
+#[test]
+fn repl_structs() {
+    let mut vm = VM::new();
+    assert!(vm
+        .interpret_repl("struct Point { x, y }\n".to_string())
+        .is_ok());
+    assert!(vm
+        .interpret_repl("let p = Point { y = 3, x = 4 }\n".to_string())
+        .is_ok());
+    assert!(matches!(
+        vm.interpret_repl("let q = Point { y = 3 }\n".to_string()),
+        Err(InterpretError::RuntimeError)
+    ));
+    assert!(vm.interpret_repl("let result = p.x\n".to_string()).is_ok());
+    assert_eq!(vm.get_global("result"), Some(Value::Number(4.0)));
+}
+
+#[test]
+fn display_in_declared_order() {
+    let source_str = "\
+struct Point { x, y, z, w }
+let text = str(Point { w = 4, z = 3, y = 2, x = 1 })
+";
+    let mut vm = VM::new();
+    assert!(vm.interpret(source_str.to_string()).is_ok());
+
+    let text = vm.get_global("text").unwrap();
+    assert_eq!(
+        vm.heap().as_str(&text),
+        Some("Point instance{\tx:1, y:2, z:3, w:4, \t}")
+    );
+}
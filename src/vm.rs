@@ -1,27 +1,64 @@
-use std::{borrow::Borrow, cell::RefCell, collections::HashMap, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+};
 
+pub mod context;
 mod gc;
+pub mod heap;
+
+use context::{NativeContext, NativeError};
+use gc::GC_INITIAL_THRESHOLD;
+use heap::Heap;
 
 use crate::{
     compiler::{
+        bytecode::{self, BytecodeError},
         chunk::OpCode,
-        values::{FunctionObj, NativeFn, Obj, StrObj, Structs, StructsInstance, Value},
+        diagnostic::{compact_calls, render_snippet, Diagnostic},
+        values::{
+            BoundMethodObj, ClosureObj, FunctionObj, GlobalSlots, ListObj, MapKey, MapObj,
+            ModuleObj, NativeFn, NativeFnPtr, Obj, ObjData, Structs, StructsInstance,
+            UpvalueLocation, UpvalueObj, Value,
+        },
         Parser,
     },
-    debug::disaseemble_code,
-    native_functions::get_all_natives,
+    debug::{disaseemble_code, disassemble_function},
+    native_functions::{get_all_natives, Arity},
 };
 
-pub static DEBUG: bool = false;
+// How deep calls can nest before it's a stack overflow, runaway recursion stops here instead of
+// using up all the memory
+pub const DEFAULT_MAX_FRAMES: usize = 1000;
+// How many values the stack can hold, checked on every call
+pub const DEFAULT_MAX_STACK: usize = 1 << 20;
+// Natives calling back into Bite, like map, run the VM again on Rust's own stack, which runs out
+// well before DEFAULT_MAX_FRAMES. So those calls get a much lower limit that can't be changed.
+const MAX_NATIVE_DEPTH: usize = 64;
 
+#[derive(Debug)]
 pub enum InterpretError {
-    CompileError,
+    CompileError(Vec<Diagnostic>),
     RuntimeError,
+    // Only from interpret_repl, the source ended in the middle of something
+    IncompleteInput,
+    // A compiled file couldn't be saved or loaded
+    BytecodeError(BytecodeError),
 }
 
 pub struct CallFrame {
-    // Rc cause 2 places need this value, but non modify it
-    function: Rc<RefCell<FunctionObj>>,
+    // Heap index of the closure being run
+    closure: usize,
+    // Heap index of the closure's function, kept here so fetching opcodes doesn't go through the
+    // closure every time.
+    function: usize,
+    // Heap index of the closure's module, for the same reason
+    module: usize,
+    // Offset of the next opcode in the function's chunk, in bytes
     ic: usize,
     // This is just an index
     slots: usize,
@@ -34,19 +71,81 @@ pub struct VM {
     stack: Vec<Value>,
 
     //stack_cap: usize,
-    globals: HashMap<String, Value>,
+    // Heap index of the ModuleObj that holds the globals of the script and the REPL
+    main_module: usize,
+    // Natives, every module can see them. Keyed by the heap index of the interned name
+    builtins: HashMap<usize, Value>,
+    // Modules that finished loading, keyed by their canonical path so each file only runs once
+    modules: HashMap<PathBuf, usize>,
+    // Canonical paths of the modules still running, importing one of them again is a cycle
+    importing: Vec<PathBuf>,
+
+    // Heap indexes of upvalues still pointing into the stack, sorted by their stack slot.
+    open_upvalues: Vec<usize>,
+
+    // Owns every object. Values only hold indexes into it.
+    heap: Heap,
+    next_gc: usize,
+
+    // Heap indexes of objects natives made or got back while running, see NativeContext
+    native_roots: Vec<usize>,
+
+    // Whether the code gets optimized when it's compiled
+    optimize: bool,
+    // Prints the stack and every opcode before it runs, and when collections happen
+    trace: bool,
+
+    max_frames: usize,
+    max_stack: usize,
+    // How many NativeContext::call are running inside each other
+    native_depth: usize,
+
+    // Where print and the REPL write, and where errors are reported. Stdout and stderr unless
+    // they're changed.
+    output: Box<dyn Write>,
+    // A RefCell so errors can be reported from anywhere that can look at the VM
+    error_output: RefCell<Box<dyn Write>>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
+        let mut heap = Heap::new();
+        let main_module = heap.alloc(ObjData::Module(ModuleObj::new("main".to_string(), None)));
+
         let mut vm = VM {
             frame: Vec::new(),
             frame_count: 0,
 
-            globals: HashMap::new(),
+            main_module,
+            builtins: HashMap::new(),
+            modules: HashMap::new(),
+            importing: Vec::new(),
 
             //stack_cap: 0,
             stack: Vec::new(),
+
+            open_upvalues: Vec::new(),
+
+            heap,
+            next_gc: GC_INITIAL_THRESHOLD,
+
+            native_roots: Vec::new(),
+
+            optimize: true,
+            trace: false,
+
+            max_frames: DEFAULT_MAX_FRAMES,
+            max_stack: DEFAULT_MAX_STACK,
+            native_depth: 0,
+
+            output: Box::new(io::stdout()),
+            error_output: RefCell::new(Box::new(io::stderr())),
         };
 
         vm.insert_natives(get_all_natives());
@@ -55,21 +154,234 @@ impl VM {
     }
 
     pub fn interpret(&mut self, source: String) -> Result<Value, InterpretError> {
-        let mut parser = Parser::new();
+        let function = self.compile_in(self.main_module, source, false)?;
+        self.run_script(function)
+    }
+
+    // Same as interpret, but the script knows it came from `path`, so its imports are looked up
+    // next to it.
+    pub fn interpret_file(&mut self, source: String, path: &Path) -> Result<Value, InterpretError> {
+        self.enter_file(path);
+        let res = self.interpret(source);
+        self.importing.clear();
+
+        res
+    }
+
+    // Runs a script saved by compile_bytecode, in the same module as `interpret`.
+    pub fn interpret_bytecode(&mut self, bytes: &[u8]) -> Result<Value, InterpretError> {
+        let function = self.load_in(self.main_module, bytes)?;
+        self.run_script(function)
+    }
+
+    // Same as interpret_file, for a script saved by compile_bytecode.
+    pub fn interpret_bytecode_file(
+        &mut self,
+        bytes: &[u8],
+        path: &Path,
+    ) -> Result<Value, InterpretError> {
+        self.enter_file(path);
+        let res = self.interpret_bytecode(bytes);
+        self.importing.clear();
+
+        res
+    }
+
+    // Compiles the source into bytes interpret_bytecode can run later, without running it.
+    // Nothing about the VM's own globals ends up in them.
+    pub fn compile_bytecode(&mut self, source: String) -> Result<Vec<u8>, InterpretError> {
+        let mut parser = Parser::new(&mut self.heap);
+        parser.set_optimize(self.optimize);
+
+        let function = parser.compile(source);
+        let global_slots = parser.take_global_slots();
+        let function = function.map_err(InterpretError::CompileError)?;
+
+        bytecode::save(&self.heap, &function, &global_slots).map_err(InterpretError::BytecodeError)
+    }
+
+    // The opcodes the source compiles to, with every function declared in it. Nothing gets run.
+    pub fn disassemble(&mut self, source: String) -> Result<String, InterpretError> {
+        let mut parser = Parser::new(&mut self.heap);
+        parser.set_optimize(self.optimize);
+
+        let function = parser.compile(source);
+        let global_slots = parser.take_global_slots();
+        let function = function.map_err(InterpretError::CompileError)?;
+
+        Ok(disassemble_function(
+            &self.heap,
+            &function,
+            &global_slots.names,
+        ))
+    }
+
+    // Same as disassemble, for a file saved by compile_bytecode.
+    pub fn disassemble_bytecode(&mut self, bytes: &[u8]) -> Result<String, InterpretError> {
+        let mut global_slots = GlobalSlots::default();
+        let function = bytecode::load(&mut self.heap, &mut global_slots, bytes)
+            .map_err(InterpretError::BytecodeError)?;
+
+        Ok(disassemble_function(
+            &self.heap,
+            &function,
+            &global_slots.names,
+        ))
+    }
+
+    // The main module gets the file's name and path, so imports are found next to it.
+    fn enter_file(&mut self, path: &Path) {
+        let module = self.heap.module_mut(self.main_module);
+        module.name = module_name(path);
+        module.path = Some(path.to_path_buf());
+
+        // Importing the script from one of its own imports is a cycle too
+        self.importing.push(canonical_path(path));
+    }
+
+    // Globals stay around between calls, so each input builds on the ones before it.
+    pub fn interpret_repl(&mut self, source: String) -> Result<Value, InterpretError> {
+        let function = self.compile_in(self.main_module, source, true)?;
+        self.run_script(function)
+    }
+
+    // Compiles code that runs in `module`. Its globals get the slots the module already gave out,
+    // so code compiled later, like the next REPL input, still finds them.
+    fn compile_in(
+        &mut self,
+        module: usize,
+        source: String,
+        repl: bool,
+    ) -> Result<FunctionObj, InterpretError> {
+        let global_slots = mem::take(&mut self.heap.module_mut(module).slots);
+
+        let mut parser = match repl {
+            true => Parser::new_repl(&mut self.heap),
+            false => Parser::new(&mut self.heap),
+        };
+        parser.set_optimize(self.optimize);
+        parser.set_global_slots(global_slots);
+
         let parser_res = parser.compile(source);
+        let is_incomplete = parser.is_incomplete();
+        let global_slots = parser.take_global_slots();
+
+        self.heap.module_mut(module).set_slots(global_slots);
+
+        if repl && is_incomplete {
+            return Err(InterpretError::IncompleteInput);
+        }
+        parser_res.map_err(InterpretError::CompileError)
+    }
 
-        if let Some(function_obj) = parser_res {
-            let function = Rc::new(RefCell::new(function_obj));
+    // Same as compile_in for a compiled file, its globals are given slots of the module.
+    fn load_in(&mut self, module: usize, bytes: &[u8]) -> Result<FunctionObj, InterpretError> {
+        let mut global_slots = mem::take(&mut self.heap.module_mut(module).slots);
+        let res = bytecode::load(&mut self.heap, &mut global_slots, bytes);
+        self.heap.module_mut(module).set_slots(global_slots);
 
-            self.stack
-                .push(Value::Obj(Obj::Function(Rc::clone(&function))));
+        res.map_err(InterpretError::BytecodeError)
+    }
+
+    fn run_script(&mut self, function_obj: FunctionObj) -> Result<Value, InterpretError> {
+        let function = self.heap.alloc(ObjData::Function(function_obj));
+
+        // Keeps the function reachable in case allocating the closure collects.
+        self.push_stack(Value::Obj(Obj::Function(function)));
+        let closure = self.allocate_obj(ObjData::Closure(ClosureObj::new(
+            function,
+            Vec::new(),
+            self.main_module,
+        )));
+        self.pop_stack();
+
+        self.push_stack(Value::Obj(Obj::Closure(closure)));
 
-            self.add_call_frame(function, 0);
-        } else {
-            return Err(InterpretError::CompileError);
+        self.add_call_frame(closure, 0)?;
+
+        let res = self.run_until(0);
+        if res.is_err() {
+            self.reset_stack();
         }
 
-        Ok(self.run()?)
+        res
+    }
+
+    // Throws away whatever the failed script left behind, so the VM can run again.
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frame.clear();
+        self.frame_count = 0;
+        self.open_upvalues.clear();
+        self.importing.clear();
+    }
+
+    // Globals of the script, natives are found too unless the script shadows them.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let name = self.heap.find_string(name)?;
+        self.lookup_global(self.main_module, name)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = self.heap.intern(name);
+        let main_module = self.main_module;
+        self.heap.module_mut(main_module).define(name, value);
+    }
+
+    // Makes a Rust function callable from Bite under `name`.
+    pub fn register_native(&mut self, name: &str, arity: Arity, native_fn: NativeFnPtr) {
+        self.define_native_fn(NativeFn {
+            name: name.to_string(),
+            arity,
+            native_fn,
+        });
+    }
+
+    // Nothing points at the string yet, so store it in a global or pass it to Bite before running
+    // more code, otherwise it can get collected.
+    pub fn new_string(&mut self, string: &str) -> Value {
+        let string = self.heap.intern(string);
+        Value::Obj(Obj::String(string))
+    }
+
+    // Code compiled from here on skips the optimizer when this is false
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+
+    // Calls nested deeper than this are a stack overflow. The script itself counts as one, so
+    // there is always room for it.
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames.max(1);
+    }
+
+    // A call made while the stack holds more values than this is a stack overflow.
+    pub fn set_max_stack(&mut self, max_stack: usize) {
+        self.max_stack = max_stack.max(1);
+    }
+
+    // Where print writes to, stdout by default
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    // Where runtime errors are reported, stderr by default. Compile errors are given back to
+    // the caller instead.
+    pub fn set_error_output(&mut self, error_output: impl Write + 'static) {
+        self.error_output = RefCell::new(Box::new(error_output));
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    #[cfg(test)]
+    pub fn object_count(&self) -> usize {
+        self.heap.live_objects().len()
     }
 
     fn get_mut_frame(&mut self) -> &mut CallFrame {
@@ -80,21 +392,29 @@ impl VM {
         &self.frame[self.frame_count - 1]
     }
 
-    fn run(&mut self) -> Result<Value, InterpretError> {
-        if DEBUG {
-            println!("\n=== VM ===");
+    #[inline]
+    fn read_constant(&self, idx: usize) -> Value {
+        self.heap
+            .function(self.get_frame().function)
+            .chunk
+            .get_const(idx)
+    }
+
+    // Names are interned strings, so their heap index is what globals and fields are keyed by.
+    #[inline]
+    fn read_name_constant(&self, idx: usize) -> usize {
+        match self.read_constant(idx) {
+            Value::Obj(Obj::String(str_idx)) => str_idx,
+            _ => unreachable!(),
         }
+    }
 
+    // Runs until the frame count drops back to `base_frame`. The script runs with 0, natives
+    // calling back into Bite run with the frames that were there before the call.
+    fn run_until(&mut self, base_frame: usize) -> Result<Value, InterpretError> {
         loop {
-            if DEBUG {
-                print!("Stack:       [");
-                for i in 0..self.stack.len() - 1 {
-                    print!("{}, ", self.stack[i]);
-                }
-                print!("{}", self.stack[self.stack.len() - 1]);
-                println!("]");
-                let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                disaseemble_code(&func.borrow().chunk, self.get_frame().ic);
+            if self.trace {
+                self.trace_instruction();
             }
 
             match self.get_op_code() {
@@ -103,23 +423,17 @@ impl VM {
                         OpCode::OpReturn => {
                             let value = self.pop_stack();
 
-                            match value {
-                                // Doesn't Support closures
-                                Value::Obj(Obj::Function(_)) => {
-                                    self.runtime_error("Can't return functions");
-                                    return Err(InterpretError::RuntimeError);
-                                }
-                                _ => {}
-                            };
+                            // Anything captured from this frame has to outlive it.
+                            self.close_upvalues(self.get_frame().slots);
 
                             if self.frame_count == 1 {
                                 self.stack.clear();
+                                // The script is done, so its closure shouldn't keep anything alive.
+                                self.frame.pop();
+                                self.frame_count -= 1;
                                 //while self.stack.len() > self.get_frame().slots {
                                 //    self.pop_stack();
                                 //}
-                                if DEBUG {
-                                    println!("Stack:       {:?}", self.stack);
-                                }
                                 return Ok(Value::None);
                             }
 
@@ -130,61 +444,23 @@ impl VM {
                             self.frame.pop();
                             self.frame_count -= 1;
                             self.push_stack(value);
+
+                            if self.frame_count == base_frame {
+                                return Ok(value);
+                            }
                         }
                         OpCode::OpPop => {
                             self.pop_stack();
                         }
-                        OpCode::OpCall(args_count) => {
-                            match self.peek_stack(args_count as usize) {
-                                Value::Obj(Obj::Function(func)) => {
-                                    self.add_call_frame(func, args_count as usize);
-                                }
-                                Value::Obj(Obj::NativeFn(func)) => {
-                                    let start = self.stack.len() - args_count as usize;
-
-                                    let native_func_obj: &RefCell<NativeFn> = func.borrow();
-                                    let func = native_func_obj.borrow();
-                                    // Check this
-                                    if &func.name != "print" && func.arity != args_count {
-                                        self.runtime_error(
-                                            format!(
-                                                "{} Expected {} arguments but got {}",
-                                                func.name, func.arity, args_count
-                                            )
-                                            .as_str(),
-                                        );
-
-                                        return Err(InterpretError::RuntimeError);
-                                    }
-
-                                    let args: Vec<Value> = self.stack.drain(start..).collect();
-
-                                    let value_res = (func.native_fn)(args_count as usize, &args);
-
-                                    // Pops the function out from the stack
-                                    self.pop_stack();
-
-                                    match value_res {
-                                        Ok(value) => self.push_stack(value),
-                                        Err(msg) => {
-                                            self.runtime_error(msg);
-                                            return Err(InterpretError::RuntimeError);
-                                        }
-                                    }
-                                }
-                                Value::Obj(Obj::Structs(struct_obj)) => {
-                                    let length = self.stack.len();
-                                    self.stack[length - (args_count as usize) - 1] =
-                                        Value::Obj(Obj::Instance(Rc::new(RefCell::new(
-                                            StructsInstance::new(struct_obj),
-                                        ))));
-                                }
-                                _ => {
-                                    self.runtime_error("Can only call Functions");
-                                    return Err(InterpretError::RuntimeError);
-                                }
+                        OpCode::OpPrint => {
+                            let value = self.pop_stack();
+                            if value != Value::None {
+                                let _ = writeln!(self.output, "{}", self.heap.display(&value));
                             }
                         }
+                        OpCode::OpCall(args_count) => {
+                            self.call_value(args_count)?;
+                        }
 
                         OpCode::OpJumpIfFalse(jump) => {
                             if self.is_falsey(self.peek_stack(0)) {
@@ -202,63 +478,40 @@ impl VM {
                         }
 
                         OpCode::OpConstant(idx) => {
-                            let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                            let const_val = func.borrow_mut().chunk.get_const(idx);
+                            let const_val = self.read_constant(idx);
                             self.push_stack(const_val);
                         }
-                        OpCode::OpDefineGlobal(idx) => {
-                            let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                            let const_val = func.borrow_mut().chunk.get_const(idx);
-                            if let Value::Obj(Obj::String(var_name)) = const_val {
-                                let value = self.pop_stack();
-
-                                let borrow_var_name: &RefCell<StrObj> = var_name.borrow();
-                                let mut name = borrow_var_name.borrow().name.to_string();
-
-                                match value {
-                                    Value::Obj(Obj::Structs(ref struct_obj)) => {
-                                        let struct_obj: &RefCell<Structs> = struct_obj.borrow();
-                                        name = struct_obj.borrow().name.to_string();
-                                    }
-                                    _ => {}
-                                };
+                        OpCode::OpDefineGlobal(slot) => {
+                            let value = self.pop_stack();
 
-                                self.globals.insert(name, value);
-                            }
+                            let module = self.get_frame().module;
+                            self.heap.module_mut(module).globals[slot] = Some(value);
                         }
-                        OpCode::OpGetGlobal(idx) => {
-                            let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                            let const_val = func.borrow_mut().chunk.get_const(idx);
-                            if let Value::Obj(Obj::String(var_name)) = const_val {
-                                let borrow_var_name: &RefCell<StrObj> = var_name.borrow();
-                                let name: &StrObj = &borrow_var_name.borrow();
-
-                                match self.globals.get(&name.name) {
-                                    Some(value) => {
-                                        self.push_stack(value.clone());
-                                    }
-                                    None => {
-                                        self.runtime_error(
-                                            format!("Undefined Variable {}", name).as_str(),
-                                        );
-                                        return Err(InterpretError::RuntimeError);
+                        OpCode::OpGetGlobal(slot) => {
+                            let module = self.heap.module(self.get_frame().module);
+
+                            match module.globals[slot] {
+                                Some(value) => self.push_stack(value),
+                                // Natives aren't in the module, they are only looked for once
+                                // nothing in it has the name
+                                None => {
+                                    let name = module.slots.names[slot];
+                                    match self.builtins.get(&name) {
+                                        Some(value) => self.push_stack(*value),
+                                        None => return Err(self.undefined_variable(name)),
                                     }
                                 }
                             }
                         }
-                        OpCode::OpSetGlobal(idx) => {
-                            let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                            let const_val = func.borrow_mut().chunk.get_const(idx);
-                            if let Value::Obj(Obj::String(var_name)) = const_val {
-                                let borrow_var_name: &RefCell<StrObj> = var_name.borrow();
-                                let name: &String = &borrow_var_name.borrow().name;
-                                if self.globals.contains_key(name) {
-                                    self.globals.insert(name.to_string(), self.peek_stack(0));
-                                } else {
-                                    self.runtime_error(
-                                        format!("Undefined Variable {}", name).as_str(),
-                                    );
-                                    return Err(InterpretError::RuntimeError);
+                        OpCode::OpSetGlobal(slot) => {
+                            let value = self.peek_stack(0);
+
+                            let module = self.heap.module_mut(self.get_frame().module);
+                            match module.globals[slot] {
+                                Some(ref mut global) => *global = value,
+                                None => {
+                                    let name = module.slots.names[slot];
+                                    return Err(self.undefined_variable(name));
                                 }
                             }
                         }
@@ -266,7 +519,7 @@ impl VM {
                             let frame = self.get_frame();
                             let frame_stack_idx = frame.slots + idx;
 
-                            let value = self.stack[frame_stack_idx].clone();
+                            let value = self.stack[frame_stack_idx];
                             self.push_stack(value);
                         }
                         OpCode::OpSetLocal(idx) => {
@@ -276,79 +529,155 @@ impl VM {
                             self.stack[frame_stack_idx] = self.peek_stack(0);
                         }
 
+                        OpCode::OpClosure(idx) => {
+                            if let Value::Obj(Obj::Function(function)) = self.read_constant(idx) {
+                                let upvalue_idxs = self.heap.function(function).upvalues.clone();
+                                let mut upvalues = Vec::with_capacity(upvalue_idxs.len());
+
+                                for upvalue in upvalue_idxs {
+                                    if upvalue.is_local {
+                                        let slot = self.get_frame().slots + upvalue.index;
+                                        upvalues.push(self.capture_upvalue(slot));
+                                    } else {
+                                        let closure = self.heap.closure(self.get_frame().closure);
+                                        upvalues.push(closure.upvalues[upvalue.index]);
+                                    }
+                                }
+
+                                let module = self.get_frame().module;
+                                let closure = self.allocate_obj(ObjData::Closure(ClosureObj::new(
+                                    function, upvalues, module,
+                                )));
+                                self.push_stack(Value::Obj(Obj::Closure(closure)));
+                            }
+                        }
+                        OpCode::OpGetUpvalue(idx) => {
+                            let closure = self.heap.closure(self.get_frame().closure);
+                            let upvalue = closure.upvalues[idx];
+
+                            let value = match self.heap.upvalue(upvalue).location {
+                                UpvalueLocation::Open(slot) => self.stack[slot],
+                                UpvalueLocation::Closed(value) => value,
+                            };
+                            self.push_stack(value);
+                        }
+                        OpCode::OpSetUpvalue(idx) => {
+                            let closure = self.heap.closure(self.get_frame().closure);
+                            let upvalue = closure.upvalues[idx];
+
+                            let value = self.peek_stack(0);
+                            let upvalue = self.heap.upvalue_mut(upvalue);
+                            match upvalue.location {
+                                UpvalueLocation::Open(slot) => self.stack[slot] = value,
+                                UpvalueLocation::Closed(_) => {
+                                    upvalue.location = UpvalueLocation::Closed(value)
+                                }
+                            };
+                        }
+                        OpCode::OpCloseUpvalue => {
+                            self.close_upvalues(self.stack.len() - 1);
+                            self.pop_stack();
+                        }
+
                         OpCode::OpSetProperty(idx) => match self.peek_stack(1) {
                             Value::Obj(Obj::Instance(instance_obj)) => {
-                                let func: &RefCell<FunctionObj> =
-                                    self.get_frame().function.borrow();
-                                let name = func.borrow_mut().chunk.get_const(idx);
-
                                 let value = self.pop_stack();
+                                let name = self.read_name_constant(idx);
 
-                                let instance: &RefCell<StructsInstance> = instance_obj.borrow();
-                                let mut instance = instance.borrow_mut();
-
-                                match name {
-                                    Value::Obj(Obj::String(str_obj)) => {
-                                        let str: &RefCell<StrObj> = str_obj.borrow();
-                                        let str = str.borrow();
-                                        if instance.fields.contains_key(&str.name) {
-                                            instance.fields.insert(str.name.to_string(), value);
-                                        } else {
-                                            self.runtime_error(
-                                                "Can't set property on unknown field",
-                                            );
-                                        }
+                                let instance = self.heap.instance_mut(instance_obj);
+                                match instance.fields.get_mut(&name) {
+                                    Some(field) => {
+                                        *field = value;
                                     }
-                                    _ => {
-                                        unreachable!();
+                                    None => {
+                                        self.runtime_error("Can't set property on unknown field");
+                                        return Err(InterpretError::RuntimeError);
                                     }
                                 }
                             }
+                            Value::Obj(Obj::Map(map)) => {
+                                let value = self.pop_stack();
+                                let key = self.read_constant(idx);
+                                let map_key = self.map_key(key)?;
+
+                                self.heap.map_mut(map).insert(map_key, key, value);
+                            }
+                            Value::Obj(Obj::Module(_)) => {
+                                self.runtime_error(
+                                    "Can't set the globals of a module from outside of it",
+                                );
+                                return Err(InterpretError::RuntimeError);
+                            }
                             _ => {
                                 self.runtime_error("Can't set property on non Instance");
                                 return Err(InterpretError::RuntimeError);
                             }
                         },
                         OpCode::OpGetProperty(idx) => {
-                            let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                            let const_val = func.borrow_mut().chunk.get_const(idx);
                             let instance = self.peek_stack(0);
 
-                            match const_val {
-                                Value::Obj(Obj::String(ref name)) => {
-                                    match instance {
-                                        Value::Obj(Obj::Instance(instance)) => {
-                                            let name: &RefCell<StrObj> = name.borrow();
-                                            let name: &String = &name.borrow().name;
-
-                                            let instance_obj: &RefCell<StructsInstance> =
-                                                instance.borrow();
-                                            let instance_fields: &HashMap<String, Value> =
-                                                &instance_obj.borrow().fields;
-
-                                            if instance_fields.contains_key(name) {
-                                                self.pop_stack();
-                                                if let Some(value) = instance_fields.get(name) {
-                                                    self.push_stack(value.clone());
-                                                }
-                                            } else {
-                                                self.runtime_error(
-                                                    format!("Undefined property: {}", name)
-                                                        .as_str(),
-                                                );
-                                                return Err(InterpretError::RuntimeError);
-                                            }
+                            match instance {
+                                Value::Obj(Obj::Instance(instance)) => {
+                                    let name = self.read_name_constant(idx);
+
+                                    let instance_fields = &self.heap.instance(instance).fields;
+
+                                    let origin = self.heap.instance(instance).origin;
+                                    let method =
+                                        self.heap.structs(origin).methods.get(&name).copied();
+
+                                    match (instance_fields.get(&name), method) {
+                                        (Some(value), _) => {
+                                            let value = *value;
+                                            self.pop_stack();
+                                            self.push_stack(value);
                                         }
-                                        _ => {
-                                            self.runtime_error("Only instances of Structs are allowed to have properties");
+                                        (None, Some(method)) => {
+                                            // The instance stays on the stack while allocating
+                                            let receiver = self.peek_stack(0);
+                                            let bound_method =
+                                                self.allocate_obj(ObjData::BoundMethod(
+                                                    BoundMethodObj::new(receiver, method),
+                                                ));
+                                            self.pop_stack();
+                                            self.push_stack(Value::Obj(Obj::BoundMethod(
+                                                bound_method,
+                                            )));
+                                        }
+                                        (None, None) => {
+                                            let msg = format!(
+                                                "Undefined property: {}",
+                                                self.heap.string(name)
+                                            );
+                                            self.runtime_error(msg.as_str());
                                             return Err(InterpretError::RuntimeError);
                                         }
-                                    };
+                                    }
+                                }
+                                Value::Obj(Obj::Map(map)) => {
+                                    let key = self.read_constant(idx);
+                                    let value = self.map_get(map, key)?;
+
+                                    self.pop_stack();
+                                    self.push_stack(value);
+                                }
+                                Value::Obj(Obj::Module(module)) => {
+                                    let value = self.module_get(module, idx)?;
+
+                                    self.pop_stack();
+                                    self.push_stack(value);
                                 }
                                 _ => {
-                                    unreachable!();
+                                    self.runtime_error(
+                                        "Only instances of Structs are allowed to have properties",
+                                    );
+                                    return Err(InterpretError::RuntimeError);
                                 }
-                            }
+                            };
+                        }
+
+                        OpCode::OpInvoke(idx, args_count) => {
+                            self.invoke(idx, args_count)?;
                         }
 
                         OpCode::OpNegate => {
@@ -362,28 +691,25 @@ impl VM {
                                 }
                             };
 
-                            match self.pop_stack() {
-                                Value::Number(number) => self.push_stack(Value::Number(-number)),
-                                _ => {}
-                            };
+                            if let Value::Number(number) = self.pop_stack() {
+                                self.push_stack(Value::Number(-number))
+                            }
                         }
-                        OpCode::OpAdd => match (self.pop_stack(), self.pop_stack()) {
-                            (
-                                Value::Obj(Obj::String(right_rc)),
-                                Value::Obj(Obj::String(left_rc)),
-                            ) => {
-                                let left_string: &RefCell<StrObj> = left_rc.borrow();
-                                let mut left_string = left_string.borrow_mut();
-
-                                let right_string: &RefCell<StrObj> = right_rc.borrow();
-                                let right_string: &String = &right_string.borrow().name;
-
-                                left_string.name.reserve(right_string.len());
-                                left_string.name.push_str(&right_string.borrow());
+                        OpCode::OpAdd => match (self.peek_stack(0), self.peek_stack(1)) {
+                            (Value::Obj(Obj::String(right)), Value::Obj(Obj::String(left))) => {
+                                // Strings never change, so this always makes a new one. Both
+                                // operands stay on the stack in case allocating collects.
+                                let mut string = self.heap.string(left).name.clone();
+                                string.push_str(&self.heap.string(right).name);
+                                let string = self.allocate_string(&string);
 
-                                self.push_stack(Value::Obj(Obj::String(left_rc.clone())))
+                                self.pop_stack();
+                                self.pop_stack();
+                                self.push_stack(Value::Obj(Obj::String(string)))
                             }
                             (Value::Number(right_num), Value::Number(left_num)) => {
+                                self.pop_stack();
+                                self.pop_stack();
                                 self.push_stack(Value::Number(left_num + right_num))
                             }
                             _ => {
@@ -391,9 +717,12 @@ impl VM {
                                 return Err(InterpretError::RuntimeError);
                             }
                         },
-                        OpCode::OpSubtract | OpCode::OpMultiply | OpCode::OpDivide => {
-                            self.binary_operators(instruction)?
-                        }
+                        OpCode::OpSubtract
+                        | OpCode::OpMultiply
+                        | OpCode::OpDivide
+                        | OpCode::OpModulo
+                        | OpCode::OpFloorDivide
+                        | OpCode::OpPower => self.binary_operators(instruction)?,
 
                         OpCode::OpTrue => self.push_stack(Value::Boolean(true)),
                         OpCode::OpFalse => self.push_stack(Value::Boolean(false)),
@@ -403,68 +732,118 @@ impl VM {
                             self.push_stack(Value::Boolean(self.is_falsey(value)));
                         }
 
-                        OpCode::OpGreater => {
+                        OpCode::OpGreater | OpCode::OpEqual | OpCode::OpLess => {
                             let right = self.pop_stack();
                             let left = self.pop_stack();
 
-                            self.push_stack(Value::Boolean(self.is_greater(left, right)?))
+                            let value = self.compare(instruction, left, right)?;
+                            self.push_stack(Value::Boolean(value))
                         }
-                        OpCode::OpEqual => {
+                        // Negating the comparison keeps them the same as with OpNot after it,
+                        // even for NaN
+                        OpCode::OpNotEqual => {
                             let right = self.pop_stack();
                             let left = self.pop_stack();
 
-                            let boolean = left == right;
-                            self.push_stack(Value::Boolean(boolean))
+                            let value = self.compare(OpCode::OpEqual, left, right)?;
+                            self.push_stack(Value::Boolean(!value))
                         }
-                        OpCode::OpLess => {
+                        OpCode::OpGreaterEqual => {
                             let right = self.pop_stack();
                             let left = self.pop_stack();
 
-                            let value: bool;
-                            if left == right {
-                                value = false
-                            } else {
-                                value = !self.is_greater(left, right)?;
-                            }
-                            self.push_stack(Value::Boolean(value))
+                            let value = self.compare(OpCode::OpLess, left, right)?;
+                            self.push_stack(Value::Boolean(!value))
+                        }
+                        OpCode::OpLessEqual => {
+                            let right = self.pop_stack();
+                            let left = self.pop_stack();
+
+                            let value = self.compare(OpCode::OpGreater, left, right)?;
+                            self.push_stack(Value::Boolean(!value))
                         }
                         OpCode::OpClass(idx) => {
-                            let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                            let const_val = func.borrow_mut().chunk.get_const(idx);
-                            match const_val {
-                                Value::Obj(Obj::String(str)) => {
-                                    let string: &RefCell<StrObj> = str.borrow();
-                                    let string: &String = &string.borrow().name;
-                                    let value = Value::Obj(Obj::Structs(Rc::new(RefCell::new(
-                                        Structs::new(string.to_string()),
-                                    ))));
-                                    self.push_stack(value);
+                            let name = self.heap.string(self.read_name_constant(idx)).name.clone();
+                            let structs = self.allocate_obj(ObjData::Structs(Structs::new(name)));
+                            self.push_stack(Value::Obj(Obj::Structs(structs)));
+                        }
+                        OpCode::OpNamedInstance(field_count) => {
+                            let start = self.stack.len() - 2 * field_count as usize;
+
+                            let struct_obj = match self.stack[start - 1] {
+                                Value::Obj(Obj::Structs(struct_obj)) => struct_obj,
+                                _ => {
+                                    self.runtime_error("Can only build instances of Structs");
+                                    return Err(InterpretError::RuntimeError);
+                                }
+                            };
+
+                            let structs = self.heap.structs(struct_obj);
+                            let mut fields = HashMap::new();
+                            for pair in self.stack[start..].chunks(2) {
+                                let name = match pair[0] {
+                                    Value::Obj(Obj::String(name)) => name,
+                                    _ => unreachable!("Field names are always strings"),
+                                };
+
+                                if !structs.fields.contains_key(&name) {
+                                    let msg = format!(
+                                        "{} has no field '{}'",
+                                        structs.name,
+                                        self.heap.string(name)
+                                    );
+                                    self.runtime_error(&msg);
+                                    return Err(InterpretError::RuntimeError);
+                                }
+                                fields.insert(name, pair[1]);
+                            }
+
+                            if let Some(missing) =
+                                structs.field_order.iter().find(|x| !fields.contains_key(x))
+                            {
+                                let msg = format!(
+                                    "Missing field '{}' for {}",
+                                    self.heap.string(*missing),
+                                    structs.name
+                                );
+                                self.runtime_error(&msg);
+                                return Err(InterpretError::RuntimeError);
+                            }
+
+                            let instance = self.allocate_obj(ObjData::Instance(
+                                StructsInstance::new(struct_obj, fields),
+                            ));
+
+                            self.stack.truncate(start);
+                            self.stack[start - 1] = Value::Obj(Obj::Instance(instance));
+                        }
+                        OpCode::OpMethod(idx) => {
+                            let name = self.read_name_constant(idx);
+
+                            match (self.peek_stack(1), self.peek_stack(0)) {
+                                (
+                                    Value::Obj(Obj::Structs(struct_obj)),
+                                    Value::Obj(Obj::Closure(closure)),
+                                ) => {
+                                    self.heap
+                                        .structs_mut(struct_obj)
+                                        .methods
+                                        .insert(name, closure);
+                                    self.pop_stack();
+                                }
+                                _ => {
+                                    unreachable!();
                                 }
-                                _ => unreachable!(),
                             }
                         }
                         OpCode::OpField(idx) => {
-                            let func: &RefCell<FunctionObj> = self.get_frame().function.borrow();
-                            let const_val = func.borrow_mut().chunk.get_const(idx);
+                            let name = self.read_name_constant(idx);
 
-                            let structs = self.peek_stack(0);
-                            match structs {
+                            match self.peek_stack(0) {
                                 Value::Obj(Obj::Structs(struct_obj)) => {
-                                    let struct_obj: &RefCell<Structs> = struct_obj.borrow();
-                                    let struct_obj: &mut Structs = &mut struct_obj.borrow_mut();
-
-                                    match const_val {
-                                        Value::Obj(Obj::String(str_obj)) => {
-                                            let str_obj: &RefCell<StrObj> = str_obj.borrow();
-                                            let str_obj: &StrObj = &str_obj.borrow();
-
-                                            struct_obj
-                                                .fields
-                                                .insert(str_obj.name.clone(), Value::None);
-                                        }
-                                        _ => {
-                                            unreachable!();
-                                        }
+                                    let structs = self.heap.structs_mut(struct_obj);
+                                    if structs.fields.insert(name, Value::None).is_none() {
+                                        structs.field_order.push(name);
                                     }
                                 }
                                 _ => {
@@ -472,6 +851,82 @@ impl VM {
                                 }
                             }
                         }
+
+                        OpCode::OpBuildList(item_count) => {
+                            let start = self.stack.len() - item_count as usize;
+                            // The items stay on the stack until the list exists, so a collection
+                            // here still sees them.
+                            let items = self.stack[start..].to_vec();
+                            let list = self.allocate_obj(ObjData::List(ListObj::new(items)));
+
+                            self.stack.truncate(start);
+                            self.push_stack(Value::Obj(Obj::List(list)));
+                        }
+                        OpCode::OpImport(idx) => {
+                            let path = self.read_name_constant(idx);
+                            let path = self.heap.string(path).name.clone();
+
+                            let module = self.import(&path)?;
+                            self.push_stack(Value::Obj(Obj::Module(module)));
+                        }
+                        OpCode::OpBuildString(part_count) => {
+                            let start = self.stack.len() - part_count as usize;
+
+                            let mut string = String::new();
+                            for part in &self.stack[start..] {
+                                string.push_str(&self.heap.display(part).to_string());
+                            }
+                            // Same as OpAdd, the parts stay on the stack while allocating
+                            let string = self.allocate_string(&string);
+
+                            self.stack.truncate(start);
+                            self.push_stack(Value::Obj(Obj::String(string)));
+                        }
+                        OpCode::OpGetIndex => {
+                            let index = self.pop_stack();
+                            let target = self.pop_stack();
+
+                            let value = match target {
+                                Value::Obj(Obj::Map(map)) => self.map_get(map, index)?,
+                                _ => {
+                                    let (list, index) = self.list_index(target, index)?;
+                                    self.heap.list(list).items[index]
+                                }
+                            };
+                            self.push_stack(value);
+                        }
+                        OpCode::OpSetIndex => {
+                            let value = self.pop_stack();
+                            let index = self.pop_stack();
+                            let target = self.pop_stack();
+
+                            match target {
+                                Value::Obj(Obj::Map(map)) => {
+                                    let map_key = self.map_key(index)?;
+                                    self.heap.map_mut(map).insert(map_key, index, value);
+                                }
+                                _ => {
+                                    let (list, index) = self.list_index(target, index)?;
+                                    self.heap.list_mut(list).items[index] = value;
+                                }
+                            }
+                            self.push_stack(value);
+                        }
+
+                        OpCode::OpBuildMap(entry_count) => {
+                            let start = self.stack.len() - 2 * entry_count as usize;
+                            // Same as lists, the entries stay on the stack while the map is made.
+                            let map = self.allocate_obj(ObjData::Map(MapObj::new()));
+
+                            for i in (start..self.stack.len()).step_by(2) {
+                                let (key, value) = (self.stack[i], self.stack[i + 1]);
+                                let map_key = self.map_key(key)?;
+                                self.heap.map_mut(map).insert(map_key, key, value);
+                            }
+
+                            self.stack.truncate(start);
+                            self.push_stack(Value::Obj(Obj::Map(map)));
+                        }
                     }
                 }
                 // LOOP STOPPER
@@ -485,96 +940,83 @@ impl VM {
     }
 
     fn binary_operators(&mut self, operator: OpCode) -> Result<(), InterpretError> {
-        let b = match self.pop_stack() {
-            Value::Number(num) => num,
-            Value::Boolean(bool) => {
-                self.runtime_error(
-                    format!("{} not supported on boolean value: {}", operator, bool).as_str(),
-                );
-                return Err(InterpretError::RuntimeError);
-            }
-            Value::None => {
-                self.runtime_error(format!("{} not supported on none value", operator).as_str());
+        let b = self.pop_number(operator)?;
+        let a = self.pop_number(operator)?;
+
+        let is_division = matches!(
+            operator,
+            OpCode::OpDivide | OpCode::OpModulo | OpCode::OpFloorDivide
+        );
+        if is_division && b == 0.0 {
+            self.runtime_error("Can't divide by zero");
+            return Err(InterpretError::RuntimeError);
+        }
+
+        match operator.apply_numbers(a, b) {
+            Some(value) => self.push_stack(Value::Number(value)),
+            None => {
+                self.runtime_error(format!("{} is not a Binary Operator", operator).as_str());
                 return Err(InterpretError::RuntimeError);
             }
-            Value::Obj(value_obj) => match value_obj {
-                Obj::String(string) => {
-                    let str: &RefCell<StrObj> = string.borrow();
-                    self.runtime_error(
-                        format!(
-                            "{} not supported on string value: {}",
-                            operator,
-                            str.borrow()
-                        )
-                        .as_str(),
-                    );
-                    return Err(InterpretError::RuntimeError);
-                }
-                _ => {
-                    self.runtime_error("{} not supported on Functions");
-                    return Err(InterpretError::RuntimeError);
-                }
-            },
-        };
+        }
+
+        Ok(())
+    }
 
-        let a = match self.pop_stack() {
-            Value::Number(num) => num,
+    fn pop_number(&mut self, operator: OpCode) -> Result<f64, InterpretError> {
+        match self.pop_stack() {
+            Value::Number(num) => Ok(num),
             Value::Boolean(bool) => {
                 self.runtime_error(
                     format!("{} not supported on boolean value: {}", operator, bool).as_str(),
                 );
-                return Err(InterpretError::RuntimeError);
+                Err(InterpretError::RuntimeError)
             }
             Value::None => {
                 self.runtime_error(format!("{} not supported on none value", operator).as_str());
-                return Err(InterpretError::RuntimeError);
+                Err(InterpretError::RuntimeError)
             }
             Value::Obj(value_obj) => match value_obj {
                 Obj::String(string) => {
-                    let str: &RefCell<StrObj> = string.borrow();
                     self.runtime_error(
                         format!(
                             "{} not supported on string value: {}",
                             operator,
-                            str.borrow()
+                            self.heap.string(string)
                         )
                         .as_str(),
                     );
-                    return Err(InterpretError::RuntimeError);
+                    Err(InterpretError::RuntimeError)
                 }
                 _ => {
-                    self.runtime_error("{} not supported on Functions");
-                    return Err(InterpretError::RuntimeError);
+                    self.runtime_error(format!("{} not supported on Functions", operator).as_str());
+                    Err(InterpretError::RuntimeError)
                 }
             },
-        };
+        }
+    }
 
+    fn compare(&self, operator: OpCode, left: Value, right: Value) -> Result<bool, InterpretError> {
         match operator {
-            OpCode::OpSubtract => self.push_stack(Value::Number(a - b)),
-            OpCode::OpMultiply => self.push_stack(Value::Number(a * b)),
-            OpCode::OpDivide => self.push_stack(Value::Number(a / b)),
-            _ => {
-                self.runtime_error(format!("{} is not a Binary Operator", operator).as_str());
-                return Err(InterpretError::RuntimeError);
-            }
+            // Strings are interned, so comparing identity is enough for them too.
+            OpCode::OpEqual => Ok(left == right),
+            OpCode::OpGreater => self.is_greater(left, right),
+            OpCode::OpLess if left == right => Ok(false),
+            OpCode::OpLess => Ok(!self.is_greater(left, right)?),
+            _ => unreachable!("{} is not a comparison", operator),
         }
-
-        Ok(())
     }
 
     fn is_greater(&self, left: Value, right: Value) -> Result<bool, InterpretError> {
-        match left {
-            Value::Number(num_left) => match right {
-                Value::Number(num_right) => return Ok(num_left > num_right),
-                _ => {}
-            },
-            _ => {}
-        };
+        if let (Value::Number(num_left), Value::Number(num_right)) = (left, right) {
+            return Ok(num_left > num_right);
+        }
 
         self.runtime_error(
             format!(
                 "Can't perform < and > on different types: '{}' and '{}'",
-                left, right
+                self.heap.display(&left),
+                self.heap.display(&right)
             )
             .as_str(),
         );
@@ -582,6 +1024,216 @@ impl VM {
         Err(InterpretError::RuntimeError)
     }
 
+    // Checks that `list` can be indexed by `index`, giving back the list's heap index and the index
+    // as a usize.
+    fn list_index(&self, list: Value, index: Value) -> Result<(usize, usize), InterpretError> {
+        let list = match list {
+            Value::Obj(Obj::List(list)) => list,
+            _ => {
+                self.runtime_error(
+                    format!(
+                        "Only lists and maps can be indexed, not '{}'",
+                        self.heap.display(&list)
+                    )
+                    .as_str(),
+                );
+                return Err(InterpretError::RuntimeError);
+            }
+        };
+
+        let index = match index {
+            Value::Number(num) if num.fract() == 0.0 => num,
+            _ => {
+                self.runtime_error(
+                    format!(
+                        "List index must be a whole number, not '{}'",
+                        self.heap.display(&index)
+                    )
+                    .as_str(),
+                );
+                return Err(InterpretError::RuntimeError);
+            }
+        };
+
+        let length = self.heap.list(list).items.len();
+        if index < 0.0 || index >= length as f64 {
+            self.runtime_error(
+                format!(
+                    "List index {} is out of bounds for a list of length {}",
+                    index, length
+                )
+                .as_str(),
+            );
+            return Err(InterpretError::RuntimeError);
+        }
+
+        Ok((list, index as usize))
+    }
+
+    fn map_key(&self, key: Value) -> Result<MapKey, InterpretError> {
+        match self.heap.map_key(&key) {
+            Some(map_key) => Ok(map_key),
+            None => {
+                self.runtime_error(
+                    format!(
+                        "Map keys must be strings or numbers, not '{}'",
+                        self.heap.display(&key)
+                    )
+                    .as_str(),
+                );
+                Err(InterpretError::RuntimeError)
+            }
+        }
+    }
+
+    fn map_get(&self, map: usize, key: Value) -> Result<Value, InterpretError> {
+        let map_key = self.map_key(key)?;
+
+        match self.heap.map(map).get(&map_key) {
+            Some(value) => Ok(value),
+            None => {
+                self.runtime_error(
+                    format!("Key '{}' not found in map", self.heap.display(&key)).as_str(),
+                );
+                Err(InterpretError::RuntimeError)
+            }
+        }
+    }
+
+    fn undefined_variable(&self, name: usize) -> InterpretError {
+        let msg = format!("Undefined Variable {}", self.heap.string(name));
+        self.runtime_error(msg.as_str());
+        InterpretError::RuntimeError
+    }
+
+    // A module's own globals, natives only count when looked up by name from inside of it.
+    fn module_get(&self, module: usize, idx: usize) -> Result<Value, InterpretError> {
+        let name = self.read_name_constant(idx);
+        let module = self.heap.module(module);
+
+        match module.get(name) {
+            Some(value) => Ok(value),
+            None => {
+                let msg = format!(
+                    "Module {} has no variable {}",
+                    module.name,
+                    self.heap.string(name)
+                );
+                self.runtime_error(&msg);
+                Err(InterpretError::RuntimeError)
+            }
+        }
+    }
+
+    // Globals of the module first, then the natives
+    fn lookup_global(&self, module: usize, name: usize) -> Option<Value> {
+        match self.heap.module(module).get(name) {
+            Some(value) => Some(value),
+            None => self.builtins.get(&name).copied(),
+        }
+    }
+
+    // Finds the file `path` points to, next to the module doing the import, and runs it the
+    // first time. Importing the same file again gives back the same module.
+    fn import(&mut self, path: &str) -> Result<usize, InterpretError> {
+        let importer = self.heap.module(self.get_frame().module);
+        let dir = match importer.path {
+            Some(ref importer_path) => importer_path.parent().unwrap_or(Path::new("")),
+            None => Path::new(""),
+        };
+
+        let file = match find_module_file(&dir.join(path)) {
+            Some(file) => file,
+            None => {
+                self.runtime_error(&format!("Can't find the module \"{}\"", path));
+                return Err(InterpretError::RuntimeError);
+            }
+        };
+
+        let canonical = canonical_path(&file);
+        if let Some(module) = self.modules.get(&canonical) {
+            return Ok(*module);
+        }
+
+        if let Some(start) = self.importing.iter().position(|x| *x == canonical) {
+            let cycle: Vec<String> = self.importing[start..]
+                .iter()
+                .chain([&canonical])
+                .map(|x| module_name(x))
+                .collect();
+            self.runtime_error(&format!("Import cycle: {}", cycle.join(" -> ")));
+            return Err(InterpretError::RuntimeError);
+        }
+
+        let bytes = match fs::read(&file) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                self.runtime_error(&format!("Can't read the module \"{}\": {}", path, err));
+                return Err(InterpretError::RuntimeError);
+            }
+        };
+
+        // The module is made first so the compiler can give its globals slots. It stays on the
+        // stack while allocating, the closure then keeps it alive.
+        let module = self.allocate_obj(ObjData::Module(ModuleObj::new(
+            module_name(&file),
+            Some(file.clone()),
+        )));
+        self.push_stack(Value::Obj(Obj::Module(module)));
+
+        // Modules can be compiled files too
+        let source = String::from_utf8_lossy(&bytes).into_owned();
+        let res = match bytecode::is_bytecode(&bytes) {
+            true => self.load_in(module, &bytes),
+            false => self.compile_in(module, source.clone(), false),
+        };
+
+        let function = match res {
+            Ok(function) => self.heap.alloc(ObjData::Function(function)),
+            Err(InterpretError::CompileError(diagnostics)) => {
+                self.pop_stack();
+                let mut error_output = self.error_output.borrow_mut();
+                let _ = writeln!(error_output, "> In {}", file.display());
+                for diagnostic in diagnostics {
+                    let _ = writeln!(error_output, "{}\n", diagnostic.render(&source));
+                }
+                drop(error_output);
+                self.runtime_error(&format!("The module \"{}\" has errors", path));
+                return Err(InterpretError::RuntimeError);
+            }
+            Err(InterpretError::BytecodeError(err)) => {
+                self.pop_stack();
+                self.runtime_error(&format!("Can't load the module \"{}\": {}", path, err));
+                return Err(InterpretError::RuntimeError);
+            }
+            Err(err) => return Err(err),
+        };
+
+        self.push_stack(Value::Obj(Obj::Function(function)));
+        let closure = self.allocate_obj(ObjData::Closure(ClosureObj::new(
+            function,
+            Vec::new(),
+            module,
+        )));
+        self.pop_stack();
+        self.pop_stack();
+        self.push_stack(Value::Obj(Obj::Closure(closure)));
+
+        let frame_count = self.frame_count;
+        self.add_call_frame(closure, 0)?;
+
+        self.importing.push(canonical.clone());
+        let res = self.run_until(frame_count);
+        self.importing.pop();
+        res?;
+
+        // What the module's script returned
+        self.pop_stack();
+
+        self.modules.insert(canonical, module);
+        Ok(module)
+    }
+
     fn is_falsey(&self, value: Value) -> bool {
         match value {
             Value::None | Value::Boolean(false) => true,
@@ -589,49 +1241,294 @@ impl VM {
         }
     }
 
-    fn add_call_frame(&mut self, function_obj: Rc<RefCell<FunctionObj>>, arg_count: usize) {
-        let temp_func_obj = function_obj.clone();
-        let temp_func_obj: &RefCell<FunctionObj> = temp_func_obj.borrow();
-        let temp_func_obj = temp_func_obj.borrow();
+    // `receiver.name(args)` in one step. Methods are called directly with the receiver already in
+    // place, anything else is looked up like a property and then called.
+    fn invoke(&mut self, idx: usize, args_count: u8) -> Result<(), InterpretError> {
+        let receiver_slot = self.stack.len() - args_count as usize - 1;
+
+        let callee = match self.stack[receiver_slot] {
+            Value::Obj(Obj::Instance(instance)) => {
+                let name = self.read_name_constant(idx);
+                let instance = self.heap.instance(instance);
+
+                if let Some(value) = instance.fields.get(&name) {
+                    *value
+                } else if let Some(method) = self.heap.structs(instance.origin).methods.get(&name) {
+                    return self.add_call_frame(*method, args_count as usize);
+                } else {
+                    let msg = format!("Undefined property: {}", self.heap.string(name));
+                    self.runtime_error(msg.as_str());
+                    return Err(InterpretError::RuntimeError);
+                }
+            }
+            Value::Obj(Obj::Map(map)) => {
+                let key = self.read_constant(idx);
+                self.map_get(map, key)?
+            }
+            Value::Obj(Obj::Module(module)) => self.module_get(module, idx)?,
+            _ => {
+                self.runtime_error("Only instances of Structs are allowed to have properties");
+                return Err(InterpretError::RuntimeError);
+            }
+        };
+
+        self.stack[receiver_slot] = callee;
+        self.call_value(args_count)
+    }
+
+    // Calls whatever sits below the arguments on the stack.
+    fn call_value(&mut self, args_count: u8) -> Result<(), InterpretError> {
+        match self.peek_stack(args_count as usize) {
+            Value::Obj(Obj::Closure(closure)) => {
+                self.add_call_frame(closure, args_count as usize)?;
+            }
+            Value::Obj(Obj::BoundMethod(bound_method)) => {
+                // The receiver takes the callee's slot, which is where the method looks for
+                // self.
+                let bound_method = self.heap.bound_method(bound_method);
+                let method = bound_method.method;
+                let length = self.stack.len();
+                self.stack[length - args_count as usize - 1] = bound_method.receiver;
+
+                self.add_call_frame(method, args_count as usize)?;
+            }
+            Value::Obj(Obj::NativeFn(func)) => {
+                let start = self.stack.len() - args_count as usize;
+
+                let func: &NativeFn = self.heap.native_fn(func);
+                if let Some(msg) = func.arity.check(&func.name, args_count) {
+                    self.runtime_error(&msg);
+                    return Err(InterpretError::RuntimeError);
+                }
+
+                // The arguments stay on the stack while the native runs, so they can't get
+                // collected from under it.
+                let native_fn = func.native_fn;
+                let args: Vec<Value> = self.stack[start..].to_vec();
 
-        if arg_count != temp_func_obj.arity as usize {
+                let value_res = (native_fn)(&mut NativeContext::new(self), &args);
+
+                // Pops the arguments and the function out from the stack
+                self.stack.truncate(start - 1);
+
+                match value_res {
+                    Ok(value) => self.push_stack(value),
+                    Err(NativeError::Message(msg)) => {
+                        self.runtime_error(&msg);
+                        return Err(InterpretError::RuntimeError);
+                    }
+                    Err(NativeError::Reported) => return Err(InterpretError::RuntimeError),
+                }
+            }
+            Value::Obj(Obj::Structs(struct_obj)) => {
+                let structs = self.heap.structs(struct_obj);
+                let mut fields = structs.fields.clone();
+
+                // `Point {}` leaves every field as none, otherwise each one
+                // needs a value in the order they were declared.
+                if args_count != 0 {
+                    if structs.field_order.len() != args_count as usize {
+                        let msg = format!(
+                            "{} has {} fields but got {}",
+                            structs.name,
+                            structs.field_order.len(),
+                            args_count
+                        );
+                        self.runtime_error(&msg);
+                        return Err(InterpretError::RuntimeError);
+                    }
+
+                    let start = self.stack.len() - args_count as usize;
+                    for (name, value) in structs.field_order.iter().zip(&self.stack[start..]) {
+                        fields.insert(*name, *value);
+                    }
+                }
+
+                // The values are still on the stack while allocating
+                let instance =
+                    self.allocate_obj(ObjData::Instance(StructsInstance::new(struct_obj, fields)));
+
+                let start = self.stack.len() - args_count as usize;
+                self.stack.truncate(start);
+                self.stack[start - 1] = Value::Obj(Obj::Instance(instance));
+            }
+            _ => {
+                self.runtime_error("Can only call Functions");
+                return Err(InterpretError::RuntimeError);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn add_call_frame(&mut self, closure: usize, arg_count: usize) -> Result<(), InterpretError> {
+        let function = self.heap.closure(closure).function;
+        let arity = self.heap.function(function).arity;
+
+        if arg_count != arity as usize {
             self.runtime_error(
-                format!(
-                    "Expected {} arguments but got {}",
-                    temp_func_obj.arity, arg_count
-                )
-                .as_str(),
+                format!("Expected {} arguments but got {}", arity, arg_count).as_str(),
+            );
+            return Err(InterpretError::RuntimeError);
+        }
+
+        // Reported at the call, the frame that would overflow is never made
+        if self.frame_count >= self.max_frames {
+            let msg = format!(
+                "Stack overflow: too many nested calls to {}",
+                self.function_name(function)
+            );
+            self.runtime_error(&msg);
+            return Err(InterpretError::RuntimeError);
+        }
+        if self.stack.len() > self.max_stack {
+            let msg = format!(
+                "Stack overflow: too many values on the stack when calling {}",
+                self.function_name(function)
             );
-            return;
+            self.runtime_error(&msg);
+            return Err(InterpretError::RuntimeError);
         }
 
         let callframe = CallFrame {
-            function: function_obj,
+            closure,
+            function,
+            module: self.heap.closure(closure).module,
             ic: 0,
             slots: self.stack.len() - arg_count - 1,
         };
 
         self.frame.push(callframe);
         self.frame_count += 1;
+        Ok(())
+    }
+
+    fn function_name(&self, function: usize) -> String {
+        match self.heap.function(function).name {
+            Some(ref name) if !name.is_empty() => name.clone(),
+            _ => "the script".to_string(),
+        }
+    }
+
+    // Reuses the upvalue if a closure already captured this slot, so both see the same variable.
+    fn capture_upvalue(&mut self, slot: usize) -> usize {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, upvalue) in self.open_upvalues.iter().enumerate().rev() {
+            if let UpvalueLocation::Open(open_slot) = self.heap.upvalue(*upvalue).location {
+                if open_slot == slot {
+                    return *upvalue;
+                }
+                if open_slot < slot {
+                    break;
+                }
+            }
+            insert_at = i;
+        }
+
+        let upvalue = self.allocate_obj(ObjData::Upvalue(UpvalueObj::new(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    // Moves every value at or above `last` off the stack and into its upvalue.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let upvalue = self.heap.upvalue_mut(*upvalue);
+            let slot = match upvalue.location {
+                UpvalueLocation::Open(slot) => slot,
+                UpvalueLocation::Closed(_) => unreachable!(),
+            };
+
+            if slot < last {
+                break;
+            }
+
+            upvalue.location = UpvalueLocation::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    // The stack, then the opcode about to run
+    fn trace_instruction(&self) {
+        let stack: Vec<String> = self
+            .stack
+            .iter()
+            .map(|x| self.heap.display(x).to_string())
+            .collect();
+        println!("          [{}]", stack.join(", "));
+
+        let frame = self.get_frame();
+        let globals = &self.heap.module(frame.module).slots.names;
+        let func = self.heap.function(frame.function);
+        disaseemble_code(&self.heap, &func.chunk, globals, frame.ic);
     }
 
     fn runtime_error(&self, message: &str) {
-        eprintln!("> Program Start");
-        for i in 0..self.frame_count - 1 {
-            let instruction = self.frame[i].ic - 1;
-            let func: &RefCell<FunctionObj> = self.frame[i].function.borrow();
-            eprint!("| [line {}] in ", func.borrow().chunk.line[instruction]);
+        let _ = self
+            .error_output
+            .borrow_mut()
+            .write_all(self.error_report(message).as_bytes());
+    }
 
-            eprintln!("{}", func.borrow());
+    // Every module's top level is a nameless function, so imported ones go by their module name.
+    // Otherwise they'd all read as <script> and get folded together.
+    fn frame_name(&self, frame: &CallFrame) -> String {
+        let func = self.heap.function(frame.function);
+        if func.name.is_none() && frame.module != self.main_module {
+            return format!("<module {}>", self.heap.module(frame.module).name);
         }
+        func.to_string()
+    }
 
-        let instruction = self.frame[self.frame_count - 1].ic - 1;
-        let func: &RefCell<FunctionObj> = self.frame[self.frame_count - 1].function.borrow();
-        eprintln!("> Error Occured Here:");
-        eprint!("| [line {}] in ", func.borrow().chunk.line[instruction]);
+    fn error_report(&self, message: &str) -> String {
+        let mut report = String::from("> Program Start\n");
+
+        // Failed before the script got its frame, there is no line to point at
+        if self.frame_count == 0 {
+            report.push_str(&format!("> Error Occured Here:\n| {}\n\n", message));
+            return report;
+        }
 
-        eprint!("{}: ", func.borrow());
-        eprintln!("{}\n", message);
+        let calls: Vec<String> = (0..self.frame_count - 1)
+            .map(|i| {
+                let instruction = self.frame[i].ic - 1;
+                let func = self.heap.function(self.frame[i].function);
+                format!(
+                    "| [line {}] in {}",
+                    func.chunk.line_at(instruction),
+                    self.frame_name(&self.frame[i])
+                )
+            })
+            .collect();
+        for call in compact_calls(&calls) {
+            report.push_str(&call);
+            report.push('\n');
+        }
+
+        let instruction = self.frame[self.frame_count - 1].ic - 1;
+        let func = self
+            .heap
+            .function(self.frame[self.frame_count - 1].function);
+        report.push_str(&format!(
+            "> Error Occured Here:\n| [line {}] in {}: {}\n",
+            func.chunk.line_at(instruction),
+            self.frame_name(&self.frame[self.frame_count - 1]),
+            message
+        ));
+
+        // Compiled files don't have their source
+        let chunk = &func.chunk;
+        if chunk.source.is_empty() {
+            report.push('\n');
+            return report;
+        }
+        report.push_str(&render_snippet(
+            &chunk.source,
+            chunk.line_at(instruction),
+            chunk.columns_at(instruction),
+        ));
+        report.push_str("\n\n");
+        report
     }
 
     fn insert_natives(&mut self, natives: Vec<NativeFn>) {
@@ -641,19 +1538,16 @@ impl VM {
     }
 
     fn define_native_fn(&mut self, native_fn: NativeFn) {
-        let native_fn_name = native_fn.name.clone();
-        let native_fn_val = Value::Obj(Obj::NativeFn(Rc::new(RefCell::new(native_fn))));
-        self.push_stack(native_fn_val);
-
-        let native_fn_val = self.pop_stack();
-        self.globals.insert(native_fn_name, native_fn_val);
+        let name = self.heap.intern(&native_fn.name);
+        let native_fn = self.heap.alloc(ObjData::NativeFn(native_fn));
+        self.builtins
+            .insert(name, Value::Obj(Obj::NativeFn(native_fn)));
     }
 
     fn get_op_code(&mut self) -> Option<OpCode> {
         if let Some(frame) = self.frame.get_mut(self.frame_count - 1) {
-            let func: &RefCell<FunctionObj> = frame.function.borrow();
-            let code = func.borrow().chunk.code[frame.ic];
-            frame.ic += 1;
+            let (code, next) = self.heap.function(frame.function).chunk.read_code(frame.ic);
+            frame.ic = next;
             return Some(code);
         }
 
@@ -662,16 +1556,12 @@ impl VM {
 
     #[inline]
     fn push_stack(&mut self, value: Value) {
-        //if self.stack_cap < self.stack.capacity() {
-        //    self.stack_cap = self.stack.capacity() * 2;
-        //    self.collect_garbage();
-        //}
         self.stack.push(value);
     }
 
     #[inline]
     fn peek_stack(&self, idx: usize) -> Value {
-        self.stack[self.stack.len() - 1 - idx].clone()
+        self.stack[self.stack.len() - 1 - idx]
     }
 
     #[inline]
@@ -682,3 +1572,28 @@ impl VM {
         }
     }
 }
+
+// `import "utils"` finds utils.bite, utils.txt or the compiled utils.bitec, a path with an
+// extension is used as it is.
+fn find_module_file(path: &Path) -> Option<PathBuf> {
+    if path.extension().is_some() {
+        return path.is_file().then(|| path.to_path_buf());
+    }
+
+    ["bite", "txt", "bitec"]
+        .iter()
+        .map(|extension| path.with_extension(extension))
+        .find(|file| file.is_file())
+}
+
+// The same file can be reached through different paths, like "utils" and "../lib/utils"
+fn canonical_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn module_name(path: &Path) -> String {
+    match path.file_stem() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => path.display().to_string(),
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::Write;
+
+use crate::{
+    compiler::values::{ListObj, Obj, ObjData, Value},
+    vm::{heap::Heap, InterpretError, MAX_NATIVE_DEPTH, VM},
+};
+
+// Why a native function failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum NativeError {
+    // Reported as a runtime error at the call
+    Message(String),
+    // A Bite function called through NativeContext::call failed and already reported its own
+    // error, so there is nothing more to say.
+    Reported,
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError::Message(message)
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        NativeError::Message(message.to_string())
+    }
+}
+
+// What a native function gets to work with while it runs. Every object it makes or gets back
+// from a call is kept alive until it returns, so it doesn't have to worry about collections.
+pub struct NativeContext<'a> {
+    vm: &'a mut VM,
+    // Where this native's roots start, natives called further in push theirs after it
+    roots_start: usize,
+}
+
+impl<'a> NativeContext<'a> {
+    pub(super) fn new(vm: &'a mut VM) -> Self {
+        let roots_start = vm.native_roots.len();
+        NativeContext { vm, roots_start }
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.vm.heap
+    }
+
+    pub(crate) fn heap_mut(&mut self) -> &mut Heap {
+        &mut self.vm.heap
+    }
+
+    // Where print writes to, see VM::set_output
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.vm.output
+    }
+
+    // Same as VM::allocate_obj, but the object is kept alive until the native returns.
+    pub(crate) fn alloc(&mut self, data: ObjData) -> usize {
+        let idx = self.vm.allocate_obj(data);
+        self.root(idx);
+        idx
+    }
+
+    pub fn new_string(&mut self, string: &str) -> Value {
+        let idx = self.vm.allocate_string(string);
+        self.root(idx);
+        Value::Obj(Obj::String(idx))
+    }
+
+    pub fn new_list(&mut self, items: Vec<Value>) -> Value {
+        Value::Obj(Obj::List(self.alloc(ObjData::List(ListObj::new(items)))))
+    }
+
+    // Calls anything Bite can call, like a closure, a bound method or another native, and gives
+    // back what it returned.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value, NativeError> {
+        if args.len() > u8::MAX as usize {
+            return Err(format!("Can't call with more than {} arguments", u8::MAX).into());
+        }
+
+        let vm = &mut *self.vm;
+        if vm.native_depth >= MAX_NATIVE_DEPTH {
+            return Err("Stack overflow: too many nested calls through natives".into());
+        }
+
+        let frame_count = vm.frame_count;
+        let stack_len = vm.stack.len();
+
+        vm.push_stack(callee);
+        for arg in args {
+            vm.push_stack(*arg);
+        }
+
+        vm.native_depth += 1;
+        let res = vm.call_value(args.len() as u8).and_then(|_| {
+            // Natives and structs are done already, Bite functions still have to run
+            if vm.frame_count > frame_count {
+                vm.run_until(frame_count)?;
+            }
+            Ok(vm.pop_stack())
+        });
+        vm.native_depth -= 1;
+
+        match res {
+            Ok(value) => {
+                if let Value::Obj(obj) = value {
+                    self.root(obj.idx());
+                }
+                Ok(value)
+            }
+            Err(InterpretError::RuntimeError) => {
+                // Puts the VM back how it was, in case the native keeps going
+                vm.close_upvalues(stack_len);
+                vm.frame.truncate(frame_count);
+                vm.frame_count = frame_count;
+                vm.stack.truncate(stack_len);
+                Err(NativeError::Reported)
+            }
+            Err(_) => unreachable!("Only runtime errors can happen while running"),
+        }
+    }
+
+    fn root(&mut self, idx: usize) {
+        self.vm.native_roots.push(idx);
+    }
+}
+
+impl Drop for NativeContext<'_> {
+    fn drop(&mut self) {
+        self.vm.native_roots.truncate(self.roots_start);
+    }
+}
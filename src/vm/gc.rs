@@ -1,212 +1,188 @@
-use std::{borrow::Borrow, cell::RefCell};
-
 use crate::{
-    compiler::values::{FunctionObj, NativeFn, Obj, StrObj, Structs, StructsInstance, Value},
-    vm::{DEBUG, VM},
+    compiler::values::{ObjData, UpvalueLocation, Value},
+    vm::VM,
 };
 
-#[allow(dead_code)]
+// After a collection, the next one happens once the heap has grown by this factor.
+pub const GC_HEAP_GROW_FACTOR: usize = 2;
+pub const GC_INITIAL_THRESHOLD: usize = 1024 * 1024;
+
 impl VM {
-    pub fn collect_garbage(&self) {
-        if DEBUG {
+    // Every object made while running goes through here. Collecting happens before the new object
+    // is placed in the heap, so whatever it refers to has to be reachable from the roots.
+    pub fn allocate_obj(&mut self, data: ObjData) -> usize {
+        if self.heap.bytes_allocated > self.next_gc {
+            self.collect_garbage();
+        }
+
+        self.heap.alloc(data)
+    }
+
+    // Same as allocate_obj, but for strings. An equal string that already exists is reused.
+    pub fn allocate_string(&mut self, string: &str) -> usize {
+        if let Some(idx) = self.heap.find_string(string) {
+            return idx;
+        }
+
+        if self.heap.bytes_allocated > self.next_gc {
+            self.collect_garbage();
+        }
+
+        self.heap.intern(string)
+    }
+
+    pub fn collect_garbage(&mut self) {
+        if self.trace {
             println!("-- Collecting Garbage");
         }
+        let before = self.heap.bytes_allocated;
 
         let mut worklist = Vec::new();
-        self.mark_root(worklist.as_mut());
+        self.mark_root(&mut worklist);
 
-        loop {
-            match worklist.pop() {
-                Some(obj) => self.blacken_obj(obj),
-                None => break,
-            }
+        while let Some(idx) = worklist.pop() {
+            self.blacken_obj(idx, &mut worklist);
         }
 
         self.sweep();
 
-        if DEBUG {
-            println!("-- Finished Collecting Garbage");
+        self.next_gc = (self.heap.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_INITIAL_THRESHOLD);
+
+        if self.trace {
+            println!(
+                "-- Finished Collecting Garbage: collected {} bytes (from {} to {}) next at {}",
+                before.saturating_sub(self.heap.bytes_allocated),
+                before,
+                self.heap.bytes_allocated,
+                self.next_gc
+            );
         }
     }
 
-    fn mark_root<'a>(&'a self, worklist: &mut Vec<&'a Obj>) {
-        for val in &self.stack {
-            self.mark_obj(val, worklist);
+    fn mark_root(&mut self, worklist: &mut Vec<usize>) {
+        for i in 0..self.stack.len() {
+            let val = self.stack[i];
+            self.mark_obj(&val, worklist);
+        }
+
+        // The frames hold the closures being run, which hold the constants of their function.
+        for i in 0..self.frame.len() {
+            let closure = self.frame[i].closure;
+            self.mark_object(closure, worklist);
+        }
+
+        for i in 0..self.open_upvalues.len() {
+            let upvalue = self.open_upvalues[i];
+            self.mark_object(upvalue, worklist);
+        }
+
+        for i in 0..self.native_roots.len() {
+            let root = self.native_roots[i];
+            self.mark_object(root, worklist);
         }
 
         self.mark_table(worklist);
     }
 
-    fn mark_table<'a>(&'a self, worklist: &mut Vec<&'a Obj>) {
-        for (_key, val) in &self.globals {
+    fn mark_table(&mut self, worklist: &mut Vec<usize>) {
+        // The modules hold the globals
+        self.mark_object(self.main_module, worklist);
+        let modules: Vec<usize> = self.modules.values().copied().collect();
+        for module in modules {
+            self.mark_object(module, worklist);
+        }
+
+        let builtins: Vec<(usize, Value)> = self.builtins.iter().map(|(k, v)| (*k, *v)).collect();
+        for (name, val) in &builtins {
+            self.mark_object(*name, worklist);
             self.mark_obj(val, worklist);
         }
     }
 
-    fn mark_obj<'a>(&self, val: &'a Value, worklist: &mut Vec<&'a Obj>) {
-        match val {
-            Value::Obj(obj) => {
-                // The is_marked check maybe unnecessary due to the fact that when I blacken, I
-                // don't have any possiblity of cycles. Though I may save 1-2 iterations of cycle
-                // for when I'm calling a function in the stack constantly. I don't need to blacken
-                // it and check for it since I already know it is marked.
-                match obj {
-                    Obj::String(str) => {
-                        let str_obj: &RefCell<StrObj> = str.borrow();
-
-                        if DEBUG {
-                            println!("mark {}", str_obj.borrow());
-                        }
-                        let mut str_obj = str_obj.borrow_mut();
-                        str_obj.is_marked = if str_obj.is_marked { return } else { true };
-                    }
-                    Obj::Function(func) => {
-                        let func_obj: &RefCell<FunctionObj> = func.borrow();
-                        if DEBUG {
-                            println!("mark {}", func_obj.borrow());
-                        }
-                        let mut func_obj = func_obj.borrow_mut();
-                        func_obj.is_marked = if func_obj.is_marked { return } else { true };
-                    }
-                    Obj::NativeFn(nativ_func) => {
-                        let native_func_obj: &RefCell<NativeFn> = nativ_func.borrow();
-                        if DEBUG {
-                            println!("mark {}", native_func_obj.borrow());
-                        }
-                        let mut native_func_obj = native_func_obj.borrow_mut();
-                        native_func_obj.is_marked = if native_func_obj.is_marked {
-                            return;
-                        } else {
-                            true
-                        };
-                    }
-                    Obj::Structs(structs) => {
-                        let struct_obj: &RefCell<Structs> = structs.borrow();
-                        if DEBUG {
-                            println!("mark {}", struct_obj.borrow());
-                        }
-                        let mut struct_obj = struct_obj.borrow_mut();
-                        struct_obj.is_marked = if struct_obj.is_marked {
-                            return;
-                        } else {
-                            true
-                        };
-                    }
-                    Obj::Instance(instance) => {
-                        let instance_obj: &RefCell<StructsInstance> = instance.borrow();
-                        if DEBUG {
-                            println!("mark {}", instance_obj.borrow());
-                        }
-                        let mut instance_obj = instance_obj.borrow_mut();
-                        instance_obj.is_marked = if instance_obj.is_marked {
-                            return;
-                        } else {
-                            true
-                        };
-                    }
-                };
-
-                worklist.push(obj);
-            }
-            _ => {}
+    fn mark_obj(&mut self, val: &Value, worklist: &mut Vec<usize>) {
+        if let Value::Obj(obj) = val {
+            self.mark_object(obj.idx(), worklist);
         }
     }
 
-    fn sweep(&self) {
-        // If only there was a place for me to store my Obj, perhaps it might be faster, perhaps I
-        // can have the opportunity to use this GC.
-        // For now all I'll do is just log, if any, unmarked objs.
-        // This implementation is still flawed too somehow.
-
-        if DEBUG {
-            println!("--- Unmarked Objs ---");
-        }
-        for val in &self.stack {
-            self.print_unmarked_val(val)
-        }
-        for (_key, val) in &self.globals {
-            self.print_unmarked_val(val)
+    fn mark_object(&mut self, idx: usize, worklist: &mut Vec<usize>) {
+        let obj = self.heap.get_mut(idx);
+        // Marked objects are skipped, otherwise cycles between instances would never finish.
+        if obj.is_marked {
+            return;
         }
+        obj.is_marked = true;
+
+        worklist.push(idx);
     }
 
-    fn blacken_obj(&self, obj: &Obj) {
-        match obj {
-            Obj::String(obj) => {
-                let str_obj: &RefCell<StrObj> = obj.borrow();
+    // Marks everything the object refers to.
+    fn blacken_obj(&mut self, idx: usize, worklist: &mut Vec<usize>) {
+        let mut children: Vec<usize> = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
 
-                if DEBUG {
-                    println!("blacken {}", str_obj.borrow());
-                }
+        match self.heap.get(idx).data {
+            ObjData::String(_) | ObjData::NativeFn(_) => {}
+            ObjData::Function(ref function) => {
+                values.extend(function.chunk.values.iter().copied());
             }
-            Obj::NativeFn(obj) => {
-                let native_fn: &RefCell<NativeFn> = obj.borrow();
-
-                if DEBUG {
-                    println!("blacken {}", native_fn.borrow());
-                }
+            ObjData::Closure(ref closure) => {
+                children.push(closure.function);
+                children.push(closure.module);
+                children.extend(closure.upvalues.iter().copied());
             }
-            Obj::Function(obj) => {
-                let fn_obj: &RefCell<FunctionObj> = obj.borrow();
-                if DEBUG {
-                    println!("blacken {}", fn_obj.borrow());
+            ObjData::Upvalue(ref upvalue) => {
+                if let UpvalueLocation::Closed(value) = upvalue.location {
+                    values.push(value);
                 }
             }
-            Obj::Structs(obj) => {
-                let structs_obj: &RefCell<Structs> = obj.borrow();
-                if DEBUG {
-                    println!("blacken {}", structs_obj.borrow());
-                }
+            ObjData::Structs(ref structs) => {
+                // Field names have to stay interned as long as something uses them
+                children.extend(structs.fields.keys().copied());
+                values.extend(structs.fields.values().copied());
+                children.extend(structs.methods.keys().copied());
+                children.extend(structs.methods.values().copied());
+            }
+            ObjData::Instance(ref instance) => {
+                children.push(instance.origin);
+                children.extend(instance.fields.keys().copied());
+                values.extend(instance.fields.values().copied());
+            }
+            ObjData::BoundMethod(ref bound_method) => {
+                children.push(bound_method.method);
+                values.push(bound_method.receiver);
+            }
+            ObjData::List(ref list) => {
+                values.extend(list.items.iter().copied());
             }
-            Obj::Instance(obj) => {
-                let instance_obj: &RefCell<StructsInstance> = obj.borrow();
-                if DEBUG {
-                    println!("blacken {}", instance_obj.borrow());
+            ObjData::Map(ref map) => {
+                for (key, value) in &map.entries {
+                    values.push(*key);
+                    values.push(*value);
                 }
             }
+            ObjData::Module(ref module) => {
+                children.extend(module.slots.names.iter().copied());
+                values.extend(module.globals.iter().flatten().copied());
+            }
         }
-    }
 
-    fn print_unmarked_val(&self, val: &Value) {
-        match val {
-            Value::Obj(obj) => match obj {
-                Obj::String(obj) => {
-                    let str_obj: &RefCell<StrObj> = obj.borrow();
-                    let str_obj = str_obj.borrow();
-                    if !str_obj.is_marked {
-                        println!("{}", str_obj);
-                    }
-                }
-                Obj::NativeFn(obj) => {
-                    let native_fn: &RefCell<NativeFn> = obj.borrow();
-                    let native_fn = native_fn.borrow();
-                    if !native_fn.is_marked {
-                        println!("{}", native_fn);
-                    }
-                }
-                Obj::Function(obj) => {
-                    let fn_obj: &RefCell<FunctionObj> = obj.borrow();
-                    let fn_obj = fn_obj.borrow();
-                    if !fn_obj.is_marked {
-                        println!("{}", fn_obj);
-                    }
-                }
-                Obj::Structs(obj) => {
-                    let structs_obj: &RefCell<Structs> = obj.borrow();
-                    let structs_obj = structs_obj.borrow();
-                    if !structs_obj.is_marked {
-                        println!("{}", structs_obj);
-                    }
-                }
+        for child in children {
+            self.mark_object(child, worklist);
+        }
+        for value in &values {
+            self.mark_obj(value, worklist);
+        }
+    }
 
-                Obj::Instance(obj) => {
-                    let instance_obj: &RefCell<StructsInstance> = obj.borrow();
-                    let instance_obj = instance_obj.borrow();
-                    if !instance_obj.is_marked {
-                        println!("{}", instance_obj);
-                    }
-                }
-            },
-            _ => {}
+    fn sweep(&mut self) {
+        for idx in self.heap.live_objects() {
+            let obj = self.heap.get_mut(idx);
+            if obj.is_marked {
+                obj.is_marked = false;
+            } else {
+                self.heap.free(idx);
+            }
         }
     }
 }
@@ -0,0 +1,266 @@
+use std::{collections::HashMap, mem};
+
+use crate::compiler::values::{
+    BoundMethodObj, ClosureObj, FunctionObj, ListObj, MapKey, MapObj, ModuleObj, NativeFn, Obj,
+    ObjData, StrObj, Structs, StructsInstance, UpvalueObj, Value, ValueDisplay,
+};
+
+pub struct HeapObj {
+    pub is_marked: bool,
+    pub data: ObjData,
+}
+
+// Arena that owns every object. Values only carry the index of their slot, so copying a Value
+// never touches the object itself. Freed slots are reused by later allocations.
+pub struct Heap {
+    objects: Vec<Option<HeapObj>>,
+    free_slots: Vec<usize>,
+    pub(crate) bytes_allocated: usize,
+    // Every string is interned here, so two equal strings are always the same object. It doesn't
+    // keep them alive, freeing a string removes it.
+    strings: HashMap<String, usize>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap::new()
+    }
+}
+
+impl Heap {
+    pub(crate) fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            free_slots: Vec::new(),
+            bytes_allocated: 0,
+            strings: HashMap::new(),
+        }
+    }
+
+    // Doesn't collect. The VM decides when that happens, see VM::allocate_obj. Strings have to go
+    // through intern instead.
+    pub(crate) fn alloc(&mut self, data: ObjData) -> usize {
+        self.bytes_allocated += obj_size(&data);
+
+        let obj = HeapObj {
+            is_marked: false,
+            data,
+        };
+
+        match self.free_slots.pop() {
+            Some(idx) => {
+                self.objects[idx] = Some(obj);
+                idx
+            }
+            None => {
+                self.objects.push(Some(obj));
+                self.objects.len() - 1
+            }
+        }
+    }
+
+    // Gives back the string already holding this text, or allocates it. Doesn't collect either.
+    pub(crate) fn intern(&mut self, string: &str) -> usize {
+        if let Some(idx) = self.find_string(string) {
+            return idx;
+        }
+
+        let idx = self.alloc(ObjData::String(StrObj::new(string.to_string())));
+        self.strings.insert(string.to_string(), idx);
+        idx
+    }
+
+    pub(crate) fn find_string(&self, string: &str) -> Option<usize> {
+        self.strings.get(string).copied()
+    }
+
+    pub(crate) fn free(&mut self, idx: usize) {
+        if let Some(obj) = self.objects[idx].take() {
+            if let ObjData::String(ref string) = obj.data {
+                self.strings.remove(&string.name);
+            }
+
+            // Lists and maps can grow after they are allocated, so this can be off by a bit.
+            self.bytes_allocated = self.bytes_allocated.saturating_sub(obj_size(&obj.data));
+            self.free_slots.push(idx);
+        }
+    }
+
+    pub(crate) fn get(&self, idx: usize) -> &HeapObj {
+        match self.objects[idx] {
+            Some(ref obj) => obj,
+            None => panic!("Use of freed object at {}", idx),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, idx: usize) -> &mut HeapObj {
+        match self.objects[idx] {
+            Some(ref mut obj) => obj,
+            None => panic!("Use of freed object at {}", idx),
+        }
+    }
+
+    // Indexes of every object still alive.
+    pub(crate) fn live_objects(&self) -> Vec<usize> {
+        self.objects
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, obj)| obj.as_ref().map(|_| idx))
+            .collect()
+    }
+
+    // The text of a string value, None if the value isn't a string.
+    pub fn as_str(&self, value: &Value) -> Option<&str> {
+        match value {
+            Value::Obj(Obj::String(idx)) => Some(&self.string(*idx).name),
+            _ => None,
+        }
+    }
+
+    pub fn display<'a>(&'a self, value: &'a Value) -> ValueDisplay<'a> {
+        ValueDisplay { value, heap: self }
+    }
+
+    pub(crate) fn string(&self, idx: usize) -> &StrObj {
+        match self.get(idx).data {
+            ObjData::String(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a string", idx),
+        }
+    }
+
+    pub(crate) fn function(&self, idx: usize) -> &FunctionObj {
+        match self.get(idx).data {
+            ObjData::Function(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a function", idx),
+        }
+    }
+
+    pub(crate) fn closure(&self, idx: usize) -> &ClosureObj {
+        match self.get(idx).data {
+            ObjData::Closure(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a closure", idx),
+        }
+    }
+
+    pub(crate) fn native_fn(&self, idx: usize) -> &NativeFn {
+        match self.get(idx).data {
+            ObjData::NativeFn(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a native function", idx),
+        }
+    }
+
+    pub(crate) fn structs(&self, idx: usize) -> &Structs {
+        match self.get(idx).data {
+            ObjData::Structs(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a struct", idx),
+        }
+    }
+
+    pub(crate) fn bound_method(&self, idx: usize) -> &BoundMethodObj {
+        match self.get(idx).data {
+            ObjData::BoundMethod(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a bound method", idx),
+        }
+    }
+
+    pub(crate) fn structs_mut(&mut self, idx: usize) -> &mut Structs {
+        match self.get_mut(idx).data {
+            ObjData::Structs(ref mut obj) => obj,
+            _ => unreachable!("Object at {} is not a struct", idx),
+        }
+    }
+
+    pub(crate) fn instance(&self, idx: usize) -> &StructsInstance {
+        match self.get(idx).data {
+            ObjData::Instance(ref obj) => obj,
+            _ => unreachable!("Object at {} is not an instance", idx),
+        }
+    }
+
+    pub(crate) fn instance_mut(&mut self, idx: usize) -> &mut StructsInstance {
+        match self.get_mut(idx).data {
+            ObjData::Instance(ref mut obj) => obj,
+            _ => unreachable!("Object at {} is not an instance", idx),
+        }
+    }
+
+    pub(crate) fn list(&self, idx: usize) -> &ListObj {
+        match self.get(idx).data {
+            ObjData::List(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a list", idx),
+        }
+    }
+
+    pub(crate) fn list_mut(&mut self, idx: usize) -> &mut ListObj {
+        match self.get_mut(idx).data {
+            ObjData::List(ref mut obj) => obj,
+            _ => unreachable!("Object at {} is not a list", idx),
+        }
+    }
+
+    pub(crate) fn map(&self, idx: usize) -> &MapObj {
+        match self.get(idx).data {
+            ObjData::Map(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a map", idx),
+        }
+    }
+
+    pub(crate) fn map_mut(&mut self, idx: usize) -> &mut MapObj {
+        match self.get_mut(idx).data {
+            ObjData::Map(ref mut obj) => obj,
+            _ => unreachable!("Object at {} is not a map", idx),
+        }
+    }
+
+    // Only strings and numbers can be used as map keys.
+    pub(crate) fn map_key(&self, value: &Value) -> Option<MapKey> {
+        match value {
+            // -0 and 0 should be the same key
+            Value::Number(num) if *num == 0.0 => Some(MapKey::Number(0.0_f64.to_bits())),
+            Value::Number(num) => Some(MapKey::Number(num.to_bits())),
+            Value::Obj(Obj::String(idx)) => Some(MapKey::String(*idx)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn module(&self, idx: usize) -> &ModuleObj {
+        match self.get(idx).data {
+            ObjData::Module(ref obj) => obj,
+            _ => unreachable!("Object at {} is not a module", idx),
+        }
+    }
+
+    pub(crate) fn module_mut(&mut self, idx: usize) -> &mut ModuleObj {
+        match self.get_mut(idx).data {
+            ObjData::Module(ref mut obj) => obj,
+            _ => unreachable!("Object at {} is not a module", idx),
+        }
+    }
+
+    pub(crate) fn upvalue(&self, idx: usize) -> &UpvalueObj {
+        match self.get(idx).data {
+            ObjData::Upvalue(ref obj) => obj,
+            _ => unreachable!("Object at {} is not an upvalue", idx),
+        }
+    }
+
+    pub(crate) fn upvalue_mut(&mut self, idx: usize) -> &mut UpvalueObj {
+        match self.get_mut(idx).data {
+            ObjData::Upvalue(ref mut obj) => obj,
+            _ => unreachable!("Object at {} is not an upvalue", idx),
+        }
+    }
+}
+
+// Rough size of the object, only used to decide when to collect.
+fn obj_size(data: &ObjData) -> usize {
+    mem::size_of::<HeapObj>()
+        + match data {
+            ObjData::String(obj) => obj.name.capacity(),
+            ObjData::Closure(obj) => obj.upvalues.len() * mem::size_of::<usize>(),
+            ObjData::List(obj) => obj.items.capacity() * mem::size_of::<Value>(),
+            ObjData::Map(obj) => obj.entries.capacity() * 2 * mem::size_of::<Value>(),
+            ObjData::Module(obj) => obj.globals.capacity() * mem::size_of::<Option<Value>>(),
+            _ => 0,
+        }
+}